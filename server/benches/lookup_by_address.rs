@@ -0,0 +1,49 @@
+//! Compares looking devices up by address through the device index with scanning sysfs:
+//!
+//! ```sh
+//! cargo bench --bench lookup_by_address
+//! ```
+
+use criterion::{criterion_group, criterion_main, Criterion};
+
+use ev3_remote_control_server::hal_ev3_index::{scan, DeviceIndex};
+
+// Shared with the unit tests, which don't use all of it.
+#[allow(dead_code)]
+#[path = "../src/hal_ev3_fixture.rs"]
+mod hal_ev3_fixture;
+
+use hal_ev3_fixture::SysfsFixture;
+
+/// A brick with every port in use: four motors and four sensors.
+fn full_brick() -> SysfsFixture {
+    let fixture = SysfsFixture::new();
+    for i in 1..=4 {
+        fixture.add_tacho_motor(&format!("motor{i}"), &format!("ev3-ports:out{i}"));
+        fixture.add_color_sensor(&format!("sensor{i}"), &format!("ev3-ports:in{i}"));
+    }
+    fixture
+}
+
+fn lookup_by_address(c: &mut Criterion) {
+    let sysfs = full_brick();
+    let address = "ev3-ports:in4";
+    let mut group = c.benchmark_group("by_address");
+
+    group.bench_function("scan", |b| {
+        b.iter(|| {
+            let entries = scan(&sysfs.class_root()).unwrap();
+            assert!(entries.iter().any(|e| e.address == address));
+        })
+    });
+
+    let index = DeviceIndex::new(sysfs.class_root());
+    group.bench_function("index", |b| {
+        b.iter(|| assert!(index.by_address(address).unwrap().is_some()))
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, lookup_by_address);
+criterion_main!(benches);
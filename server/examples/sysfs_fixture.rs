@@ -0,0 +1,60 @@
+//! Generates a fake EV3 sysfs tree for running the server off the brick with the real EV3 HAL:
+//!
+//! ```sh
+//! cargo run --example sysfs_fixture -- /tmp/ev3-sysfs
+//! EV3_SYSFS_ROOT=/tmp/ev3-sysfs cargo run
+//! ```
+//!
+//! The brick has all eight ports, its four status LEDs, two large motors on `outA` and `outB`
+//! and a color sensor on `in1`.  Edit the files to simulate readings, or delete a device's
+//! directory to unplug it.
+
+use std::path::PathBuf;
+
+use clap::Parser;
+
+// Shared with the integration tests, which don't use all of it.
+#[allow(dead_code)]
+#[path = "../src/hal_ev3_fixture.rs"]
+mod hal_ev3_fixture;
+
+use hal_ev3_fixture::SysfsFixture;
+
+#[derive(Parser)]
+#[clap(about = "Generate a fake EV3 sysfs tree for EV3_SYSFS_ROOT")]
+struct Opts {
+    /// Directory to create the tree in, which must not contain one already.
+    root: PathBuf,
+}
+
+fn main() {
+    let opts: Opts = Opts::parse();
+    if opts.root.join("class").exists() {
+        eprintln!("{} already contains a sysfs tree", opts.root.display());
+        std::process::exit(2);
+    }
+
+    let fixture = SysfsFixture::at(&opts.root);
+    let mut ports = Vec::new();
+    for (i, port) in ["in1", "in2", "in3", "in4", "outA", "outB", "outC", "outD"]
+        .iter()
+        .enumerate()
+    {
+        ports.push(fixture.add_port(&format!("port{i}"), &format!("ev3-ports:{port}")));
+    }
+
+    for led in ["led0", "led1"] {
+        for color in ["green", "red"] {
+            fixture.add_led(&format!("{led}:{color}:brick-status"));
+        }
+    }
+
+    fixture.add_color_sensor("sensor0", "ev3-ports:in1");
+    fixture.set(&ports[0], "status", "ev3-uart");
+    fixture.add_tacho_motor("motor0", "ev3-ports:outA");
+    fixture.set(&ports[4], "status", "tacho-motor");
+    fixture.add_tacho_motor("motor1", "ev3-ports:outB");
+    fixture.set(&ports[5], "status", "tacho-motor");
+
+    println!("EV3_SYSFS_ROOT={}", fixture.root().display());
+}
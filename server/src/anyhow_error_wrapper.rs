@@ -1,9 +1,14 @@
 use async_trait::async_trait;
+use coap_lite::{ContentFormat, MessageClass, ResponseType};
 use coap_server::app::request_handler::RequestHandler;
 use coap_server::app::{CoapError, Request, Response};
+use serde::Serialize;
+use std::fmt;
 use std::future::Future;
 use std::net::SocketAddr;
 
+use crate::hal::HalError;
+
 #[derive(Clone)]
 pub struct AnyhowErrorWrapper<F> {
     wrapper: F,
@@ -28,7 +33,108 @@ where
     R: Future<Output = anyhow::Result<Response>> + Send,
 {
     async fn handle(&self, request: Request<SocketAddr>) -> Result<Response, CoapError> {
-        (self.wrapper)(request).await.map_err(anyhow_error_mapping)
+        // Prepared up front as the handler consumes the request.
+        let mut error_reply = request.new_response();
+        match (self.wrapper)(request).await {
+            Ok(reply) => Ok(reply),
+            Err(error) => match into_diagnostic(error) {
+                Ok(diagnostic) => {
+                    error_reply.message.header.code = MessageClass::Response(diagnostic.code);
+                    error_reply
+                        .message
+                        .set_content_format(ContentFormat::ApplicationJSON);
+                    error_reply.message.payload = serde_json::to_vec(&diagnostic)
+                        .map_err(|e| CoapError::internal(e.to_string()))?;
+                    Ok(error_reply)
+                }
+                Err(error) => Err(anyhow_error_mapping(error)),
+            },
+        }
+    }
+}
+
+/// Error that is reported to the client as a JSON payload so that it can react
+/// programmatically, rather than just a status code and message.
+#[derive(Debug, Serialize)]
+pub struct DiagnosticError {
+    #[serde(skip)]
+    pub code: ResponseType,
+
+    /// Machine readable error kind, e.g. `invalid_value`.
+    pub kind: &'static str,
+    pub message: String,
+
+    /// Address of the device involved, if any.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub device: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub attribute: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub allowed_values: Option<Vec<String>>,
+}
+
+impl DiagnosticError {
+    /// Describes a HAL failure, picking the response code according to whether the client can do
+    /// anything about it.
+    pub fn from_hal(error: &HalError) -> Self {
+        let (code, kind) = match error {
+            HalError::InvalidValue(_) => (ResponseType::BadRequest, "invalid_value"),
+            HalError::PermissionDenied(_) => (ResponseType::Forbidden, "permission_denied"),
+            HalError::UnknownAttribute(_) => (ResponseType::NotFound, "unknown_attribute"),
+            HalError::NotConnected { .. } => (ResponseType::NotFound, "not_connected"),
+            HalError::DeviceGone => (ResponseType::NotFound, "device_gone"),
+            HalError::NotApplicable => (ResponseType::MethodNotAllowed, "not_applicable"),
+            HalError::Busy => (ResponseType::ServiceUnavailable, "busy"),
+            HalError::Io(_) => (ResponseType::InternalServerError, "io"),
+            HalError::InternalError(_) => (ResponseType::InternalServerError, "internal"),
+        };
+        let attribute = match error {
+            HalError::PermissionDenied(name) | HalError::UnknownAttribute(name) => {
+                Some(name.clone())
+            }
+            _ => None,
+        };
+        Self {
+            code,
+            kind,
+            message: error.to_string(),
+            device: None,
+            attribute,
+            allowed_values: None,
+        }
+    }
+}
+
+impl fmt::Display for DiagnosticError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.kind, self.message)
+    }
+}
+
+impl std::error::Error for DiagnosticError {}
+
+/// Tags errors with the device they occurred on, turning HAL errors into [`DiagnosticError`]s
+/// along the way.
+pub fn with_device(error: anyhow::Error, device: &str) -> anyhow::Error {
+    match into_diagnostic(error) {
+        Ok(mut diagnostic) => {
+            if diagnostic.device.is_none() {
+                diagnostic.device = Some(device.to_owned());
+            }
+            diagnostic.into()
+        }
+        Err(error) => error,
+    }
+}
+
+fn into_diagnostic(error: anyhow::Error) -> Result<DiagnosticError, anyhow::Error> {
+    let error = match error.downcast::<DiagnosticError>() {
+        Ok(diagnostic) => return Ok(diagnostic),
+        Err(error) => error,
+    };
+    match error.downcast_ref::<HalError>() {
+        Some(hal_error) => Ok(DiagnosticError::from_hal(hal_error)),
+        None => Err(error),
     }
 }
 
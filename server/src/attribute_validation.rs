@@ -0,0 +1,297 @@
+//! Checks attribute writes before they reach the HAL, so that clients get an actionable error
+//! rather than whatever the driver makes of a bogus value.  In order, a write must:
+//!
+//! 1. target an attribute the device has (4.04 otherwise)
+//! 2. target a writable attribute (4.03)
+//! 3. match the attribute's type, e.g. no fractions for integers, no numbers for strings (4.00)
+//! 4. fit the range of the type as well as [`HalAttribute::bounds`] (4.00)
+//! 5. be one of the values listed by [`HalAttribute::allowed_values_from`] (4.00)
+
+use coap_lite::ResponseType;
+
+use crate::anyhow_error_wrapper::DiagnosticError;
+use crate::hal::{HalAttribute, HalAttributeType, HalBounds, HalDevice, HalResult};
+
+/// Looks up the attribute being written, failing with a [`DiagnosticError`] if the device doesn't
+/// have it.
+pub fn find_attribute<'a>(
+    attributes: &'a [HalAttribute],
+    name: &str,
+) -> Result<&'a HalAttribute, DiagnosticError> {
+    attributes
+        .iter()
+        .find(|a| a.name == name)
+        .ok_or_else(|| DiagnosticError {
+            code: ResponseType::NotFound,
+            kind: "unknown_attribute",
+            message: format!("No such attribute: {name}"),
+            device: None,
+            attribute: Some(name.to_owned()),
+            allowed_values: None,
+        })
+}
+
+/// Validates writing `value` to `attribute`, returning the value as it should be passed to
+/// [`HalDevice::set_attribute_str`].  Invalid values yield a [`DiagnosticError`].
+pub fn validate_write(
+    device: &dyn HalDevice,
+    attribute: &HalAttribute,
+    value: &serde_json::Value,
+) -> anyhow::Result<String> {
+    if !attribute.is_writable {
+        return Err(diagnostic(
+            ResponseType::Forbidden,
+            "not_writable",
+            attribute,
+            "Attribute is read-only".to_owned(),
+        )
+        .into());
+    }
+
+    let value_str = match (attribute.data_type, value) {
+        (HalAttributeType::String, serde_json::Value::String(s)) => s.clone(),
+        (HalAttributeType::String, _) => {
+            return Err(invalid_value(attribute, format!("Expected a string, got {value}")).into())
+        }
+        (HalAttributeType::Float32 | HalAttributeType::Float64, serde_json::Value::Number(n)) => {
+            n.to_string()
+        }
+        (_, serde_json::Value::Number(n)) => {
+            let integer = n
+                .as_i64()
+                .map(i128::from)
+                .or_else(|| n.as_u64().map(i128::from));
+            let integer = integer
+                .ok_or_else(|| invalid_value(attribute, format!("Expected an integer, got {n}")))?;
+            let (min, max) = integer_range(attribute.data_type);
+            if integer < min || integer > max {
+                return Err(invalid_value(
+                    attribute,
+                    format!("{integer} is out of range for {:?}", attribute.data_type),
+                )
+                .into());
+            }
+            integer.to_string()
+        }
+        _ => {
+            return Err(invalid_value(attribute, format!("Expected a number, got {value}")).into())
+        }
+    };
+
+    if let Some((min, max)) = resolve_bounds(device, attribute)? {
+        let number = value.as_f64().unwrap_or_default();
+        if number < min as f64 || number > max as f64 {
+            return Err(
+                invalid_value(attribute, format!("{number} is outside of {min}..={max}")).into(),
+            );
+        }
+    }
+
+    if let Some(allowed_values_from) = &attribute.allowed_values_from {
+        let allowed_values_str = device.get_attribute_str(allowed_values_from)?;
+        let allowed_values: Vec<_> = allowed_values_str.split_whitespace().collect();
+        if !allowed_values.contains(&value_str.as_str()) {
+            return Err(DiagnosticError {
+                allowed_values: Some(allowed_values.into_iter().map(|v| v.to_owned()).collect()),
+                ..invalid_value(
+                    attribute,
+                    format!("{value_str:?} is not one of {allowed_values_from}"),
+                )
+            }
+            .into());
+        }
+    }
+
+    Ok(value_str)
+}
+
+/// Works out the current `(min, max)` of the attribute's [`HalBounds`], if it has any.
+pub fn resolve_bounds(
+    device: &dyn HalDevice,
+    attribute: &HalAttribute,
+) -> HalResult<Option<(i64, i64)>> {
+    let read_limit = |name: &str| -> HalResult<i64> {
+        let value = device.get_attribute_str(name)?;
+        value.trim().parse().map_err(|_| {
+            crate::hal::HalError::InternalError(format!("Unexpected {name} value: {value}"))
+        })
+    };
+    let resolved = match &attribute.bounds {
+        None => None,
+        Some(HalBounds::Fixed { min, max }) => Some((*min, *max)),
+        Some(HalBounds::Symmetric(name)) => {
+            let limit = read_limit(name)?;
+            Some((-limit, limit))
+        }
+        Some(HalBounds::UpTo(name)) => Some((0, read_limit(name)?)),
+    };
+    Ok(resolved)
+}
+
+fn integer_range(data_type: HalAttributeType) -> (i128, i128) {
+    match data_type {
+        HalAttributeType::Int8 => (i8::MIN.into(), i8::MAX.into()),
+        HalAttributeType::Int16 => (i16::MIN.into(), i16::MAX.into()),
+        HalAttributeType::Int32 => (i32::MIN.into(), i32::MAX.into()),
+        HalAttributeType::UInt8 => (0, u8::MAX.into()),
+        HalAttributeType::UInt16 => (0, u16::MAX.into()),
+        HalAttributeType::UInt32 => (0, u32::MAX.into()),
+        HalAttributeType::UInt64 => (0, u64::MAX.into()),
+        _ => (i64::MIN.into(), i64::MAX.into()),
+    }
+}
+
+fn invalid_value(attribute: &HalAttribute, message: String) -> DiagnosticError {
+    diagnostic(
+        ResponseType::BadRequest,
+        "invalid_value",
+        attribute,
+        message,
+    )
+}
+
+fn diagnostic(
+    code: ResponseType,
+    kind: &'static str,
+    attribute: &HalAttribute,
+    message: String,
+) -> DiagnosticError {
+    DiagnosticError {
+        code,
+        kind,
+        message,
+        device: None,
+        attribute: Some(attribute.name.clone()),
+        allowed_values: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hal::{
+        HalAttributeType, HalBounds, HalDeviceType, HalError, HalResult, WatchHandle,
+    };
+    use anyhow::anyhow;
+    use serde_json::json;
+
+    /// Motor with a representative selection of attributes.
+    struct FakeMotor;
+
+    impl HalDevice for FakeMotor {
+        fn get_type(&self) -> HalResult<HalDeviceType> {
+            Ok(HalDeviceType::Actuator)
+        }
+
+        fn get_driver_name(&self) -> HalResult<String> {
+            Ok("lego-ev3-l-motor".to_owned())
+        }
+
+        fn get_address(&self) -> HalResult<String> {
+            Ok("ev3-ports:outA".to_owned())
+        }
+
+        fn get_applicable_attributes(&self) -> HalResult<Vec<HalAttribute>> {
+            Ok(vec![
+                HalAttribute::new_writeonly(HalAttributeType::String, "command")
+                    .with_allowed_values_from("commands"),
+                HalAttribute::new_readonly_array(HalAttributeType::String, "commands"),
+                HalAttribute::new_rw(HalAttributeType::Int8, "duty_cycle_sp").with_bounds(
+                    HalBounds::Fixed {
+                        min: -100,
+                        max: 100,
+                    },
+                ),
+                HalAttribute::new_readonly(HalAttributeType::Int32, "max_speed"),
+                HalAttribute::new_rw(HalAttributeType::String, "polarity"),
+                HalAttribute::new_readonly(HalAttributeType::Int32, "position"),
+                HalAttribute::new_rw(HalAttributeType::Int32, "speed_sp")
+                    .with_bounds(HalBounds::Symmetric("max_speed".to_owned())),
+            ])
+        }
+
+        fn get_attribute_str(&self, name: &str) -> HalResult<String> {
+            match name {
+                "commands" => Ok("run-forever stop reset".to_owned()),
+                "max_speed" => Ok("1050".to_owned()),
+                _ => Err(HalError::NotApplicable),
+            }
+        }
+
+        fn set_attribute_str(&mut self, _name: &str, _value: &str) -> HalResult<()> {
+            Ok(())
+        }
+
+        fn watch_attributes(&self, _names: &[String]) -> anyhow::Result<WatchHandle> {
+            Err(anyhow!("Not watchable"))
+        }
+    }
+
+    fn motor() -> (Box<dyn HalDevice>, Vec<HalAttribute>) {
+        let device = Box::new(FakeMotor);
+        let attributes = device.get_applicable_attributes().unwrap();
+        (device, attributes)
+    }
+
+    fn rejection(
+        device: &dyn HalDevice,
+        attributes: &[HalAttribute],
+        name: &str,
+        value: serde_json::Value,
+    ) -> DiagnosticError {
+        let attribute = find_attribute(attributes, name).unwrap();
+        validate_write(device, attribute, &value)
+            .unwrap_err()
+            .downcast::<DiagnosticError>()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_allowed_values() {
+        let (device, attributes) = motor();
+        let command = find_attribute(&attributes, "command").unwrap();
+
+        assert_eq!(
+            validate_write(device.as_ref(), command, &json!("run-forever")).unwrap(),
+            "run-forever"
+        );
+        let diagnostic = rejection(device.as_ref(), &attributes, "command", json!("explode"));
+        assert_eq!(diagnostic.code, ResponseType::BadRequest);
+        assert!(diagnostic
+            .allowed_values
+            .unwrap()
+            .contains(&"run-forever".to_owned()));
+    }
+
+    #[test]
+    fn test_types_and_bounds() {
+        let (device, attributes) = motor();
+        let device = device.as_ref();
+        let speed_sp = find_attribute(&attributes, "speed_sp").unwrap();
+
+        assert_eq!(
+            validate_write(device, speed_sp, &json!(-1050)).unwrap(),
+            "-1050"
+        );
+        assert_eq!(
+            resolve_bounds(device, speed_sp).unwrap(),
+            Some((-1050, 1050))
+        );
+        for (name, value) in [
+            ("speed_sp", json!(1051)),
+            ("speed_sp", json!(1.5)),
+            ("speed_sp", json!("100")),
+            ("duty_cycle_sp", json!(300)),
+            ("duty_cycle_sp", json!(101)),
+            ("polarity", json!(1)),
+        ] {
+            let diagnostic = rejection(device, &attributes, name, value);
+            assert_eq!(diagnostic.kind, "invalid_value", "{name}");
+        }
+
+        let diagnostic = rejection(device, &attributes, "position", json!(0));
+        assert_eq!(diagnostic.code, ResponseType::Forbidden);
+        let diagnostic = find_attribute(&attributes, "bogus").unwrap_err();
+        assert_eq!(diagnostic.code, ResponseType::NotFound);
+    }
+}
@@ -0,0 +1,462 @@
+//! Applies a batch of (already validated) attribute writes, reporting the outcome of each one
+//! rather than just the first failure.
+//!
+//! Writes are applied in a fixed order regardless of the order they were requested in, so that
+//! e.g. a motor never starts running with stale setpoints:
+//!
+//! 1. configuration, i.e. `stop_action` and `polarity`
+//! 2. setpoints (`*_sp`, as well as unit-converted ones such as `speed_sp_rpm`)
+//! 3. triggers, i.e. `command` and `mode`
+//!
+//! Writes within the same group keep their requested order, and writes to any other attribute
+//! keep their requested position.  Writes stop at the first failure since later ones depend on
+//! earlier ones, e.g. `command` acting on the setpoints.
+//!
+//! In atomic mode the current values of the touched attributes are read up front, and if any
+//! write fails the ones that went through are restored.  Write-only attributes such as `command`
+//! can't be read back and so are never restored.
+//!
+//! The overall outcome is reported as 2.04 if all writes succeeded, the code of the failed write
+//! if none of them stuck, or [`PARTIAL_SUCCESS`] if some stuck and others didn't.
+
+use coap_lite::{MessageClass, ResponseType};
+use log::{debug, warn};
+use serde::Serialize;
+
+use crate::anyhow_error_wrapper::DiagnosticError;
+use crate::hal::{HalAttribute, HalDevice, HalError, HalResult};
+use crate::hal_motor_units::MotorUnitAttribute;
+
+/// Response code for a batch that was only partly applied, leaving the device with a mix of old
+/// and new values.
+pub const PARTIAL_SUCCESS: ResponseType = ResponseType::Conflict;
+
+/// Attributes which affect how later writes take effect, e.g. `stop_action` for `command`.
+const CONFIGURATION_ATTRIBUTES: [&str; 2] = ["polarity", "stop_action"];
+
+/// Attributes which act on the values written before them.
+const TRIGGER_ATTRIBUTES: [&str; 2] = ["command", "mode"];
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WriteStatus {
+    Ok,
+    Error,
+
+    /// Not attempted as an earlier write failed.
+    Skipped,
+
+    /// Written successfully, then restored to the previous value as a later write failed.
+    RolledBack,
+
+    /// Written successfully but restoring the previous value failed as well.
+    RollbackFailed,
+}
+
+#[derive(Debug, Serialize)]
+pub struct WriteResult {
+    pub name: String,
+    pub status: WriteStatus,
+
+    /// CoAP response code of the failure, e.g. `5.00`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub code: Option<String>,
+
+    /// Machine readable error kind, as in [`DiagnosticError::kind`].
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub kind: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+}
+
+impl WriteResult {
+    fn new(name: &str, status: WriteStatus) -> Self {
+        Self {
+            name: name.to_owned(),
+            status,
+            code: None,
+            kind: None,
+            message: None,
+        }
+    }
+
+    fn set_error(&mut self, error: &HalError) -> ResponseType {
+        let diagnostic = DiagnosticError::from_hal(error);
+        self.code = Some(format_code(diagnostic.code));
+        self.kind = Some(diagnostic.kind);
+        self.message = Some(diagnostic.message);
+        diagnostic.code
+    }
+}
+
+pub struct WriteOutcome {
+    pub results: Vec<WriteResult>,
+
+    /// Response code of the first failed write, None if all of them succeeded.
+    pub failure: Option<ResponseType>,
+}
+
+impl WriteOutcome {
+    /// Response code for the batch as a whole, as described in the module docs.
+    pub fn response_code(&self) -> ResponseType {
+        let is_applied =
+            |r: &WriteResult| matches!(r.status, WriteStatus::Ok | WriteStatus::RollbackFailed);
+        match self.failure {
+            None => ResponseType::Changed,
+            Some(_) if self.results.iter().any(is_applied) => PARTIAL_SUCCESS,
+            Some(code) => code,
+        }
+    }
+}
+
+/// Writes `writes` (attribute name and HAL value pairs) in the order described in the module docs,
+/// reporting results in that order too.  Only fails outright if the snapshot needed for `atomic`
+/// can't be taken, in which case nothing has been written yet.
+pub fn apply_writes(
+    device: &mut dyn HalDevice,
+    attributes: &[HalAttribute],
+    writes: &[(String, String)],
+    atomic: bool,
+) -> HalResult<WriteOutcome> {
+    let writes = order_writes(writes);
+
+    let snapshot = if atomic {
+        take_snapshot(device, attributes, &writes)?
+    } else {
+        Vec::new()
+    };
+
+    let mut results = Vec::with_capacity(writes.len());
+    let mut failure = None;
+    for (name, value) in &writes {
+        if failure.is_some() {
+            results.push(WriteResult::new(name, WriteStatus::Skipped));
+            continue;
+        }
+        match device.set_attribute_str(name, value) {
+            Ok(()) => results.push(WriteResult::new(name, WriteStatus::Ok)),
+            Err(e) => {
+                let mut result = WriteResult::new(name, WriteStatus::Error);
+                failure = Some(result.set_error(&e));
+                results.push(result);
+            }
+        }
+    }
+
+    if failure.is_some() && atomic {
+        roll_back(device, &snapshot, &mut results);
+    }
+
+    Ok(WriteOutcome { results, failure })
+}
+
+/// Position of the attribute in the write order, None for attributes that may go anywhere.
+fn write_group(name: &str) -> Option<u8> {
+    match name {
+        name if CONFIGURATION_ATTRIBUTES.contains(&name) => Some(0),
+        name if TRIGGER_ATTRIBUTES.contains(&name) => Some(2),
+        name if name.ends_with("_sp") => Some(1),
+        name if matches!(MotorUnitAttribute::parse(name), Some(unit) if unit.is_setpoint()) => {
+            Some(1)
+        }
+        _ => None,
+    }
+}
+
+/// Sorts the writes that belong to a [`write_group`] among the positions they were requested at,
+/// leaving the others in place.
+fn order_writes(writes: &[(String, String)]) -> Vec<(String, String)> {
+    let slots: Vec<_> = (0..writes.len())
+        .filter(|&i| write_group(&writes[i].0).is_some())
+        .collect();
+    let mut grouped: Vec<_> = slots.iter().map(|&i| writes[i].clone()).collect();
+    grouped.sort_by_key(|(name, _)| write_group(name));
+
+    let mut ordered = writes.to_vec();
+    for (slot, write) in slots.into_iter().zip(grouped) {
+        ordered[slot] = write;
+    }
+    ordered
+}
+
+/// Reads the current value of each readable attribute about to be written.
+fn take_snapshot(
+    device: &dyn HalDevice,
+    attributes: &[HalAttribute],
+    writes: &[(String, String)],
+) -> HalResult<Vec<(String, String)>> {
+    let mut snapshot = Vec::new();
+    for (name, _) in writes {
+        let is_readable = attributes.iter().any(|a| &a.name == name && a.is_readable);
+        if is_readable && !snapshot.iter().any(|(n, _)| n == name) {
+            snapshot.push((name.clone(), device.get_attribute_str(name)?));
+        }
+    }
+    Ok(snapshot)
+}
+
+/// Restores the snapshot values of the successful writes, in reverse order.
+fn roll_back(
+    device: &mut dyn HalDevice,
+    snapshot: &[(String, String)],
+    results: &mut [WriteResult],
+) {
+    for result in results.iter_mut().rev() {
+        if result.status != WriteStatus::Ok {
+            continue;
+        }
+        let previous = snapshot.iter().find(|(name, _)| name == &result.name);
+        match previous {
+            Some((name, value)) => {
+                debug!("Rolling back {}={}...", name, value);
+                match device.set_attribute_str(name, value) {
+                    Ok(()) => result.status = WriteStatus::RolledBack,
+                    Err(e) => {
+                        warn!("Failed to roll back {}: {}", name, e);
+                        result.status = WriteStatus::RollbackFailed;
+                        result.set_error(&e);
+                    }
+                }
+            }
+            None => {
+                result.status = WriteStatus::RollbackFailed;
+                result.message = Some("Write-only attribute can't be restored".to_owned());
+            }
+        }
+    }
+}
+
+fn format_code(code: ResponseType) -> String {
+    let code = u8::from(MessageClass::Response(code));
+    format!("{}.{:02}", code >> 5, code & 0x1f)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hal::{Hal, HalAttributeType, HalDeviceType, WatchHandle};
+    use crate::hal_mock::HalMock;
+    use anyhow::anyhow;
+    use std::collections::BTreeMap;
+
+    /// Device whose writes to `broken` always fail.
+    struct FakeMotor {
+        values: BTreeMap<String, String>,
+
+        /// Successful writes so far, in the order they were made.
+        writes: Vec<(String, String)>,
+    }
+
+    impl FakeMotor {
+        fn new() -> Self {
+            let values = [("speed_sp", "0"), ("time_sp", "0"), ("broken", "0")]
+                .into_iter()
+                .map(|(k, v)| (k.to_owned(), v.to_owned()))
+                .collect();
+            Self {
+                values,
+                writes: Vec::new(),
+            }
+        }
+    }
+
+    impl HalDevice for FakeMotor {
+        fn get_type(&self) -> HalResult<HalDeviceType> {
+            Ok(HalDeviceType::Actuator)
+        }
+
+        fn get_driver_name(&self) -> HalResult<String> {
+            Ok("lego-ev3-l-motor".to_owned())
+        }
+
+        fn get_address(&self) -> HalResult<String> {
+            Ok("ev3-ports:outA".to_owned())
+        }
+
+        fn get_applicable_attributes(&self) -> HalResult<Vec<HalAttribute>> {
+            let mut attributes: Vec<_> = self
+                .values
+                .keys()
+                .map(|name| HalAttribute::new_rw(HalAttributeType::Int32, name))
+                .collect();
+            attributes.push(HalAttribute::new_writeonly(
+                HalAttributeType::String,
+                "command",
+            ));
+            Ok(attributes)
+        }
+
+        fn get_attribute_str(&self, name: &str) -> HalResult<String> {
+            self.values
+                .get(name)
+                .cloned()
+                .ok_or(HalError::NotApplicable)
+        }
+
+        fn set_attribute_str(&mut self, name: &str, value: &str) -> HalResult<()> {
+            match name {
+                "broken" => Err(HalError::Busy),
+                "command" => {
+                    self.writes.push((name.to_owned(), value.to_owned()));
+                    Ok(())
+                }
+                _ => {
+                    self.writes.push((name.to_owned(), value.to_owned()));
+                    self.values.insert(name.to_owned(), value.to_owned());
+                    Ok(())
+                }
+            }
+        }
+
+        fn watch_attributes(&self, _names: &[String]) -> anyhow::Result<WatchHandle> {
+            Err(anyhow!("Not watchable"))
+        }
+    }
+
+    fn writes(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    fn statuses(outcome: &WriteOutcome) -> Vec<WriteStatus> {
+        outcome.results.iter().map(|r| r.status).collect()
+    }
+
+    #[test]
+    fn test_partial_failure() {
+        let mut device = FakeMotor::new();
+        let attributes = device.get_applicable_attributes().unwrap();
+        let writes = writes(&[("speed_sp", "500"), ("broken", "1"), ("time_sp", "1000")]);
+
+        let outcome = apply_writes(&mut device, &attributes, &writes, false).unwrap();
+        assert_eq!(
+            statuses(&outcome),
+            vec![WriteStatus::Ok, WriteStatus::Error, WriteStatus::Skipped]
+        );
+        assert_eq!(outcome.failure, Some(ResponseType::ServiceUnavailable));
+        assert_eq!(outcome.response_code(), PARTIAL_SUCCESS);
+        assert_eq!(outcome.results[1].kind, Some("busy"));
+        assert_eq!(device.values["speed_sp"], "500");
+        assert_eq!(device.values["time_sp"], "0");
+    }
+
+    #[test]
+    fn test_atomic_rollback() {
+        let mut device = FakeMotor::new();
+        let attributes = device.get_applicable_attributes().unwrap();
+        let writes = writes(&[
+            ("speed_sp", "500"),
+            ("time_sp", "1000"),
+            ("command", "run-timed"),
+            ("broken", "1"),
+        ]);
+
+        let outcome = apply_writes(&mut device, &attributes, &writes, true).unwrap();
+        assert_eq!(
+            statuses(&outcome),
+            vec![
+                WriteStatus::RolledBack,
+                WriteStatus::RolledBack,
+                WriteStatus::RollbackFailed,
+                WriteStatus::Error,
+            ]
+        );
+        assert_eq!(device.values["speed_sp"], "0");
+        assert_eq!(device.values["time_sp"], "0");
+        // `command` couldn't be undone.
+        assert_eq!(outcome.response_code(), PARTIAL_SUCCESS);
+
+        let ok = apply_writes(&mut device, &attributes, &writes[..2], true).unwrap();
+        assert_eq!(statuses(&ok), vec![WriteStatus::Ok, WriteStatus::Ok]);
+        assert_eq!(ok.failure, None);
+        assert_eq!(ok.response_code(), ResponseType::Changed);
+        assert_eq!(device.values["speed_sp"], "500");
+    }
+
+    #[test]
+    fn test_nothing_applied() {
+        let mut device = FakeMotor::new();
+        let attributes = device.get_applicable_attributes().unwrap();
+        let writes = writes(&[("broken", "1"), ("speed_sp", "500")]);
+
+        let outcome = apply_writes(&mut device, &attributes, &writes, false).unwrap();
+        assert_eq!(
+            statuses(&outcome),
+            vec![WriteStatus::Error, WriteStatus::Skipped]
+        );
+        assert_eq!(outcome.response_code(), ResponseType::ServiceUnavailable);
+    }
+
+    #[test]
+    fn test_setpoints_before_command() {
+        let mut device = FakeMotor::new();
+        let attributes = device.get_applicable_attributes().unwrap();
+        let requested = writes(&[
+            ("command", "run-to-abs-pos"),
+            ("position_sp", "200"),
+            ("stop_action", "hold"),
+            ("speed_sp", "500"),
+        ]);
+
+        let outcome = apply_writes(&mut device, &attributes, &requested, false).unwrap();
+        let applied: Vec<_> = outcome.results.iter().map(|r| r.name.as_str()).collect();
+        assert_eq!(
+            applied,
+            vec!["stop_action", "position_sp", "speed_sp", "command"]
+        );
+        let expected = [
+            ("stop_action", "hold"),
+            ("position_sp", "200"),
+            ("speed_sp", "500"),
+            ("command", "run-to-abs-pos"),
+        ];
+        assert_eq!(device.writes, writes(&expected));
+    }
+
+    #[test]
+    fn test_mock_motor_write_order() {
+        let hal = HalMock::with_hardcoded_devices();
+        let mut motor = hal.by_address("ev3-ports:outA").unwrap().unwrap();
+        let attributes = motor.get_applicable_attributes().unwrap();
+        let requested = writes(&[
+            ("command", "run-to-abs-pos"),
+            ("position_sp", "200"),
+            ("stop_action", "hold"),
+            ("speed_sp", "500"),
+        ]);
+
+        apply_writes(motor.as_mut(), &attributes, &requested, false).unwrap();
+        let applied: Vec<_> = hal
+            .motor_writes()
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect();
+        assert_eq!(
+            applied,
+            vec!["stop_action", "position_sp", "speed_sp", "command"]
+        );
+        assert_eq!(motor.get_attribute_str("state").unwrap(), "running");
+    }
+
+    #[test]
+    fn test_other_attributes_keep_their_position() {
+        let requested = writes(&[
+            ("command", "run-timed"),
+            ("broken", "1"),
+            ("time_sp", "1000"),
+        ]);
+        let ordered = order_writes(&requested);
+        let names: Vec<_> = ordered.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, vec!["time_sp", "broken", "command"]);
+    }
+
+    #[test]
+    fn test_unit_setpoints_are_setpoints() {
+        assert_eq!(write_group("speed_sp_rpm"), Some(1));
+        assert_eq!(write_group("position_sp_deg"), Some(1));
+        assert_eq!(write_group("position_deg"), None);
+        assert_eq!(write_group("speed_pid/Kp"), None);
+    }
+}
@@ -0,0 +1,132 @@
+//! Decoding of the lego-sensor `bin_data` attribute, which holds all of the current mode's values
+//! in one buffer.  Reading it once gives a consistent snapshot, whereas reading `value0`..`valueN`
+//! one file at a time can mix readings taken at different times.
+
+use crate::hal::HalAttributeType;
+
+/// Layout of each value in `bin_data`, as reported by `bin_data_format`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum BinDataFormat {
+    U8,
+    S8,
+    U16,
+    S16,
+    S16Be,
+    S32,
+    S32Be,
+    Float,
+}
+
+impl BinDataFormat {
+    pub fn parse(format: &str) -> Option<Self> {
+        match format.trim() {
+            "u8" => Some(Self::U8),
+            "s8" => Some(Self::S8),
+            "u16" => Some(Self::U16),
+            "s16" => Some(Self::S16),
+            "s16_be" => Some(Self::S16Be),
+            "s32" => Some(Self::S32),
+            "s32_be" => Some(Self::S32Be),
+            "float" => Some(Self::Float),
+            _ => None,
+        }
+    }
+
+    pub fn value_size(&self) -> usize {
+        match self {
+            Self::U8 | Self::S8 => 1,
+            Self::U16 | Self::S16 | Self::S16Be => 2,
+            Self::S32 | Self::S32Be | Self::Float => 4,
+        }
+    }
+
+    /// Type of the decoded values.
+    pub fn data_type(&self) -> HalAttributeType {
+        match self {
+            Self::Float => HalAttributeType::Float32,
+            _ => HalAttributeType::Int32,
+        }
+    }
+
+    /// Decodes the first `num_values` values from `data`, formatted as strings as if they had
+    /// been read from the corresponding `valueN` attributes.  Returns None if `data` is too short.
+    pub fn decode(&self, data: &[u8], num_values: usize) -> Option<Vec<String>> {
+        let size = self.value_size();
+        let data = data.get(..num_values * size)?;
+        let values = data
+            .chunks_exact(size)
+            .map(|chunk| match self {
+                Self::U8 => chunk[0].to_string(),
+                Self::S8 => (chunk[0] as i8).to_string(),
+                Self::U16 => u16::from_le_bytes([chunk[0], chunk[1]]).to_string(),
+                Self::S16 => i16::from_le_bytes([chunk[0], chunk[1]]).to_string(),
+                Self::S16Be => i16::from_be_bytes([chunk[0], chunk[1]]).to_string(),
+                Self::S32 => {
+                    i32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]).to_string()
+                }
+                Self::S32Be => {
+                    i32::from_be_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]).to_string()
+                }
+                Self::Float => {
+                    f32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]).to_string()
+                }
+            })
+            .collect();
+        Some(values)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// EV3 color sensor in `RGB-RAW` mode (s16, 3 values).
+    const RGB_RAW: &[u8] = include_bytes!("../testdata/bin_data/lego-ev3-color-rgb-raw.bin");
+
+    /// EV3 gyro sensor in `GYRO-G&A` mode (s16, 2 values), turned 90 degrees counterclockwise.
+    const GYRO_G_AND_A: &[u8] = include_bytes!("../testdata/bin_data/lego-ev3-gyro-g-and-a.bin");
+
+    /// EV3 IR sensor in `IR-SEEK` mode (s8, 8 values), beacon on channel 1 only.
+    const IR_SEEK: &[u8] = include_bytes!("../testdata/bin_data/lego-ev3-ir-seek.bin");
+
+    /// HiTechnic NXT compass in big endian mode (s16_be, 1 value).
+    const COMPASS: &[u8] = include_bytes!("../testdata/bin_data/ht-nxt-compass.bin");
+
+    fn decode(format: &str, data: &[u8], num_values: usize) -> Vec<String> {
+        BinDataFormat::parse(format)
+            .unwrap()
+            .decode(data, num_values)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_decode_fixtures() {
+        assert_eq!(decode("s16", RGB_RAW, 3), vec!["312", "455", "201"]);
+        assert_eq!(decode("s16", GYRO_G_AND_A, 2), vec!["-90", "5"]);
+        assert_eq!(
+            decode("s8", IR_SEEK, 8),
+            vec!["-12", "47", "0", "-128", "0", "-128", "0", "-128"]
+        );
+        assert_eq!(decode("s16_be", COMPASS, 1), vec!["271"]);
+    }
+
+    #[test]
+    fn test_decode_all_formats() {
+        let data = [0xfe, 0xff, 0xff, 0xff];
+        assert_eq!(decode("u8", &data, 2), vec!["254", "255"]);
+        assert_eq!(decode("s8", &data, 2), vec!["-2", "-1"]);
+        assert_eq!(decode("u16", &data, 1), vec!["65534"]);
+        assert_eq!(decode("s16", &data, 1), vec!["-2"]);
+        assert_eq!(decode("s16_be", &data, 1), vec!["-257"]);
+        assert_eq!(decode("s32", &data, 1), vec!["-2"]);
+        assert_eq!(decode("s32_be", &data, 1), vec!["-16777217"]);
+        assert_eq!(decode("float", &1.5f32.to_le_bytes(), 1), vec!["1.5"]);
+    }
+
+    #[test]
+    fn test_decode_short_data() {
+        let format = BinDataFormat::parse("s32").unwrap();
+        assert_eq!(format.decode(&[0, 0, 0, 0, 0], 2), None);
+        assert_eq!(BinDataFormat::parse("bogus"), None);
+    }
+}
@@ -0,0 +1,189 @@
+//! Time as seen by the mock HAL, so that tests can move it along by hand instead of waiting.
+//!
+//! The mock's simulated devices read [`Clock::now`] and anything that happens periodically (watch
+//! notifications, the status screen refreshing) is a timer started with [`Clock::run_at`].  In
+//! production that's [`SystemClock`], which runs timers on threads of their own; tests use
+//! [`VirtualClock`], which stands still until [`VirtualClock::advance`] is called and runs the
+//! timers that come due right there.
+
+use std::fmt::Debug;
+use std::sync::Arc;
+use std::thread;
+use std::time::Instant;
+
+#[cfg(test)]
+use std::sync::{Condvar, Mutex};
+#[cfg(test)]
+use std::time::Duration;
+
+/// Timer callback, called with the deadline it was due at.  Returns when to call it next, or
+/// None to stop.
+pub type Tick = Box<dyn FnMut(Instant) -> Option<Instant> + Send>;
+
+pub trait Clock: Debug + Send + Sync {
+    fn now(&self) -> Instant;
+
+    /// Blocks the calling thread until `now()` reaches `deadline`.
+    fn sleep_until(&self, deadline: Instant);
+
+    /// Calls `tick` once `now()` reaches `deadline`, and again at whatever deadline it returns
+    /// until it returns None.  Timers that are meant to stop along with a [`WatchHandle`] should
+    /// check for that on every tick.
+    ///
+    /// [`WatchHandle`]: crate::hal::WatchHandle
+    fn run_at(&self, deadline: Instant, tick: Tick);
+}
+
+#[derive(Debug, Default)]
+pub struct SystemClock;
+
+impl SystemClock {
+    pub fn shared() -> Arc<dyn Clock> {
+        Arc::new(SystemClock)
+    }
+}
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep_until(&self, deadline: Instant) {
+        thread::sleep(deadline.saturating_duration_since(Instant::now()));
+    }
+
+    fn run_at(&self, mut deadline: Instant, mut tick: Tick) {
+        thread::spawn(move || loop {
+            SystemClock.sleep_until(deadline);
+            match tick(deadline) {
+                Some(next) => deadline = next,
+                None => break,
+            }
+        });
+    }
+}
+
+/// A clock that only moves when told to.  It starts out at the real time it was created, as
+/// `Instant`s can't be made up from nothing.
+#[cfg(test)]
+pub struct VirtualClock {
+    now: Mutex<Instant>,
+    advanced: Condvar,
+    timers: Mutex<Vec<(Instant, Tick)>>,
+}
+
+#[cfg(test)]
+impl VirtualClock {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self {
+            now: Mutex::new(Instant::now()),
+            advanced: Condvar::new(),
+            timers: Mutex::new(Vec::new()),
+        })
+    }
+
+    /// Moves the clock forward by `by`, running the timers that come due on the calling thread
+    /// in order, with the clock set to their deadline.  Threads sleeping until then are woken
+    /// once it's done.
+    pub fn advance(&self, by: Duration) {
+        let end = self.now() + by;
+        while let Some((deadline, mut tick)) = self.next_timer(end) {
+            *self.now.lock().unwrap() = deadline;
+            // Called without holding any locks, so that it can read the clock or start timers.
+            if let Some(next) = tick(deadline) {
+                self.timers.lock().unwrap().push((next, tick));
+            }
+        }
+        *self.now.lock().unwrap() = end;
+        self.advanced.notify_all();
+    }
+
+    /// Number of timers that haven't stopped yet.
+    pub fn pending_timers(&self) -> usize {
+        self.timers.lock().unwrap().len()
+    }
+
+    /// Takes the earliest timer due by `end`.
+    fn next_timer(&self, end: Instant) -> Option<(Instant, Tick)> {
+        let mut timers = self.timers.lock().unwrap();
+        let earliest = (0..timers.len())
+            .filter(|&i| timers[i].0 <= end)
+            .min_by_key(|&i| timers[i].0)?;
+        Some(timers.remove(earliest))
+    }
+}
+
+#[cfg(test)]
+impl Debug for VirtualClock {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("VirtualClock")
+            .field("now", &self.now())
+            .field("timers", &self.pending_timers())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+impl Clock for VirtualClock {
+    fn now(&self) -> Instant {
+        *self.now.lock().unwrap()
+    }
+
+    fn sleep_until(&self, deadline: Instant) {
+        let now = self.now.lock().unwrap();
+        let _now = self
+            .advanced
+            .wait_while(now, |now| *now < deadline)
+            .unwrap();
+    }
+
+    fn run_at(&self, deadline: Instant, tick: Tick) {
+        self.timers.lock().unwrap().push((deadline, tick));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+
+    #[test]
+    fn test_virtual_clock_wakes_sleepers() {
+        let clock = VirtualClock::new();
+        let start = clock.now();
+        let sleeper = {
+            let clock = clock.clone();
+            thread::spawn(move || clock.sleep_until(start + Duration::from_secs(60)))
+        };
+
+        clock.advance(Duration::from_secs(59));
+        assert_eq!(clock.now(), start + Duration::from_secs(59));
+        assert!(!sleeper.is_finished());
+        clock.advance(Duration::from_secs(1));
+        sleeper.join().unwrap();
+    }
+
+    #[test]
+    fn test_virtual_clock_runs_timers() {
+        let clock = VirtualClock::new();
+        let start = clock.now();
+        let (tx, rx) = mpsc::channel();
+        let mut remaining = 3;
+        let tick_clock = clock.clone();
+        clock.run_at(
+            start + Duration::from_secs(1),
+            Box::new(move |deadline| {
+                tx.send(tick_clock.now() - start).unwrap();
+                remaining -= 1;
+                (remaining > 0).then(|| deadline + Duration::from_secs(2))
+            }),
+        );
+
+        clock.advance(Duration::from_millis(999));
+        assert!(rx.try_recv().is_err());
+        clock.advance(Duration::from_secs(10));
+        let ticks: Vec<_> = rx.try_iter().map(|d| d.as_secs()).collect();
+        assert_eq!(ticks, vec![1, 3, 5]);
+        assert_eq!(clock.now(), start + Duration::from_millis(10999));
+    }
+}
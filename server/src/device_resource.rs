@@ -40,6 +40,14 @@
 //! **name**: string - attribute name
 //! **is_readable**: bool - can this be read from?
 //! **is_writable**: bool - can this be written to?
+//! **units**: optional string - unit of the scaled value, e.g. `pct` or `C`
+//! **decimals**: optional int - raw integer values are the actual value times `10^decimals`.  Both
+//!    `units` and `decimals` can change along with the device's `mode`.
+//! **allowed_values_from**: optional string - name of the array attribute listing the values this
+//!    attribute accepts, e.g. `modes` for `mode`
+//! **min**: optional int - smallest value the attribute accepts, e.g. `-100` for `duty_cycle_sp`
+//! **max**: optional int - largest value the attribute accepts.  Some limits come from other
+//!    attributes, e.g. `speed_sp` is limited by the motor's `max_speed`.
 //!
 //! ### Example:
 //! ```
@@ -48,6 +56,8 @@
 //!   "name": "duty_cycle_sp",
 //!   "is_readable": true,
 //!   "is_writable": true,
+//!   "min": -100,
+//!   "max": 100,
 //! }
 //! ```
 //!
@@ -68,6 +78,26 @@
 //! }
 //! ```
 //!
+//! # Errors
+//!
+//! Failures the client can act on are reported with a JSON payload along with the response code:
+//!
+//! **kind**: string - machine readable error kind, see below
+//! **message**: string - human readable description
+//! **device**: optional string - address of the device involved
+//! **attribute**: optional string - name of the attribute involved
+//! **allowed_values**: optional array of string - values the attribute accepts
+//!
+//! Kinds and their response codes:
+//!
+//! * 4.00 `invalid_value` - the value was rejected, either up front or by the device driver
+//! * 4.03 `not_writable`, `permission_denied` - the attribute can't be written (or read)
+//! * 4.04 `unknown_attribute`, `device_gone`, `not_connected` - the attribute or device doesn't
+//!   exist (anymore)
+//! * 4.05 `not_applicable` - the device doesn't support the request, e.g. blob uploads
+//! * 5.03 `busy` - the device can't handle the request right now, try again later
+//! * 5.00 `io`, `internal` - anything else
+//!
 //! # Requests
 //!
 //! ## GET /devices
@@ -90,19 +120,74 @@
 //!
 //! ## GET /device/<address>/attributes
 //!
-//! Read all attribute values
+//! Read all attribute values.  Add the `scaled=1` query parameter to any of the attribute GET
+//! requests to have values with `decimals` converted to floating point, e.g. `213` becomes `21.3`.
 //!
 //! Response Type: array of AttributeValue
 //!
 //! ## PUT /device/<address>/attributes
 //!
-//! Write multiple attribute values
+//! Write multiple attribute values.  Values are checked before anything is written: unknown
+//! attributes fail the request with 4.04 and read-only ones with 4.03.  Values of the wrong type
+//! (e.g. `"100"` or `1.5` for an integer), outside of `min`..`max`, or not listed by the
+//! attribute's `allowed_values_from` fail the request with 4.00.  All of these come with a JSON
+//! payload such as:
+//!
+//! ```
+//! {
+//!   "kind": "invalid_value",
+//!   "message": "\"spin\" is not one of commands",
+//!   "device": "ev3-ports:outA",
+//!   "attribute": "command",
+//!   "allowed_values": ["run-forever", "run-to-abs-pos", ...],
+//! }
+//! ```
+//!
+//! Writes are then applied in the following order, regardless of the order in the request, and
+//! stop at the first failure:
+//!
+//! 1. configuration, i.e. `stop_action` and `polarity`
+//! 2. setpoints (`*_sp`, `*_sp_deg`, `*_sp_rpm`)
+//! 3. `command` and `mode`
+//!
+//! This way e.g. `run-to-abs-pos` always acts on the `position_sp` and `speed_sp` sent along with
+//! it.  Writes within the same group keep their requested order, and writes to other attributes
+//! keep their position in the request.
+//!
+//! Add the `atomic=1` query parameter to have the previous values of the written attributes
+//! restored if any write fails (write-only attributes such as `command` can't be restored).  The
+//! response is 2.04 if all writes succeeded, 4.09 if some of them succeeded and still apply,
+//! otherwise the code of the failed write, e.g. 5.00.  The payload holds the outcome of each
+//! write, in the order they were applied:
+//!
+//! ```
+//! [
+//!   { "name": "speed_sp", "status": "rolled_back" },
+//!   { "name": "time_sp", "status": "error", "code": "5.00", "message": "..." },
+//!   { "name": "command", "status": "skipped" },
+//! ]
+//! ```
+//!
+//! `status` is one of `ok`, `error`, `skipped`, `rolled_back` or `rollback_failed`.
 //!
 //! Request Type: array of AttributeValue
 //!
+//! Response Type: array of WriteResult
+//!
+//! ## PUT /device/<address>/blobs/<name>
+//!
+//! Upload binary content to devices that accept it, for example a WAV clip for the sound device
+//! which can then be played by writing its name to the `play` attribute, or a PBM/PNG `image` for
+//! the display device.  The payload has to fit in a single request.  Devices that don't accept
+//! uploads respond with 4.05.
+//!
+//! Request Type: raw bytes
+//!
 //! ## GET /device/<address>/attributes/<attribute>
 //!
-//! Read a specific attribute value.
+//! Read a specific attribute value.  Sensors with multiple values per reading also provide a
+//! `values` array which, unlike reading `value0,value1,...`, is guaranteed to come from a single
+//! reading.
 //!
 //! Response Type: AttributeValue
 //!
@@ -112,12 +197,14 @@
 //!
 //! Response Type: array of AttributeValue
 
-use crate::anyhow_error_wrapper::AnyhowErrorWrapper;
+use crate::anyhow_error_wrapper::{with_device, AnyhowErrorWrapper};
+use crate::attribute_validation::{find_attribute, resolve_bounds, validate_write};
+use crate::attribute_writes::apply_writes;
 use crate::attributes_observable::HalWatchAttributes;
 use crate::devices_observable::HalWatchDevices;
-use anyhow::anyhow;
+use crate::status_screen::RecordClients;
 use coap_lite::link_format::{LINK_ATTR_CONTENT_FORMAT, LINK_ATTR_RESOURCE_TYPE};
-use coap_lite::{ContentFormat, MessageClass, RequestType, ResponseType};
+use coap_lite::{CoapOption, ContentFormat, MessageClass, RequestType, ResponseType};
 use coap_server::app;
 use coap_server::app::{CoapError, Request, ResourceBuilder, Response};
 use serde::Deserialize;
@@ -136,14 +223,16 @@ pub fn device_resources() -> Vec<ResourceBuilder<SocketAddr>> {
             .link_attr(LINK_ATTR_RESOURCE_TYPE, "devices")
             .link_attr(LINK_ATTR_CONTENT_FORMAT, ContentFormat::ApplicationJSON)
             .observable(HalWatchDevices::default())
-            .get(AnyhowErrorWrapper::new(handle_list_devices)),
+            .get(RecordClients::new(AnyhowErrorWrapper::new(
+                handle_list_devices,
+            ))),
         app::resource("device")
             .link_attr(LINK_ATTR_RESOURCE_TYPE, "device")
             .link_attr(LINK_ATTR_CONTENT_FORMAT, ContentFormat::ApplicationJSON)
             .observable(watch_attributes)
-            .default_handler(AnyhowErrorWrapper::new(move |req| {
+            .default_handler(RecordClients::new(AnyhowErrorWrapper::new(move |req| {
                 handle_single_device(req, watch_attributes_for_handler.clone())
-            })),
+            }))),
     ]
     .into_iter()
     .collect()
@@ -185,12 +274,12 @@ async fn handle_single_device(
 
     let unmatched_path_for_iter = request.unmatched_path.clone();
     let mut path_iter = unmatched_path_for_iter.into_iter();
-    let device = match path_iter.next() {
-        None => Err(CoapError::bad_request("Missing address"))?,
-        Some(address) => hal.by_address(&address)?.ok_or_else(CoapError::not_found)?,
-    };
+    let address = path_iter
+        .next()
+        .ok_or_else(|| CoapError::bad_request("Missing address"))?;
+    let device = hal.by_address(&address)?.ok_or_else(CoapError::not_found)?;
 
-    match method {
+    let result = match method {
         RequestType::Get => handle_single_device_get(device, request, path_iter.as_slice()),
         RequestType::Put => {
             let unmatched_path_flat = request.unmatched_path.join("/");
@@ -209,7 +298,8 @@ async fn handle_single_device(
             put_result
         }
         _ => Err(CoapError::method_not_allowed())?,
-    }
+    };
+    result.map_err(|e| with_device(e, &address))
 }
 
 fn handle_single_device_get(
@@ -217,27 +307,15 @@ fn handle_single_device_get(
     request: Request<SocketAddr>,
     remaining_path: &[String],
 ) -> anyhow::Result<Response> {
+    let scaled = is_query_flag_set(&request, "scaled");
     let mut path_iter = remaining_path.iter();
     let payload = match path_iter.next() {
         None => serde_json::to_string(&Device::from_hal(device)?)?,
         Some(path) if path == "attributes" => match path_iter.next() {
-            None => {
-                let values: Result<Vec<_>, _> = device
-                    .get_applicable_attributes()?
-                    .into_iter()
-                    .map(|a| AttributeValue::from_hal(&device, &a))
-                    .collect();
-                serde_json::to_string(&values?)?
-            }
+            None => serde_json::to_string(&read_attributes(device, None, scaled)?)?,
             Some(attributes) if attributes.contains(',') => {
-                let attributes_vec: HashSet<_> = attributes.split(',').collect();
-                let values: Result<Vec<_>, _> = device
-                    .get_applicable_attributes()?
-                    .into_iter()
-                    .filter(|a| attributes_vec.contains(&a.name.as_str()))
-                    .map(|a| AttributeValue::from_hal(&device, &a))
-                    .collect();
-                serde_json::to_string(&values?)?
+                let names: HashSet<_> = attributes.split(',').collect();
+                serde_json::to_string(&read_attributes(device, Some(&names), scaled)?)?
             }
             Some(attribute) => {
                 let attribute = device
@@ -245,7 +323,7 @@ fn handle_single_device_get(
                     .into_iter()
                     .find(|a| &a.name == attribute)
                     .ok_or_else(CoapError::not_found)?;
-                let value = AttributeValue::from_hal(&device, &attribute)?;
+                let value = AttributeValue::from_hal(&device, &attribute, scaled)?;
                 serde_json::to_string(&value)?
             }
         },
@@ -260,6 +338,47 @@ fn handle_single_device_get(
     Ok(reply)
 }
 
+/// Reads the attributes named in `names`, or all readable ones.  Naming one that can't be read,
+/// e.g. a motor's write-only `command`, fails the whole request like reading it on its own would.
+fn read_attributes(
+    device: Box<dyn HalDevice>,
+    names: Option<&HashSet<&str>>,
+    scaled: bool,
+) -> anyhow::Result<Vec<AttributeValue>> {
+    let mut values = Vec::new();
+    for attribute in device.get_applicable_attributes()? {
+        match names {
+            Some(names) if !names.contains(attribute.name.as_str()) => continue,
+            Some(_) if !attribute.is_readable => {
+                Err(HalError::PermissionDenied(attribute.name.clone()))?
+            }
+            None if !attribute.is_readable => continue,
+            _ => {}
+        }
+        values.push(AttributeValue::from_hal(&device, &attribute, scaled)?);
+    }
+    Ok(values)
+}
+
+/// Checks for a boolean query parameter, e.g. `?scaled=1`.
+fn is_query_flag_set(request: &Request<SocketAddr>, flag: &str) -> bool {
+    let query = request
+        .original
+        .message
+        .get_option(CoapOption::UriQuery)
+        .map(|values| {
+            values
+                .iter()
+                .map(|v| String::from_utf8_lossy(v).into_owned())
+                .collect::<Vec<_>>()
+                .join("&")
+        })
+        .unwrap_or_default();
+    querystring::querify(&query)
+        .into_iter()
+        .any(|(key, value)| key == flag && matches!(value, "1" | "true"))
+}
+
 fn handle_single_device_put(
     mut device: Box<dyn HalDevice>,
     request: Request<SocketAddr>,
@@ -271,19 +390,38 @@ fn handle_single_device_put(
             let payload_str = String::from_utf8(request.original.message.payload.clone())?;
             let values = serde_json::from_str::<Vec<AttributeValue>>(&payload_str)?;
 
+            let attributes = device.get_applicable_attributes()?;
+            let mut writes = Vec::with_capacity(values.len());
             for value in &values {
-                // TODO: We really need to yield errors for each write, not just abort the
-                // whole thing with no reasonable rollback.
-                device.set_attribute_str(&value.name, &value.to_hal_value_str()?)?;
+                let attribute = find_attribute(&attributes, &value.name)?;
+                let value_str = validate_write(device.as_ref(), attribute, &value.value)?;
+                writes.push((value.name.clone(), value_str));
             }
+
+            let atomic = is_query_flag_set(&request, "atomic");
+            let outcome = apply_writes(device.as_mut(), &attributes, &writes, atomic)?;
+
+            let mut reply = request.new_response();
+            reply.message.header.code = MessageClass::Response(outcome.response_code());
+            reply
+                .message
+                .set_content_format(ContentFormat::ApplicationJSON);
+            reply.message.payload = serde_json::to_string(&outcome.results)?.into_bytes();
+            Ok(reply)
+        }
+        Some(path) if path == "blobs" => {
+            let name = path_iter
+                .next()
+                .ok_or_else(|| CoapError::bad_request("Missing blob name"))?;
+            device.put_blob(name, &request.original.message.payload)?;
+
+            let mut reply = request.new_response();
+            reply.message.header.code = MessageClass::Response(ResponseType::Changed);
+            reply.message.payload.clear();
+            Ok(reply)
         }
         _ => Err(CoapError::not_found())?,
     }
-
-    let mut reply = request.new_response();
-    reply.message.header.code = MessageClass::Response(ResponseType::Changed);
-    reply.message.payload.clear();
-    Ok(reply)
 }
 
 #[derive(Serialize, Deserialize)]
@@ -304,7 +442,7 @@ impl Device {
         let attributes = hal
             .get_applicable_attributes()?
             .into_iter()
-            .map(Attribute::from_hal)
+            .map(|a| Attribute::from_hal(hal.as_ref(), a))
             .collect();
         Ok(Self {
             type_name,
@@ -321,10 +459,20 @@ struct Attribute {
     name: String,
     is_readable: bool,
     is_writable: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    units: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    decimals: Option<u8>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    allowed_values_from: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    min: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    max: Option<i64>,
 }
 
 impl Attribute {
-    pub fn from_hal(hal: HalAttribute) -> Self {
+    pub fn from_hal(device: &dyn HalDevice, hal: HalAttribute) -> Self {
         let data_type = match hal.data_type {
             HalAttributeType::Int8 => "int8",
             HalAttributeType::Int16 => "int16",
@@ -344,11 +492,18 @@ impl Attribute {
         } else {
             data_type
         };
+        // Bounds taken from another attribute are best effort; a failed read just leaves them out.
+        let bounds = resolve_bounds(device, &hal).ok().flatten();
         Self {
             type_name,
             name: hal.name,
             is_readable: hal.is_readable,
             is_writable: hal.is_writable,
+            units: hal.units,
+            decimals: hal.decimals,
+            allowed_values_from: hal.allowed_values_from,
+            min: bounds.map(|(min, _)| min),
+            max: bounds.map(|(_, max)| max),
         }
     }
 }
@@ -360,17 +515,28 @@ struct AttributeValue {
 }
 
 impl AttributeValue {
-    pub fn from_hal(device: &Box<dyn HalDevice>, attr: &HalAttribute) -> HalResult<Self> {
+    /// Reads the attribute's current value.  With `scaled`, integer values are divided by
+    /// `10^decimals` (if the attribute has any) yielding floating point values.
+    pub fn from_hal(
+        device: &Box<dyn HalDevice>,
+        attr: &HalAttribute,
+        scaled: bool,
+    ) -> HalResult<Self> {
         let value_str = device.get_attribute_str(attr.name.as_str())?;
 
+        let convert = |v: &str| {
+            let value = Self::convert_value(attr, v)?;
+            match attr.decimals {
+                Some(decimals) if scaled && decimals > 0 => Ok(Self::scale_value(value, decimals)),
+                _ => Ok(value),
+            }
+        };
         let value = if attr.is_array {
-            let values: Result<Vec<_>, _> = value_str
-                .split(' ')
-                .map(|v| Self::convert_value(attr, v))
-                .collect();
+            let values: Result<Vec<_>, HalError> =
+                value_str.split_whitespace().map(convert).collect();
             serde_json::Value::Array(values?)
         } else {
-            Self::convert_value(attr, &value_str)?
+            convert(&value_str)?
         };
 
         Ok(Self {
@@ -379,22 +545,14 @@ impl AttributeValue {
         })
     }
 
-    fn to_hal_value_str(&self) -> anyhow::Result<String> {
-        match &self.value {
-            serde_json::Value::String(s) => Ok(s.clone()),
-            serde_json::Value::Number(n) => {
-                let num_as_str = if n.is_i64() {
-                    n.as_i64().map(|x| x.to_string())
-                } else if n.is_u64() {
-                    n.as_u64().map(|x| x.to_string())
-                } else if n.is_f64() {
-                    n.as_f64().map(|x| x.to_string())
-                } else {
-                    None
-                };
-                num_as_str.ok_or_else(|| anyhow!("n={}", n))
-            }
-            n => Err(anyhow!("Can't serialize {:?}", n)),
+    fn scale_value(value: serde_json::Value, decimals: u8) -> serde_json::Value {
+        let scaled = value
+            .as_f64()
+            .map(|raw| raw / 10f64.powi(decimals.into()))
+            .and_then(serde_json::Number::from_f64);
+        match scaled {
+            Some(scaled) => serde_json::Value::Number(scaled),
+            None => value,
         }
     }
 
@@ -430,3 +588,80 @@ impl AttributeValue {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hal::WatchHandle;
+    use anyhow::anyhow;
+
+    struct FakeSensor;
+
+    impl HalDevice for FakeSensor {
+        fn get_type(&self) -> HalResult<HalDeviceType> {
+            Ok(HalDeviceType::Sensor)
+        }
+
+        fn get_driver_name(&self) -> HalResult<String> {
+            Ok("lego-nxt-temp".to_owned())
+        }
+
+        fn get_address(&self) -> HalResult<String> {
+            Ok("ev3-ports:in1".to_owned())
+        }
+
+        fn get_applicable_attributes(&self) -> HalResult<Vec<HalAttribute>> {
+            Ok(vec![
+                HalAttribute::new_readonly(HalAttributeType::Int32, "value0")
+                    .with_units(Some("C".to_owned()), Some(1)),
+                HalAttribute::new_writeonly(HalAttributeType::String, "command"),
+            ])
+        }
+
+        fn get_attribute_str(&self, name: &str) -> HalResult<String> {
+            match name {
+                "value0" => Ok("213".to_owned()),
+                "pressed" => Ok("".to_owned()),
+                _ => Err(HalError::PermissionDenied(name.to_owned())),
+            }
+        }
+
+        fn set_attribute_str(&mut self, _name: &str, _value: &str) -> HalResult<()> {
+            Err(HalError::NotApplicable)
+        }
+
+        fn watch_attributes(&self, _names: &[String]) -> anyhow::Result<WatchHandle> {
+            Err(anyhow!("Not watchable"))
+        }
+    }
+
+    #[test]
+    fn test_scaled_values() {
+        let device: Box<dyn HalDevice> = Box::new(FakeSensor);
+        let attribute = device.get_applicable_attributes().unwrap().remove(0);
+
+        let raw = AttributeValue::from_hal(&device, &attribute, false).unwrap();
+        assert_eq!(raw.value, serde_json::json!(213));
+        let scaled = AttributeValue::from_hal(&device, &attribute, true).unwrap();
+        assert_eq!(scaled.value, serde_json::json!(21.3));
+    }
+
+    #[test]
+    fn test_empty_array() {
+        let device: Box<dyn HalDevice> = Box::new(FakeSensor);
+        let attribute = HalAttribute::new_readonly_array(HalAttributeType::String, "pressed");
+
+        let value = AttributeValue::from_hal(&device, &attribute, false).unwrap();
+        assert_eq!(value.value, serde_json::json!([]));
+    }
+
+    #[test]
+    fn test_read_attributes_skips_write_only() {
+        let all = read_attributes(Box::new(FakeSensor), None, false).unwrap();
+        let names: Vec<_> = all.iter().map(|v| v.name.as_str()).collect();
+        assert_eq!(names, ["value0"]);
+
+        let selected = HashSet::from(["value0", "command"]);
+        assert!(read_attributes(Box::new(FakeSensor), Some(&selected), false).is_err());
+    }
+}
@@ -0,0 +1,316 @@
+//! Minimal Linux framebuffer support for the EV3's LCD.  Drawing happens on a monochrome
+//! [`Canvas`] which is then converted to the framebuffer's pixel format and written out in one go.
+//! A [`Framebuffer`] can equally be backed by a regular file, which lets rendering be exercised
+//! off-brick.
+
+use std::convert::Infallible;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use embedded_graphics::mono_font::ascii::FONT_6X10;
+use embedded_graphics::mono_font::MonoTextStyle;
+use embedded_graphics::pixelcolor::BinaryColor;
+use embedded_graphics::prelude::*;
+use embedded_graphics::text::{Baseline, Text};
+use image::imageops::FilterType;
+use image::DynamicImage;
+
+pub const EV3_FRAMEBUFFER_DEVICE: &str = "/dev/fb0";
+pub const EV3_FRAMEBUFFER_SYSFS: &str = "/sys/class/graphics/fb0";
+
+const FONT_WIDTH: u32 = 6;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct FramebufferInfo {
+    pub width: u32,
+    pub height: u32,
+    pub bits_per_pixel: u32,
+    /// Bytes per row, which may include padding.
+    pub stride: u32,
+}
+
+impl FramebufferInfo {
+    /// Geometry of the EV3 LCD as exposed by ev3dev-stretch.
+    pub const EV3: Self = Self {
+        width: 178,
+        height: 128,
+        bits_per_pixel: 32,
+        stride: 178 * 4,
+    };
+
+    pub fn from_sysfs(sysfs_path: &Path) -> io::Result<Self> {
+        let read = |name: &str| -> io::Result<String> {
+            Ok(fs::read_to_string(sysfs_path.join(name))?.trim().to_owned())
+        };
+        let parse = |value: &str| -> io::Result<u32> {
+            value
+                .parse()
+                .map_err(|_| invalid_data(format!("Unexpected framebuffer value: {value}")))
+        };
+
+        let virtual_size = read("virtual_size")?;
+        let (width, height) = virtual_size
+            .split_once(',')
+            .ok_or_else(|| invalid_data(format!("Unexpected virtual_size: {virtual_size}")))?;
+        Ok(Self {
+            width: parse(width)?,
+            height: parse(height)?,
+            bits_per_pixel: parse(&read("bits_per_pixel")?)?,
+            stride: parse(&read("stride")?)?,
+        })
+    }
+
+    fn buffer_len(&self) -> usize {
+        (self.stride * self.height) as usize
+    }
+}
+
+pub struct Framebuffer {
+    path: PathBuf,
+    info: FramebufferInfo,
+}
+
+impl Framebuffer {
+    pub fn open_ev3() -> io::Result<Self> {
+        let info = FramebufferInfo::from_sysfs(Path::new(EV3_FRAMEBUFFER_SYSFS))?;
+        Self::check_supported(&info)?;
+        Ok(Self {
+            path: PathBuf::from(EV3_FRAMEBUFFER_DEVICE),
+            info,
+        })
+    }
+
+    /// Stand-in for a real framebuffer device, using the same pixel layout but writing to a
+    /// regular file at `path`.
+    pub fn file_backed(path: PathBuf, info: FramebufferInfo) -> io::Result<Self> {
+        Self::check_supported(&info)?;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(&path, vec![0u8; info.buffer_len()])?;
+        Ok(Self { path, info })
+    }
+
+    pub fn info(&self) -> FramebufferInfo {
+        self.info
+    }
+
+    pub fn write(&self, canvas: &Canvas) -> io::Result<()> {
+        let mut buffer = vec![0u8; self.info.buffer_len()];
+        for y in 0..self.info.height.min(canvas.height) {
+            let row = &mut buffer[(y * self.info.stride) as usize..];
+            for x in 0..self.info.width.min(canvas.width) {
+                let is_ink = canvas.pixel(x, y);
+                match self.info.bits_per_pixel {
+                    // Set bits are black, least significant bit first.
+                    1 => {
+                        if is_ink {
+                            row[(x / 8) as usize] |= 1 << (x % 8);
+                        }
+                    }
+                    // XRGB
+                    _ => {
+                        let level = if is_ink { 0x00 } else { 0xff };
+                        let offset = (x * 4) as usize;
+                        row[offset..offset + 3].fill(level);
+                    }
+                }
+            }
+        }
+        let mut file = OpenOptions::new().write(true).open(&self.path)?;
+        file.write_all(&buffer)
+    }
+
+    /// Reads back the current contents of a file-backed framebuffer.
+    #[cfg(test)]
+    pub fn read(&self) -> io::Result<Canvas> {
+        let buffer = fs::read(&self.path)?;
+        if buffer.len() < self.info.buffer_len() {
+            return Err(invalid_data(format!(
+                "Framebuffer too short: {} bytes",
+                buffer.len()
+            )));
+        }
+        let mut canvas = Canvas::new(self.info.width, self.info.height);
+        for y in 0..self.info.height {
+            let row = &buffer[(y * self.info.stride) as usize..];
+            for x in 0..self.info.width {
+                let is_ink = match self.info.bits_per_pixel {
+                    1 => row[(x / 8) as usize] & (1 << (x % 8)) != 0,
+                    _ => row[(x * 4) as usize] < 0x80,
+                };
+                canvas.set_pixel(x, y, is_ink);
+            }
+        }
+        Ok(canvas)
+    }
+
+    fn check_supported(info: &FramebufferInfo) -> io::Result<()> {
+        match info.bits_per_pixel {
+            1 | 32 => Ok(()),
+            bpp => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("Unsupported framebuffer depth: {bpp}bpp"),
+            )),
+        }
+    }
+}
+
+/// Monochrome drawing surface where `true` pixels are "ink" (black on the EV3 LCD).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Canvas {
+    width: u32,
+    height: u32,
+    pixels: Vec<bool>,
+}
+
+impl Canvas {
+    pub fn new(width: u32, height: u32) -> Self {
+        Self {
+            width,
+            height,
+            pixels: vec![false; (width * height) as usize],
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.pixels.fill(false);
+    }
+
+    pub fn pixel(&self, x: u32, y: u32) -> bool {
+        x < self.width && y < self.height && self.pixels[(y * self.width + x) as usize]
+    }
+
+    pub fn set_pixel(&mut self, x: u32, y: u32, is_ink: bool) {
+        if x < self.width && y < self.height {
+            self.pixels[(y * self.width + x) as usize] = is_ink;
+        }
+    }
+
+    #[cfg(test)]
+    pub fn ink_count(&self) -> usize {
+        self.pixels.iter().filter(|&&p| p).count()
+    }
+
+    /// Draws `text` from the top left corner, wrapping lines that don't fit.
+    pub fn draw_text(&mut self, text: &str) {
+        let max_chars = (self.width / FONT_WIDTH).max(1) as usize;
+        let wrapped = wrap_text(text, max_chars);
+        let style = MonoTextStyle::new(&FONT_6X10, BinaryColor::On);
+        // Drawing onto a canvas is infallible.
+        let _ = Text::with_baseline(&wrapped, Point::zero(), style, Baseline::Top).draw(self);
+    }
+
+    /// Draws an image scaled down to fit (if necessary) and centered.  Dark pixels become ink.
+    pub fn draw_image(&mut self, image: &DynamicImage) {
+        let fitted = if image.width() > self.width || image.height() > self.height {
+            image.resize(self.width, self.height, FilterType::Nearest)
+        } else {
+            image.clone()
+        };
+        let gray = fitted.to_luma8();
+        let left = (self.width - gray.width()) / 2;
+        let top = (self.height - gray.height()) / 2;
+        for (x, y, pixel) in gray.enumerate_pixels() {
+            self.set_pixel(left + x, top + y, pixel.0[0] < 0x80);
+        }
+    }
+}
+
+impl OriginDimensions for Canvas {
+    fn size(&self) -> Size {
+        Size::new(self.width, self.height)
+    }
+}
+
+impl DrawTarget for Canvas {
+    type Color = BinaryColor;
+    type Error = Infallible;
+
+    fn draw_iter<I>(&mut self, pixels: I) -> Result<(), Self::Error>
+    where
+        I: IntoIterator<Item = Pixel<Self::Color>>,
+    {
+        for Pixel(point, color) in pixels {
+            if let (Ok(x), Ok(y)) = (u32::try_from(point.x), u32::try_from(point.y)) {
+                self.set_pixel(x, y, color.is_on());
+            }
+        }
+        Ok(())
+    }
+}
+
+fn wrap_text(text: &str, max_chars: usize) -> String {
+    let mut lines = Vec::new();
+    for line in text.lines() {
+        let chars: Vec<_> = line.chars().collect();
+        if chars.is_empty() {
+            lines.push(String::new());
+        }
+        for chunk in chars.chunks(max_chars) {
+            lines.push(chunk.iter().collect());
+        }
+    }
+    lines.join("\n")
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn checkerboard(width: u32, height: u32) -> Canvas {
+        let mut canvas = Canvas::new(width, height);
+        for y in 0..height {
+            for x in 0..width {
+                canvas.set_pixel(x, y, (x + y) % 2 == 0);
+            }
+        }
+        canvas
+    }
+
+    #[test]
+    fn test_file_backed_round_trip() {
+        let tempdir = tempfile::tempdir().unwrap();
+        for bits_per_pixel in [1, 32] {
+            let info = FramebufferInfo {
+                width: 178,
+                height: 128,
+                bits_per_pixel,
+                stride: if bits_per_pixel == 1 { 24 } else { 178 * 4 },
+            };
+            let path = tempdir.path().join(format!("fb-{bits_per_pixel}"));
+            let framebuffer = Framebuffer::file_backed(path, info).unwrap();
+
+            let canvas = checkerboard(info.width, info.height);
+            framebuffer.write(&canvas).unwrap();
+            assert_eq!(framebuffer.read().unwrap(), canvas);
+        }
+    }
+
+    #[test]
+    fn test_draw_text_wraps() {
+        let mut canvas = Canvas::new(4 * FONT_WIDTH, 40);
+        canvas.draw_text("abcdefgh");
+        let second_line_ink = (10..20)
+            .flat_map(|y| (0..canvas.width).map(move |x| (x, y)))
+            .filter(|&(x, y)| canvas.pixel(x, y))
+            .count();
+        assert!(second_line_ink > 0);
+    }
+
+    #[test]
+    fn test_draw_image_pbm() {
+        // 2x2 PBM with the top left pixel set (black).
+        let pbm = b"P1\n2 2\n1 0\n0 0\n";
+        let image = image::load_from_memory(pbm).unwrap();
+        let mut canvas = Canvas::new(4, 4);
+        canvas.draw_image(&image);
+        assert!(canvas.pixel(1, 1));
+        assert_eq!(canvas.ink_count(), 1);
+    }
+}
@@ -1,19 +1,59 @@
+use std::io;
 use std::mem;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc::Receiver;
+use std::sync::{Arc, Mutex};
 
+use anyhow::bail;
 use lazy_static::lazy_static;
+use serde::Deserialize;
 use thiserror::Error;
 
+use crate::clock::SystemClock;
+use crate::hal_display::Display;
 use crate::hal_ev3::HalEv3;
+use crate::hal_faulty::{FaultConfig, HalFaulty};
 use crate::hal_mock::HalMock;
 
-const LEGO_PORT_ROOT: &str = "/sys/class/lego-port";
+/// Where the kernel mounts sysfs.
+pub(crate) const SYSFS_ROOT: &str = "/sys";
+
+/// Environment variable pointing the EV3 HAL at a different sysfs tree, e.g. a copy of a brick's
+/// for development off the brick.
+const SYSFS_ROOT_ENV: &str = "EV3_SYSFS_ROOT";
 
 lazy_static! {
     pub static ref HAL: Box<dyn Hal + Sync> = { HalFactory::sense_from_environment() };
 }
 
+/// Set as soon as [`HAL`] starts picking an implementation, after which it's too late to
+/// configure it.
+static HAL_SENSED: AtomicBool = AtomicBool::new(false);
+
+/// Mock set up by [`use_mock`], which takes precedence over sensing the environment.
+static CONFIGURED_MOCK: Mutex<Option<HalMock>> = Mutex::new(None);
+
+/// Makes [`HAL`] the given mock regardless of what the server is running on, e.g. one simulating
+/// a scenario.  Fails if [`HAL`] has already been used.
+pub fn use_mock(mock: HalMock) -> anyhow::Result<()> {
+    let mut configured = CONFIGURED_MOCK.lock().unwrap();
+    if HAL_SENSED.load(Ordering::SeqCst) {
+        bail!("Too late to use a mock, the HAL is already in use");
+    }
+    *configured = Some(mock);
+    Ok(())
+}
+
+/// Faults set up by [`use_faults`], injected into whichever HAL is picked.
+static CONFIGURED_FAULTS: Mutex<Option<FaultConfig>> = Mutex::new(None);
+
+/// Makes [`HAL`] slow down and fail as configured, see [`HalFaulty`].  Must be called before
+/// [`HAL`] is first used.
+pub fn use_faults(config: FaultConfig) {
+    *CONFIGURED_FAULTS.lock().unwrap() = Some(config);
+}
+
 pub trait Hal {
     fn list_devices(&self) -> HalResult<Vec<Box<dyn HalDevice>>>;
     fn by_driver(&self, driver: &str) -> HalResult<Vec<Box<dyn HalDevice>>>;
@@ -22,6 +62,20 @@ pub trait Hal {
     /// Watch for any change such that [`list_devices`] would yield a different result.  Any
     /// emission on the receiver indicates a change.
     fn watch_devices(&self) -> anyhow::Result<WatchHandle>;
+
+    /// Physical ports that devices can be plugged into, see [`HalPort`].
+    fn list_ports(&self) -> HalResult<Vec<Box<dyn HalPort>>>;
+    fn port_by_address(&self, address: &str) -> HalResult<Option<Box<dyn HalPort>>>;
+
+    /// Watch for any change such that [`list_ports`] would yield a different result, including
+    /// changes to the mode or status of any port.
+    fn watch_ports(&self) -> anyhow::Result<WatchHandle>;
+
+    /// Screen shared between the `display` device and the server's own status screen, if the
+    /// platform has one.
+    fn display(&self) -> Option<Arc<Display>> {
+        None
+    }
 }
 
 #[derive(Error, Debug)]
@@ -37,10 +91,59 @@ pub enum HalError {
         /// Device was expected to be on this port (None if no port was specified)
         port: Option<String>,
     },
+    /// The attribute can't be accessed this way, e.g. writing a read-only attribute.
+    #[error("permission denied: {0}")]
+    PermissionDenied(String),
+    /// The device rejected the value written.
+    #[error("invalid value: {0}")]
+    InvalidValue(String),
+    #[error("unknown attribute: {0}")]
+    UnknownAttribute(String),
+    /// The device was unplugged or otherwise went away while it was being accessed.
+    #[error("device gone")]
+    DeviceGone,
+    /// The device can't handle the request right now but may be able to later.
+    #[error("device busy")]
+    Busy,
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
 }
 
 pub type HalResult<T> = Result<T, HalError>;
 
+/// Error for the mock and the fault-injecting HAL to inject, named after the `kind` reported to
+/// clients.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FaultKind {
+    NotConnected,
+    DeviceGone,
+    PermissionDenied,
+    InvalidValue,
+    Busy,
+    Io,
+    Internal,
+}
+
+impl FaultKind {
+    /// The error for accessing `what` (e.g. an attribute name) on the device or port at
+    /// `address`.
+    pub fn to_hal_error(self, address: &str, what: &str) -> HalError {
+        match self {
+            FaultKind::NotConnected => HalError::NotConnected {
+                device: address.to_owned(),
+                port: None,
+            },
+            FaultKind::DeviceGone => HalError::DeviceGone,
+            FaultKind::PermissionDenied => HalError::PermissionDenied(what.to_owned()),
+            FaultKind::InvalidValue => HalError::InvalidValue(format!("Injected for {what}")),
+            FaultKind::Busy => HalError::Busy,
+            FaultKind::Io => HalError::Io(io::Error::other(format!("Injected for {what}"))),
+            FaultKind::Internal => HalError::InternalError(format!("Injected for {what}")),
+        }
+    }
+}
+
 pub struct WatchHandle {
     /// Dropping this will stop the watch.
     cancel_handle: Option<Box<dyn Drop + Send>>,
@@ -73,11 +176,37 @@ pub trait HalDevice {
     fn get_attribute_str(&self, name: &str) -> HalResult<String>;
     fn set_attribute_str(&mut self, name: &str, value: &str) -> HalResult<()>;
 
+    /// Store binary content such as a sound clip under `name`.  Devices which don't accept
+    /// uploads yield [`HalError::NotApplicable`].
+    fn put_blob(&mut self, _name: &str, _data: &[u8]) -> HalResult<()> {
+        Err(HalError::NotApplicable)
+    }
+
     /// Watch for any change such that [`get_attribute_str`] would yield a different result
     /// for any of the provided set of names.  Any emission on the receiver indicates a change.
     fn watch_attributes(&self, names: &[String]) -> anyhow::Result<WatchHandle>;
 }
 
+/// A port such as `ev3-ports:in1` which devices are connected to.  Most of the time ports detect
+/// what's plugged in by themselves (mode `auto`), but some devices (e.g. third-party I2C sensors)
+/// need the port to be switched to a specific mode and told which driver to load.
+pub trait HalPort {
+    fn get_address(&self) -> HalResult<String>;
+    fn get_driver_name(&self) -> HalResult<String>;
+    fn get_modes(&self) -> HalResult<Vec<String>>;
+    fn get_mode(&self) -> HalResult<String>;
+
+    /// Port specific status, typically the name of the connected device or something like
+    /// `no-sensor`.
+    fn get_status(&self) -> HalResult<String>;
+
+    fn set_mode(&mut self, mode: &str) -> HalResult<()>;
+
+    /// Loads the given driver for the device on this port.  Only supported in modes that can't
+    /// detect the device automatically.
+    fn set_device(&mut self, driver_name: &str) -> HalResult<()>;
+}
+
 #[derive(Debug, Copy, Clone)]
 pub enum HalDeviceType {
     Sensor,
@@ -91,6 +220,20 @@ pub struct HalAttribute {
     pub name: String,
     pub is_readable: bool,
     pub is_writable: bool,
+
+    /// Unit of the (scaled) value, e.g. `pct` or `deg`, if known.
+    pub units: Option<String>,
+
+    /// Integer values are reported as `value * 10^decimals`, e.g. a reading of `213` with
+    /// `decimals` of 1 means 21.3.  Depends on the current mode for most sensors.
+    pub decimals: Option<u8>,
+
+    /// Name of the (array) attribute listing the values this one accepts, e.g. `modes` for
+    /// `mode`.
+    pub allowed_values_from: Option<String>,
+
+    /// Range of values accepted on write, where narrower than what `data_type` allows.
+    pub bounds: Option<HalBounds>,
 }
 
 impl HalAttribute {
@@ -101,6 +244,10 @@ impl HalAttribute {
             name: name.to_owned(),
             is_readable: true,
             is_writable: true,
+            units: None,
+            decimals: None,
+            allowed_values_from: None,
+            bounds: None,
         }
     }
 
@@ -111,6 +258,10 @@ impl HalAttribute {
             name: name.to_owned(),
             is_readable: true,
             is_writable: false,
+            units: None,
+            decimals: None,
+            allowed_values_from: None,
+            bounds: None,
         }
     }
 
@@ -121,6 +272,10 @@ impl HalAttribute {
             name: name.to_owned(),
             is_readable: false,
             is_writable: true,
+            units: None,
+            decimals: None,
+            allowed_values_from: None,
+            bounds: None,
         }
     }
 
@@ -131,11 +286,82 @@ impl HalAttribute {
             name: name.to_owned(),
             is_readable: true,
             is_writable: false,
+            units: None,
+            decimals: None,
+            allowed_values_from: None,
+            bounds: None,
         }
     }
+
+    /// Attaches unit and scaling metadata, see [`HalAttribute::decimals`].
+    pub fn with_units(mut self, units: Option<String>, decimals: Option<u8>) -> Self {
+        self.units = units;
+        self.decimals = decimals;
+        self
+    }
+
+    /// Restricts writes to the values listed by the `allowed_values` attribute.
+    pub fn with_allowed_values_from(mut self, allowed_values: &str) -> Self {
+        self.allowed_values_from = Some(allowed_values.to_owned());
+        self
+    }
+
+    pub fn with_bounds(mut self, bounds: HalBounds) -> Self {
+        self.bounds = Some(bounds);
+        self
+    }
 }
 
-#[derive(Debug, Copy, Clone)]
+/// Inclusive range of values an attribute accepts.  Some limits depend on the device, in which
+/// case they are read from another attribute.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HalBounds {
+    Fixed {
+        min: i64,
+        max: i64,
+    },
+
+    /// `-limit..=limit` where `limit` is the value of the named attribute, e.g. `max_speed`.
+    Symmetric(String),
+
+    /// `0..=limit` where `limit` is the value of the named attribute, e.g. `max_brightness`.
+    UpTo(String),
+}
+
+/// Returns the range of values the named ev3dev attribute accepts, if narrower than its type.
+/// `attributes` are the other attributes of the same device, which some limits are taken from.
+pub fn attribute_bounds(name: &str, attributes: &[HalAttribute]) -> Option<HalBounds> {
+    let has_attribute = |name: &str| attributes.iter().any(|a| a.name == name);
+    match name {
+        "duty_cycle_sp" => Some(HalBounds::Fixed {
+            min: -100,
+            max: 100,
+        }),
+        "ramp_down_sp" | "ramp_up_sp" => Some(HalBounds::Fixed { min: 0, max: 60000 }),
+        "time_sp" => Some(HalBounds::Fixed {
+            min: 0,
+            max: i32::MAX.into(),
+        }),
+        "speed_sp" if has_attribute("max_speed") => {
+            Some(HalBounds::Symmetric("max_speed".to_owned()))
+        }
+        _ => None,
+    }
+}
+
+/// Fills in [`HalAttribute::bounds`] for all known attributes according to [`attribute_bounds`].
+pub fn apply_bounds(attributes: &mut [HalAttribute]) {
+    let bounds: Vec<_> = attributes
+        .iter()
+        .map(|a| attribute_bounds(&a.name, attributes))
+        .collect();
+    for (attribute, bounds) in attributes.iter_mut().zip(bounds) {
+        attribute.bounds = bounds;
+    }
+}
+
+#[derive(Debug, Copy, Clone, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum HalAttributeType {
     Int8,
     Int16,
@@ -150,6 +376,12 @@ pub enum HalAttributeType {
     String,
 }
 
+/// Whether sensing the HAL would find real devices, those of the brick or of an `EV3_SYSFS_ROOT`
+/// tree, rather than fall back to the mock.
+pub fn has_real_devices() -> bool {
+    std::env::var_os(SYSFS_ROOT_ENV).is_some() || HalEv3::is_present(Path::new(SYSFS_ROOT))
+}
+
 pub struct HalFactory;
 
 impl HalFactory {
@@ -158,11 +390,33 @@ impl HalFactory {
     }
 
     fn for_ev3() -> Box<dyn Hal + Sync> {
-        Box::new(HalEv3 {})
+        Box::new(HalEv3::new())
+    }
+
+    fn for_sysfs_root(sysfs_root: &Path) -> Box<dyn Hal + Sync> {
+        Box::new(HalEv3::with_sysfs_root(sysfs_root))
     }
 
     fn sense_from_environment() -> Box<dyn Hal + Sync> {
-        if Path::new(LEGO_PORT_ROOT).exists() {
+        HAL_SENSED.store(true, Ordering::SeqCst);
+        let hal = Self::sense_platform();
+        match CONFIGURED_FAULTS.lock().unwrap().take() {
+            Some(config) => {
+                log::info!("Injecting faults into the HAL layer...");
+                Box::new(HalFaulty::new(hal, config, SystemClock::shared()))
+            }
+            None => hal,
+        }
+    }
+
+    fn sense_platform() -> Box<dyn Hal + Sync> {
+        if let Some(mock) = CONFIGURED_MOCK.lock().unwrap().take() {
+            log::info!("Running with configured mock HAL layer...");
+            Box::new(mock)
+        } else if let Some(sysfs_root) = std::env::var_os(SYSFS_ROOT_ENV).map(PathBuf::from) {
+            log::info!("Using sysfs tree at {}...", sysfs_root.display());
+            Self::for_sysfs_root(&sysfs_root)
+        } else if HalEv3::is_present(Path::new(SYSFS_ROOT)) {
             log::info!("Detected EV3 environment...");
             Self::for_ev3()
         } else {
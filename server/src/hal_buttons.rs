@@ -0,0 +1,58 @@
+//! Brick buttons exposed as a sensor device.  Each button has its own attribute which reads `1`
+//! while held and `0` otherwise, and `pressed` lists every button currently held.
+
+use std::collections::BTreeSet;
+
+use crate::hal::{HalAttribute, HalAttributeType, HalError, HalResult};
+
+pub const BUTTONS_ADDRESS: &str = "buttons";
+pub const BUTTONS_DRIVER_NAME: &str = "ev3-buttons";
+
+pub const BUTTON_NAMES: [&str; 6] = ["up", "down", "left", "right", "enter", "backspace"];
+
+/// Attributes common to all button devices, not including `address` and `driver_name`.
+pub fn button_attributes() -> Vec<HalAttribute> {
+    let mut result: Vec<_> = BUTTON_NAMES
+        .iter()
+        .map(|&name| HalAttribute::new_readonly(HalAttributeType::UInt8, name))
+        .collect();
+    result.push(HalAttribute::new_readonly_array(
+        HalAttributeType::String,
+        "pressed",
+    ));
+    result
+}
+
+/// Resolves a button attribute given the set of buttons currently held.
+pub fn get_button_attribute_str(name: &str, pressed: &BTreeSet<&str>) -> HalResult<String> {
+    if name == "pressed" {
+        // Report in a stable order regardless of how the set was built.
+        let ordered: Vec<_> = BUTTON_NAMES
+            .iter()
+            .filter(|&name| pressed.contains(name))
+            .copied()
+            .collect();
+        Ok(ordered.join(" "))
+    } else if BUTTON_NAMES.contains(&name) {
+        Ok(if pressed.contains(name) { "1" } else { "0" }.to_owned())
+    } else {
+        Err(HalError::UnknownAttribute(name.to_owned()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_button_attributes() {
+        let pressed = BTreeSet::from(["enter", "up"]);
+        assert_eq!(get_button_attribute_str("up", &pressed).unwrap(), "1");
+        assert_eq!(get_button_attribute_str("down", &pressed).unwrap(), "0");
+        assert_eq!(
+            get_button_attribute_str("pressed", &pressed).unwrap(),
+            "up enter"
+        );
+        assert!(get_button_attribute_str("value0", &pressed).is_err());
+    }
+}
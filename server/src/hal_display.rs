@@ -0,0 +1,190 @@
+//! The brick's screen, exposed both as a [`HalDevice`] that clients can draw on and as the
+//! surface for the server's own status screen.  Whatever a client draws stays up until it writes
+//! to `clear`, at which point the status screen takes over again.
+//!
+//! Attributes:
+//!
+//! * **width**, **height**: screen size in pixels
+//! * **text**: replace the screen contents with the given text
+//! * **clear**: blank the screen and hand it back to the status screen (value is ignored)
+//! * **image**: show a PBM or PNG image, which is uploaded as the `image` blob rather than
+//!   written as a value, see [`HalDevice::put_blob`]
+
+use std::sync::{Arc, Mutex};
+
+use anyhow::anyhow;
+use log::debug;
+
+use crate::framebuffer::{Canvas, Framebuffer};
+use crate::hal::{
+    HalAttribute, HalAttributeType, HalDevice, HalDeviceType, HalError, HalResult, WatchHandle,
+};
+
+pub const DISPLAY_ADDRESS: &str = "display";
+pub const DISPLAY_DRIVER_NAME: &str = "ev3-display";
+
+/// Upper bound on uploaded images, which only need to cover a 178x128 screen.
+const MAX_IMAGE_BYTES: usize = 256 * 1024;
+
+pub struct Display {
+    inner: Mutex<DisplayInner>,
+}
+
+struct DisplayInner {
+    framebuffer: Framebuffer,
+    canvas: Canvas,
+
+    /// Set while a client's content is on screen, which suppresses the status screen.
+    is_client_owned: bool,
+}
+
+impl Display {
+    pub fn new(framebuffer: Framebuffer) -> Self {
+        let info = framebuffer.info();
+        Self {
+            inner: Mutex::new(DisplayInner {
+                framebuffer,
+                canvas: Canvas::new(info.width, info.height),
+                is_client_owned: false,
+            }),
+        }
+    }
+
+    pub fn size(&self) -> (u32, u32) {
+        let info = self.inner.lock().unwrap().framebuffer.info();
+        (info.width, info.height)
+    }
+
+    /// Draws client content, taking over the screen from the status screen.
+    pub fn draw_client(&self, draw: impl FnOnce(&mut Canvas)) -> HalResult<()> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.is_client_owned = true;
+        inner.redraw(draw)
+    }
+
+    /// Blanks the screen and hands it back to the status screen.
+    pub fn clear(&self) -> HalResult<()> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.is_client_owned = false;
+        inner.redraw(|_| {})
+    }
+
+    /// Draws the status screen unless a client currently owns the display.  Returns whether
+    /// anything was drawn.
+    pub fn draw_status(&self, draw: impl FnOnce(&mut Canvas)) -> HalResult<bool> {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.is_client_owned {
+            return Ok(false);
+        }
+        inner.redraw(draw)?;
+        Ok(true)
+    }
+}
+
+impl DisplayInner {
+    fn redraw(&mut self, draw: impl FnOnce(&mut Canvas)) -> HalResult<()> {
+        self.canvas.clear();
+        draw(&mut self.canvas);
+        self.framebuffer.write(&self.canvas).map_err(HalError::Io)
+    }
+}
+
+pub struct HalDeviceDisplay {
+    display: Arc<Display>,
+}
+
+impl HalDeviceDisplay {
+    pub fn new(display: Arc<Display>) -> Self {
+        Self { display }
+    }
+}
+
+impl HalDevice for HalDeviceDisplay {
+    fn get_type(&self) -> HalResult<HalDeviceType> {
+        Ok(HalDeviceType::Actuator)
+    }
+
+    fn get_driver_name(&self) -> HalResult<String> {
+        Ok(DISPLAY_DRIVER_NAME.to_owned())
+    }
+
+    fn get_address(&self) -> HalResult<String> {
+        Ok(DISPLAY_ADDRESS.to_owned())
+    }
+
+    fn get_applicable_attributes(&self) -> HalResult<Vec<HalAttribute>> {
+        Ok(vec![
+            HalAttribute::new_readonly(HalAttributeType::String, "address"),
+            HalAttribute::new_writeonly(HalAttributeType::String, "clear"),
+            HalAttribute::new_readonly(HalAttributeType::String, "driver_name"),
+            HalAttribute::new_readonly(HalAttributeType::UInt32, "height"),
+            HalAttribute::new_writeonly(HalAttributeType::String, "image"),
+            HalAttribute::new_writeonly(HalAttributeType::String, "text"),
+            HalAttribute::new_readonly(HalAttributeType::UInt32, "width"),
+        ])
+    }
+
+    fn get_attribute_str(&self, name: &str) -> HalResult<String> {
+        let (width, height) = self.display.size();
+        match name {
+            "address" => self.get_address(),
+            "driver_name" => self.get_driver_name(),
+            "width" => Ok(width.to_string()),
+            "height" => Ok(height.to_string()),
+            _ => Err(HalError::UnknownAttribute(name.to_owned())),
+        }
+    }
+
+    fn set_attribute_str(&mut self, name: &str, value: &str) -> HalResult<()> {
+        debug!("Writing attribute {}={}...", name, value);
+        match name {
+            "text" => self.display.draw_client(|canvas| canvas.draw_text(value)),
+            "clear" => self.display.clear(),
+            "image" => Err(HalError::InvalidValue(
+                "Images are uploaded as the image blob".to_owned(),
+            )),
+            _ => Err(HalError::PermissionDenied(name.to_owned())),
+        }
+    }
+
+    fn put_blob(&mut self, name: &str, data: &[u8]) -> HalResult<()> {
+        if name != "image" {
+            return Err(HalError::NotApplicable);
+        }
+        if data.len() > MAX_IMAGE_BYTES {
+            return Err(HalError::InvalidValue(format!(
+                "Image too large: {} > {} bytes",
+                data.len(),
+                MAX_IMAGE_BYTES
+            )));
+        }
+        let image = image::load_from_memory(data)
+            .map_err(|e| HalError::InvalidValue(format!("Unsupported image: {e}")))?;
+        self.display.draw_client(|canvas| canvas.draw_image(&image))
+    }
+
+    fn watch_attributes(&self, names: &[String]) -> anyhow::Result<WatchHandle> {
+        Err(anyhow!("No watchable attribute in {names:?}"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::framebuffer::FramebufferInfo;
+
+    #[test]
+    fn test_client_content_suppresses_status() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let framebuffer =
+            Framebuffer::file_backed(tempdir.path().join("fb0"), FramebufferInfo::EV3).unwrap();
+        let display = Arc::new(Display::new(framebuffer));
+        let mut device = HalDeviceDisplay::new(display.clone());
+
+        assert!(display.draw_status(|c| c.draw_text("status")).unwrap());
+        device.set_attribute_str("text", "Hello").unwrap();
+        assert!(!display.draw_status(|c| c.draw_text("status")).unwrap());
+        device.set_attribute_str("clear", "").unwrap();
+        assert!(display.draw_status(|c| c.draw_text("status")).unwrap());
+    }
+}
@@ -1,105 +1,317 @@
-use std::fs::read_dir;
-use std::path::Path;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
 use std::sync::mpsc::{Receiver, Sender};
+use std::sync::Arc;
 use std::time::Duration;
 use std::{io, thread};
 
-use ev3dev_lang_rust::{Attribute, Ev3Error};
-use log::{debug, trace};
+use log::{debug, trace, warn};
 use notify::poll::PollWatcherConfig;
 use notify::{Event, PollWatcher, RecursiveMode, Watcher};
 
+use crate::bin_data::BinDataFormat;
+use crate::framebuffer::Framebuffer;
 use crate::hal::{
-    Hal, HalAttribute, HalAttributeType, HalDevice, HalDeviceType, HalError, HalResult, WatchHandle,
+    Hal, HalAttribute, HalAttributeType, HalDevice, HalDeviceType, HalError, HalPort, HalResult,
+    WatchHandle, SYSFS_ROOT,
 };
-
-pub struct HalEv3 {}
+use crate::hal_buttons::BUTTONS_ADDRESS;
+use crate::hal_display::{Display, HalDeviceDisplay, DISPLAY_ADDRESS};
+use crate::hal_ev3_attributes::{discover_attributes, is_scaled_attribute, VALUES_ATTRIBUTE};
+use crate::hal_ev3_buttons::HalDeviceEv3Buttons;
+use crate::hal_ev3_files::SysfsFiles;
+use crate::hal_ev3_index::{watch_sysfs_classes, DeviceIndex, IndexEntry};
+use crate::hal_ev3_port::{self, LEGO_PORT_SYSFS_CLASS, PORT_POLL_INTERVAL};
+use crate::hal_ev3_sound::{HalDeviceEv3Sound, SOUND_ADDRESS};
+use crate::hal_leds::{is_ev3_led, led_attributes};
+use crate::hal_motor_units::MotorUnitAttribute;
+
+/// Directory under the sysfs root listing devices by class.
+const SYSFS_CLASS_DIR: &str = "class";
+
+/// sysfs classes (under [`SYSFS_CLASS_DIR`]) whose entries are exposed as [`HalDevice`]s.
+pub(crate) const DEVICE_SYSFS_CLASSES: [&str; 6] = [
+    "tacho-motor",
+    "dc-motor",
+    "servo-motor",
+    "lego-sensor",
+    LEDS_SYSFS_CLASS,
+    POWER_SUPPLY_SYSFS_CLASS,
+];
+
+/// Generic Linux LED class.  On the EV3 this holds the brick status LEDs, e.g.
+/// `led0:green:brick-status`, along with others that we don't expose.
+const LEDS_SYSFS_CLASS: &str = "leds";
+
+/// Generic Linux power supply class.  On the EV3 this holds `lego-ev3-battery`.
+const POWER_SUPPLY_SYSFS_CLASS: &str = "power_supply";
+
+/// Power supply attributes which the kernel reports in micro-units (µV/µA) but which we expose as
+/// floating point volts/amps.
+const POWER_SUPPLY_MICRO_ATTRIBUTES: [&str; 4] = [
+    "current_now",
+    "voltage_max_design",
+    "voltage_min_design",
+    "voltage_now",
+];
+
+/// How often to re-read `bin_data` when the sensor's mode keeps changing underneath us.
+const BIN_DATA_READ_ATTEMPTS: usize = 3;
+
+pub struct HalEv3 {
+    display: Option<Arc<Display>>,
+    index: DeviceIndex,
+    port_poll_interval: Duration,
+
+    /// Whether to look for the sound and buttons input devices, which live outside of sysfs.
+    has_input_devices: bool,
+}
 
 impl HalEv3 {
-    fn find_devices_by_sysfs_class(
-        &self,
-        sysfs_class: &str,
-    ) -> io::Result<Vec<Box<dyn HalDevice>>> {
-        let mut results = Vec::<Box<dyn HalDevice>>::new();
-        for entry in (read_dir(format!("/sys/class/{}", sysfs_class))?).flatten() {
-            if let Some(device_name) = entry.file_name().to_str() {
-                results.push(Box::new(HalDeviceEv3 {
-                    sysfs_class: sysfs_class.to_owned(),
-                    full_device_path: format!("/sys/class/{}/{}", sysfs_class, device_name)
-                        .to_owned(),
-                }));
+    pub fn new() -> Self {
+        let display = match Framebuffer::open_ev3() {
+            Ok(framebuffer) => Some(Arc::new(Display::new(framebuffer))),
+            Err(e) => {
+                warn!("LCD not available: {e}");
+                None
             }
+        };
+        Self {
+            display,
+            has_input_devices: true,
+            ..Self::with_sysfs_root(Path::new(SYSFS_ROOT))
         }
+    }
 
-        Ok(results)
-    }
-
-    fn find_device_by_address(&self, address: &str) -> io::Result<Option<Box<dyn HalDevice>>> {
-        for sysfs_class in ["tacho-motor", "lego-sensor"] {
-            for entry in (read_dir(format!("/sys/class/{}", sysfs_class))?).flatten() {
-                if let Some(device_name) = entry.file_name().to_str() {
-                    let full_device_path = format!("/sys/class/{}/{}", sysfs_class, device_name);
-                    if let Ok(attr) = Attribute::from_path(&format!("{}/address", full_device_path))
-                    {
-                        if let Ok(value) = attr.get::<String>() {
-                            if value == address {
-                                return Ok(Some(Box::new(HalDeviceEv3 {
-                                    sysfs_class: sysfs_class.to_owned(),
-                                    full_device_path: full_device_path.to_owned(),
-                                })));
-                            }
-                        }
-                    }
-                }
-            }
+    /// Serves the devices and ports of the sysfs tree at `sysfs_root` rather than the kernel's,
+    /// e.g. a fake tree for testing.  Devices that aren't backed by sysfs (the display, sound and
+    /// buttons) are only available on the brick.
+    pub fn with_sysfs_root(sysfs_root: &Path) -> Self {
+        Self {
+            display: None,
+            index: DeviceIndex::new(sysfs_root.join(SYSFS_CLASS_DIR)),
+            port_poll_interval: PORT_POLL_INTERVAL,
+            has_input_devices: false,
+        }
+    }
+
+    /// Polls for devices and ports coming and going every `poll_interval` rather than every
+    /// second or two, e.g. to speed up tests against a fake tree.
+    pub fn with_poll_interval(self, poll_interval: Duration) -> Self {
+        Self {
+            index: self.index.with_poll_interval(poll_interval),
+            port_poll_interval: poll_interval,
+            ..self
+        }
+    }
+
+    /// Whether `sysfs_root` looks like it belongs to an ev3dev system.
+    pub fn is_present(sysfs_root: &Path) -> bool {
+        sysfs_root
+            .join(SYSFS_CLASS_DIR)
+            .join(LEGO_PORT_SYSFS_CLASS)
+            .exists()
+    }
+
+    fn port_root(&self) -> PathBuf {
+        self.index.class_root().join(LEGO_PORT_SYSFS_CLASS)
+    }
+
+    fn class_devices(
+        &self,
+        entries: impl IntoIterator<Item = IndexEntry>,
+    ) -> Vec<Box<dyn HalDevice>> {
+        entries
+            .into_iter()
+            .map(|e| Box::new(e.to_device()) as Box<dyn HalDevice>)
+            .collect()
+    }
+
+    fn special_devices(&self) -> impl Iterator<Item = Box<dyn HalDevice>> {
+        self.find_sound_device()
+            .into_iter()
+            .chain(self.find_buttons_device())
+            .chain(self.find_display_device())
+    }
+
+    fn find_display_device(&self) -> Option<Box<dyn HalDevice>> {
+        self.display
+            .as_ref()
+            .map(|d| Box::new(HalDeviceDisplay::new(d.clone())) as Box<dyn HalDevice>)
+    }
+
+    fn find_sound_device(&self) -> Option<Box<dyn HalDevice>> {
+        if self.has_input_devices && HalDeviceEv3Sound::is_present() {
+            let clips_dir = std::env::temp_dir()
+                .join("ev3-remote-control")
+                .join("clips");
+            Some(Box::new(HalDeviceEv3Sound::with_clips_dir(clips_dir)))
+        } else {
+            None
+        }
+    }
+
+    fn find_buttons_device(&self) -> Option<Box<dyn HalDevice>> {
+        if self.has_input_devices && HalDeviceEv3Buttons::is_present() {
+            Some(Box::new(HalDeviceEv3Buttons {}))
+        } else {
+            None
         }
-        Ok(None)
     }
 }
 
 impl Hal for HalEv3 {
     fn list_devices(&self) -> HalResult<Vec<Box<dyn HalDevice>>> {
-        let unmerged_results: HalResult<Vec<_>> = ["tacho-motor", "lego-sensor"]
-            .iter()
-            .map(|&x| {
-                self.find_devices_by_sysfs_class(x)
-                    .map_err(|e| HalError::InternalError(e.to_string()))
-            })
-            .collect();
-
-        let merged: Vec<_> = unmerged_results?.into_iter().flatten().collect();
+        let entries = self.index.entries()?;
+        let mut merged = self.class_devices(entries.iter().cloned());
+        merged.extend(self.special_devices());
         Ok(merged)
     }
 
     fn by_driver(&self, driver: &str) -> HalResult<Vec<Box<dyn HalDevice>>> {
-        Ok(self
-            .list_devices()?
-            .into_iter()
-            .filter(|d| d.get_driver_name().as_deref().unwrap_or("") == driver)
-            .collect())
+        let mut matches = self.class_devices(self.index.by_driver(driver)?);
+        matches.extend(
+            self.special_devices()
+                .filter(|d| d.get_driver_name().as_deref().unwrap_or("") == driver),
+        );
+        Ok(matches)
     }
 
     fn by_address(&self, address: &str) -> HalResult<Option<Box<dyn HalDevice>>> {
-        self.find_device_by_address(address)
-            .map_err(|e| HalError::InternalError(e.to_string()))
+        match address {
+            SOUND_ADDRESS => return Ok(self.find_sound_device()),
+            BUTTONS_ADDRESS => return Ok(self.find_buttons_device()),
+            DISPLAY_ADDRESS => return Ok(self.find_display_device()),
+            _ => {}
+        }
+        let entry = self.index.by_address(address)?;
+        Ok(self.class_devices(entry).pop())
     }
 
     fn watch_devices(&self) -> anyhow::Result<WatchHandle> {
-        let paths = ["tacho-motor", "lego-sensor"].map(|path| format!("/sys/class/{}", path));
-        watch_paths(&paths, Duration::from_secs(2))
+        watch_sysfs_classes(self.index.class_root(), self.index.poll_interval())
+    }
+
+    fn list_ports(&self) -> HalResult<Vec<Box<dyn HalPort>>> {
+        hal_ev3_port::list_ports(&self.port_root())
+    }
+
+    fn port_by_address(&self, address: &str) -> HalResult<Option<Box<dyn HalPort>>> {
+        hal_ev3_port::port_by_address(&self.port_root(), address)
+    }
+
+    fn watch_ports(&self) -> anyhow::Result<WatchHandle> {
+        hal_ev3_port::watch_ports(&self.port_root(), self.port_poll_interval)
+    }
+
+    fn display(&self) -> Option<Arc<Display>> {
+        self.display.clone()
     }
 }
 
 pub struct HalDeviceEv3 {
     sysfs_class: String,
-    full_device_path: String,
+    device_name: String,
+
+    /// Shared with the device index (and so with every other instance for the same device) so
+    /// that attribute files stay open across requests.
+    files: Arc<SysfsFiles>,
+}
+
+impl HalDeviceEv3 {
+    pub(crate) fn new(class_root: &Path, sysfs_class: &str, device_name: &str) -> Self {
+        let device_path = class_root.join(sysfs_class).join(device_name);
+        Self::with_files(
+            sysfs_class,
+            device_name,
+            Arc::new(SysfsFiles::new(device_path)),
+        )
+    }
+
+    pub(crate) fn with_files(sysfs_class: &str, device_name: &str, files: Arc<SysfsFiles>) -> Self {
+        Self {
+            sysfs_class: sysfs_class.to_owned(),
+            device_name: device_name.to_owned(),
+            files,
+        }
+    }
+
+    pub(crate) fn files(&self) -> &Arc<SysfsFiles> {
+        &self.files
+    }
+
+    fn read_attribute(&self, name: &str) -> HalResult<String> {
+        trace!("Reading attribute {}...", name);
+        self.files.read(name)
+    }
+
+    fn read_bin_data(&self) -> HalResult<Vec<u8>> {
+        trace!("Reading attribute bin_data...");
+        self.files.read_bytes("bin_data")
+    }
+
+    fn attribute_path(&self, name: &str) -> String {
+        self.files
+            .device_path()
+            .join(name)
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    /// Decodes all of the current mode's values from a single read of `bin_data`.
+    ///
+    /// The format is read separately from the data, so a mode change in between could make us
+    /// decode the data with the wrong format.  The format is read again afterwards and the read
+    /// retried if it changed.
+    fn read_values(&self) -> HalResult<String> {
+        for _ in 0..BIN_DATA_READ_ATTEMPTS {
+            let format = self.read_bin_data_format()?;
+            let data = self.read_bin_data()?;
+            if self.read_bin_data_format()? != format {
+                debug!("bin_data_format changed while reading bin_data, retrying...");
+                continue;
+            }
+            let (format_str, num_values) = format;
+            let format = BinDataFormat::parse(&format_str).ok_or_else(|| {
+                HalError::InternalError(format!("Unknown bin_data_format: {format_str}"))
+            })?;
+            let values = format.decode(&data, num_values).ok_or_else(|| {
+                HalError::InternalError(format!("Short bin_data: {} bytes", data.len()))
+            })?;
+            return Ok(values.join(" "));
+        }
+        Err(HalError::Busy)
+    }
+
+    /// Reads `bin_data_format` and `num_values`, which together describe `bin_data`.
+    fn read_bin_data_format(&self) -> HalResult<(String, usize)> {
+        let format_str = self.read_attribute("bin_data_format")?;
+        let num_values = self
+            .read_attribute("num_values")?
+            .parse::<usize>()
+            .map_err(|e| HalError::InternalError(e.to_string()))?;
+        Ok((format_str, num_values))
+    }
+
+    /// Generic Linux classes (as opposed to the ev3dev ones) don't have `address` or
+    /// `driver_name` attributes so we synthesize them: the address is the sysfs device name and
+    /// the driver name is the class name.
+    fn is_generic_linux_class(&self) -> bool {
+        matches!(
+            self.sysfs_class.as_str(),
+            LEDS_SYSFS_CLASS | POWER_SUPPLY_SYSFS_CLASS
+        )
+    }
 }
 
 impl HalDevice for HalDeviceEv3 {
     fn get_type(&self) -> HalResult<HalDeviceType> {
         match self.sysfs_class.as_str() {
-            "tacho-motor" => Ok(HalDeviceType::Actuator),
+            "tacho-motor" | "dc-motor" | "servo-motor" => Ok(HalDeviceType::Actuator),
             "lego-sensor" => Ok(HalDeviceType::Sensor),
+            LEDS_SYSFS_CLASS => Ok(HalDeviceType::Actuator),
+            POWER_SUPPLY_SYSFS_CLASS => Ok(HalDeviceType::Sensor),
             unknown => Err(HalError::InternalError(format!(
                 "Unknown sysfs class: {}",
                 unknown
@@ -116,86 +328,166 @@ impl HalDevice for HalDeviceEv3 {
     }
 
     fn get_applicable_attributes(&self) -> HalResult<Vec<HalAttribute>> {
-        let mut result = Vec::with_capacity(64);
-
-        result.extend([
-            HalAttribute::new_readonly(HalAttributeType::String, "address"),
-            HalAttribute::new_writeonly(HalAttributeType::String, "command"),
-            HalAttribute::new_readonly_array(HalAttributeType::String, "commands"),
-            HalAttribute::new_readonly(HalAttributeType::String, "driver_name"),
-            HalAttribute::new_readonly(HalAttributeType::String, "fw_version"),
-        ]);
-
+        // Generic Linux classes have plenty of files we have no business exposing as well as
+        // synthesized attributes, so they keep a fixed list.
         match self.sysfs_class.as_str() {
-            "tacho-motor" => result.extend([
-                HalAttribute::new_readonly(HalAttributeType::Int32, "count_per_rot"),
-                HalAttribute::new_readonly(HalAttributeType::Int8, "duty_cycle"),
-                HalAttribute::new_rw(HalAttributeType::Int8, "duty_cycle_sp"),
-                HalAttribute::new_readonly(HalAttributeType::Int32, "position"),
-                HalAttribute::new_rw(HalAttributeType::Int32, "position_sp"),
-                HalAttribute::new_rw(HalAttributeType::Int32, "ramp_down_sp"),
-                HalAttribute::new_rw(HalAttributeType::Int32, "ramp_up_sp"),
-                HalAttribute::new_readonly(HalAttributeType::Int32, "speed"),
-                HalAttribute::new_rw(HalAttributeType::Int32, "speed_sp"),
-                HalAttribute::new_readonly(HalAttributeType::String, "state"),
-                HalAttribute::new_rw(HalAttributeType::String, "stop_action"),
-                HalAttribute::new_readonly_array(HalAttributeType::String, "stop_actions"),
-            ]),
-            "lego-sensor" => result.extend([
-                HalAttribute::new_rw(HalAttributeType::String, "mode"),
-                HalAttribute::new_readonly_array(HalAttributeType::String, "modes"),
-                HalAttribute::new_readonly(HalAttributeType::UInt8, "num_values"),
-                HalAttribute::new_readonly(HalAttributeType::Int32, "value0"),
-                HalAttribute::new_readonly(HalAttributeType::Int32, "value1"),
-                HalAttribute::new_readonly(HalAttributeType::Int32, "value2"),
-                HalAttribute::new_readonly(HalAttributeType::Int32, "value3"),
-                HalAttribute::new_readonly(HalAttributeType::Int32, "value4"),
-                HalAttribute::new_readonly(HalAttributeType::Int32, "value5"),
-                HalAttribute::new_readonly(HalAttributeType::Int32, "value6"),
-                HalAttribute::new_readonly(HalAttributeType::Int32, "value7"),
+            LEDS_SYSFS_CLASS => {
+                let mut result = vec![
+                    HalAttribute::new_readonly(HalAttributeType::String, "address"),
+                    HalAttribute::new_readonly(HalAttributeType::String, "driver_name"),
+                ];
+                result.extend(led_attributes());
+                Ok(result)
+            }
+            POWER_SUPPLY_SYSFS_CLASS => Ok(vec![
+                HalAttribute::new_readonly(HalAttributeType::String, "address"),
+                HalAttribute::new_readonly(HalAttributeType::Float32, "current_now"),
+                HalAttribute::new_readonly(HalAttributeType::String, "driver_name"),
+                HalAttribute::new_readonly(HalAttributeType::String, "technology"),
+                HalAttribute::new_readonly(HalAttributeType::String, "type"),
+                HalAttribute::new_readonly(HalAttributeType::Float32, "voltage_max_design"),
+                HalAttribute::new_readonly(HalAttributeType::Float32, "voltage_min_design"),
+                HalAttribute::new_readonly(HalAttributeType::Float32, "voltage_now"),
             ]),
-            _ => {}
-        };
-
-        Ok(result)
+            _ => discover_attributes(self.files.device_path()).map_err(HalError::Io),
+        }
     }
 
     fn get_attribute_str(&self, name: &str) -> HalResult<String> {
-        trace!("Reading attribute {}...", name);
-        Attribute::from_path(&format!("{}/{}", self.full_device_path, name))
-            .and_then(|a| a.get())
-            .map_err(convert_to_hal_error)
+        match (self.sysfs_class.as_str(), name) {
+            (_, "address") if self.is_generic_linux_class() => Ok(self.device_name.clone()),
+            (_, "driver_name") if self.is_generic_linux_class() => Ok(self.sysfs_class.clone()),
+            (LEDS_SYSFS_CLASS, "trigger") => {
+                let triggers = self.read_attribute("trigger")?;
+                let selected = parse_led_triggers(&triggers)
+                    .find(|&(_, selected)| selected)
+                    .map(|(trigger, _)| trigger.to_owned());
+                Ok(selected.unwrap_or_default())
+            }
+            (LEDS_SYSFS_CLASS, "triggers") => {
+                let triggers = self.read_attribute("trigger")?;
+                let names: Vec<_> = parse_led_triggers(&triggers)
+                    .map(|(trigger, _)| trigger)
+                    .collect();
+                Ok(names.join(" "))
+            }
+            // Raw bytes, which we present as an array of numbers.
+            (_, "bin_data") => {
+                let bytes: Vec<_> = self
+                    .read_bin_data()?
+                    .iter()
+                    .map(|b| b.to_string())
+                    .collect();
+                Ok(bytes.join(" "))
+            }
+            (_, VALUES_ATTRIBUTE) => self.read_values(),
+            (POWER_SUPPLY_SYSFS_CLASS, name) if POWER_SUPPLY_MICRO_ATTRIBUTES.contains(&name) => {
+                let micros = self.read_attribute(name)?;
+                convert_from_micro(&micros).ok_or_else(|| {
+                    HalError::InternalError(format!("Unexpected {name} value: {micros}"))
+                })
+            }
+            _ => match MotorUnitAttribute::parse(name) {
+                Some(unit) => unit.read(|name| self.read_attribute(name)),
+                None => self.read_attribute(name),
+            },
+        }
     }
 
     fn set_attribute_str(&mut self, name: &str, value: &str) -> HalResult<()> {
+        if let Some(unit) = MotorUnitAttribute::parse(name) {
+            let native_value = unit.native_value(value, |name| self.read_attribute(name))?;
+            return self.set_attribute_str(unit.native_attribute(), &native_value);
+        }
         debug!("Writing attribute {}={}...", name, value);
-        Attribute::from_path(&format!("{}/{}", self.full_device_path, name))
-            .and_then(|a| a.set(value))
-            .map_err(convert_to_hal_error)
+        self.files.write(name, value)
     }
 
     fn watch_attributes(&self, names: &[String]) -> anyhow::Result<WatchHandle> {
-        let attr_paths = names
+        let mut attr_paths = names
             .iter()
-            .map(|name| format!("{}/{name}", self.full_device_path))
+            .map(|name| match name.as_str() {
+                // Virtual attribute derived from the `trigger` file.
+                "triggers" if self.sysfs_class == LEDS_SYSFS_CLASS => "trigger",
+                VALUES_ATTRIBUTE => "bin_data",
+                name => MotorUnitAttribute::parse(name)
+                    .map(|unit| unit.native_attribute())
+                    .unwrap_or(name),
+            })
+            .map(|name| self.attribute_path(name))
             .collect::<Vec<_>>();
 
+        // A mode change alters the scaling of values even if the raw reading stays the same.
+        if names.iter().any(|name| is_scaled_attribute(name)) {
+            attr_paths.push(self.attribute_path("mode"));
+        }
+
         watch_paths(&attr_paths, Duration::from_millis(100))
     }
 }
 
-fn convert_to_hal_error(err: Ev3Error) -> HalError {
-    match err {
-        Ev3Error::InternalError { msg } => HalError::InternalError(msg),
-        Ev3Error::NotConnected { device, port } => HalError::NotConnected { device, port },
-        Ev3Error::MultipleMatches { device, ports } => HalError::InternalError(format!(
-            "MultipleMatches: device: {}, ports: {:?}",
-            device, ports
-        )),
+/// Whether the device `device_name` of `sysfs_class` is exposed at all.  Besides the brick status
+/// LEDs, the LED class holds LEDs that have nothing to do with the brick, e.g. the SD card
+/// activity LED `mmc0::`.
+pub(crate) fn is_exposed_device(sysfs_class: &str, device_name: &str) -> bool {
+    sysfs_class != LEDS_SYSFS_CLASS || is_ev3_led(device_name)
+}
+
+/// Converts a micro-unit integer (e.g. µV) as reported by the kernel into the base unit.
+fn convert_from_micro(value: &str) -> Option<String> {
+    let micros = value.trim().parse::<i64>().ok()?;
+    Some((micros as f64 / 1_000_000.0).to_string())
+}
+
+/// Parses the LED class `trigger` file, which lists every available trigger with the active one
+/// in square brackets, e.g. `none [timer] heartbeat`.  Yields `(trigger, is_selected)` pairs.
+fn parse_led_triggers(value: &str) -> impl Iterator<Item = (&str, bool)> {
+    value.split_whitespace().map(|trigger| {
+        match trigger.strip_prefix('[').and_then(|t| t.strip_suffix(']')) {
+            Some(selected) => (selected, true),
+            None => (trigger, false),
+        }
+    })
+}
+
+pub(crate) fn read_sysfs_attribute(path: &Path) -> HalResult<String> {
+    let value = fs::read_to_string(path).map_err(|e| convert_to_hal_error(e, path))?;
+    Ok(value.trim_end().to_owned())
+}
+
+pub(crate) fn write_sysfs_attribute(path: &Path, value: &str) -> HalResult<()> {
+    OpenOptions::new()
+        .write(true)
+        .truncate(true)
+        .open(path)
+        .and_then(|mut file| file.write_all(value.as_bytes()))
+        .map_err(|e| convert_to_hal_error(e, path))
+}
+
+/// Maps a failed access of the sysfs attribute file at `path` according to what the kernel
+/// reported.  Drivers reject values they don't understand with `EINVAL`, and attribute files of
+/// unplugged devices either vanish along with the device directory or fail with `ENODEV`.
+pub(crate) fn convert_to_hal_error(err: io::Error, path: &Path) -> HalError {
+    let attribute = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let device_exists = matches!(path.parent(), Some(dir) if dir.exists());
+    match (err.kind(), err.raw_os_error()) {
+        (_, Some(libc::ENODEV)) => HalError::DeviceGone,
+        (io::ErrorKind::NotFound, _) if !device_exists => HalError::DeviceGone,
+        (io::ErrorKind::NotFound, _) => HalError::UnknownAttribute(attribute),
+        (io::ErrorKind::PermissionDenied, _) => HalError::PermissionDenied(attribute),
+        (io::ErrorKind::InvalidInput, _) => HalError::InvalidValue(format!("{attribute}: {err}")),
+        (_, Some(libc::EBUSY | libc::EAGAIN)) => HalError::Busy,
+        _ => HalError::Io(err),
     }
 }
 
-fn watch_paths(paths: &[String], poll_interval: Duration) -> anyhow::Result<WatchHandle> {
+pub(crate) fn watch_paths(
+    paths: &[String],
+    poll_interval: Duration,
+) -> anyhow::Result<WatchHandle> {
     let (tx, rx) = std::sync::mpsc::channel();
     let mut watcher = PollWatcher::with_config(
         tx,
@@ -250,6 +542,60 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_parse_led_triggers() {
+        let triggers: Vec<_> = parse_led_triggers("none mmc0 [timer] heartbeat\n").collect();
+        assert_eq!(
+            triggers,
+            vec![
+                ("none", false),
+                ("mmc0", false),
+                ("timer", true),
+                ("heartbeat", false)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_convert_from_micro() {
+        assert_eq!(convert_from_micro("7512000").as_deref(), Some("7.512"));
+        assert_eq!(convert_from_micro("-185000\n").as_deref(), Some("-0.185"));
+        assert_eq!(convert_from_micro("bogus"), None);
+    }
+
+    #[test]
+    fn test_sysfs_errors() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let device_path = tempdir.path().join("motor0");
+        std::fs::create_dir(&device_path).unwrap();
+        std::fs::write(device_path.join("speed_sp"), "0\n").unwrap();
+
+        write_sysfs_attribute(&device_path.join("speed_sp"), "500").unwrap();
+        assert_eq!(
+            read_sysfs_attribute(&device_path.join("speed_sp")).unwrap(),
+            "500"
+        );
+        assert!(matches!(
+            read_sysfs_attribute(&device_path.join("bogus")),
+            Err(HalError::UnknownAttribute(name)) if name == "bogus"
+        ));
+        assert!(matches!(
+            write_sysfs_attribute(&tempdir.path().join("motor1/speed_sp"), "500"),
+            Err(HalError::DeviceGone)
+        ));
+
+        let attribute = device_path.join("command");
+        let convert = |errno| convert_to_hal_error(io::Error::from_raw_os_error(errno), &attribute);
+        assert!(matches!(convert(libc::EINVAL), HalError::InvalidValue(_)));
+        assert!(matches!(
+            convert(libc::EACCES),
+            HalError::PermissionDenied(_)
+        ));
+        assert!(matches!(convert(libc::EBUSY), HalError::Busy));
+        assert!(matches!(convert(libc::ENODEV), HalError::DeviceGone));
+        assert!(matches!(convert(libc::EIO), HalError::Io(_)));
+    }
+
     #[test]
     fn test_drop_causes_cancel() {
         let _ = env_logger::builder().is_test(true).try_init();
@@ -0,0 +1,295 @@
+//! Attribute discovery for ev3dev class devices.  Rather than keeping a list of attributes per
+//! class (which inevitably falls behind the drivers) we enumerate the device's sysfs directory and
+//! take readability/writability from the file permissions.  The only thing sysfs can't tell us is
+//! how to interpret the contents, which is what [`attribute_type`] is for.
+
+use std::fs;
+use std::io;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+
+use crate::bin_data::BinDataFormat;
+use crate::hal::{apply_bounds, HalAttribute, HalAttributeType};
+use crate::hal_motor_units::add_unit_attributes;
+
+/// Subdirectories which hold attributes of their own, exposed as e.g. `hold_pid/Kp`.
+const ATTRIBUTE_SUBDIRS: [&str; 2] = ["hold_pid", "speed_pid"];
+
+/// Attributes which only accept the values listed by their companion attribute.
+const ALLOWED_VALUES: [(&str, &str); 3] = [
+    ("command", "commands"),
+    ("mode", "modes"),
+    ("stop_action", "stop_actions"),
+];
+
+/// Virtual attribute decoded from `bin_data`.
+pub const VALUES_ATTRIBUTE: &str = "values";
+
+/// Files that exist on every sysfs device but aren't device attributes.
+const IGNORED_FILES: [&str; 1] = ["uevent"];
+
+/// Returns the type of the named ev3dev attribute and whether it's a space separated array.
+/// Attributes we don't know about are assumed to be strings.
+pub fn attribute_type(name: &str) -> (HalAttributeType, bool) {
+    match name {
+        "commands" | "modes" | "stop_actions" => (HalAttributeType::String, true),
+        "bin_data" => (HalAttributeType::UInt8, true),
+        "duty_cycle" | "duty_cycle_sp" => (HalAttributeType::Int8, false),
+        "decimals" | "num_values" => (HalAttributeType::UInt8, false),
+        "poll_ms" => (HalAttributeType::UInt32, false),
+        "count_per_m" | "count_per_rot" | "full_travel_count" | "max_pulse_sp" | "max_speed"
+        | "mid_pulse_sp" | "min_pulse_sp" | "position" | "position_sp" | "ramp_down_sp"
+        | "ramp_up_sp" | "rate_sp" | "speed" | "speed_sp" | "time_sp" => {
+            (HalAttributeType::Int32, false)
+        }
+        _ if parse_value_index(name).is_some() => (HalAttributeType::Int32, false),
+        _ if is_pid_attribute(name) => (HalAttributeType::Int32, false),
+        _ => (HalAttributeType::String, false),
+    }
+}
+
+/// Lists the attributes of the device at `device_path`, sorted by name.  `valueN` attributes
+/// beyond the current mode's `num_values` are left out since reading them is meaningless, and the
+/// remaining ones carry the current mode's `units` and `decimals`.
+///
+/// Devices with `bin_data` also get a virtual `values` array holding all of the current mode's
+/// values decoded from a single read of `bin_data`, and tacho motors get the virtual attributes
+/// from [`crate::hal_motor_units`].
+pub fn discover_attributes(device_path: &Path) -> io::Result<Vec<HalAttribute>> {
+    let mut result = Vec::new();
+    discover_attributes_in(device_path, None, &mut result)?;
+    for subdir in ATTRIBUTE_SUBDIRS {
+        let subdir_path = device_path.join(subdir);
+        if subdir_path.is_dir() {
+            discover_attributes_in(&subdir_path, Some(subdir), &mut result)?;
+        }
+    }
+
+    if result.iter().any(|a| a.name == "num_values") {
+        let num_values = fs::read_to_string(device_path.join("num_values"))?
+            .trim()
+            .parse::<usize>()
+            .unwrap_or(0);
+        result.retain(|a| !matches!(parse_value_index(&a.name), Some(i) if i >= num_values));
+    }
+
+    if result.iter().any(|a| a.name == "bin_data") {
+        let format =
+            read_optional(device_path, "bin_data_format")?.and_then(|f| BinDataFormat::parse(&f));
+        if let Some(format) = format {
+            result.push(HalAttribute::new_readonly_array(
+                format.data_type(),
+                VALUES_ATTRIBUTE,
+            ));
+        }
+    }
+
+    let units = read_optional(device_path, "units")?.filter(|u| !u.is_empty());
+    let decimals = read_optional(device_path, "decimals")?.and_then(|d| d.parse::<u8>().ok());
+    for attribute in result.iter_mut() {
+        if is_scaled_attribute(&attribute.name) {
+            attribute.units = units.clone();
+            attribute.decimals = decimals;
+        }
+    }
+
+    for (name, allowed_values) in ALLOWED_VALUES {
+        if result.iter().any(|a| a.name == allowed_values) {
+            if let Some(attribute) = result.iter_mut().find(|a| a.name == name) {
+                attribute.allowed_values_from = Some(allowed_values.to_owned());
+            }
+        }
+    }
+
+    apply_bounds(&mut result);
+    add_unit_attributes(&mut result);
+
+    result.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(result)
+}
+
+fn discover_attributes_in(
+    dir: &Path,
+    prefix: Option<&str>,
+    result: &mut Vec<HalAttribute>,
+) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        // Symlinks (`device`, `subsystem`) and directories (`power`) aren't attributes.
+        if !entry.file_type()?.is_file() {
+            continue;
+        }
+        let file_name = match entry.file_name().to_str() {
+            Some(name) if !IGNORED_FILES.contains(&name) => name.to_owned(),
+            _ => continue,
+        };
+        let name = match prefix {
+            Some(prefix) => format!("{prefix}/{file_name}"),
+            None => file_name,
+        };
+
+        let mode = entry.metadata()?.permissions().mode();
+        let (data_type, is_array) = attribute_type(&name);
+        result.push(HalAttribute {
+            is_array,
+            data_type,
+            name,
+            is_readable: mode & 0o444 != 0,
+            is_writable: mode & 0o222 != 0,
+            units: None,
+            decimals: None,
+            allowed_values_from: None,
+            bounds: None,
+        });
+    }
+    Ok(())
+}
+
+/// Attributes that are subject to the mode's `units` and `decimals`.
+pub fn is_scaled_attribute(name: &str) -> bool {
+    name == VALUES_ATTRIBUTE || parse_value_index(name).is_some()
+}
+
+/// Reads a trimmed attribute value, or None if the device doesn't have the attribute.
+fn read_optional(device_path: &Path, name: &str) -> io::Result<Option<String>> {
+    match fs::read_to_string(device_path.join(name)) {
+        Ok(value) => Ok(Some(value.trim().to_owned())),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Returns `N` for `valueN` attributes.
+fn parse_value_index(name: &str) -> Option<usize> {
+    name.strip_prefix("value")?.parse().ok()
+}
+
+fn is_pid_attribute(name: &str) -> bool {
+    ATTRIBUTE_SUBDIRS
+        .iter()
+        .any(|subdir| name.starts_with(subdir) && name[subdir.len()..].starts_with('/'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hal::HalBounds;
+    use std::fs::Permissions;
+
+    fn write_attribute(dir: &Path, name: &str, value: &str, mode: u32) {
+        let path = dir.join(name);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, value).unwrap();
+        fs::set_permissions(&path, Permissions::from_mode(mode)).unwrap();
+    }
+
+    fn names(attributes: &[HalAttribute]) -> Vec<&str> {
+        attributes.iter().map(|a| a.name.as_str()).collect()
+    }
+
+    #[test]
+    fn test_discover_tacho_motor() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let dir = tempdir.path();
+        write_attribute(dir, "address", "ev3-ports:outA", 0o444);
+        write_attribute(dir, "command", "", 0o220);
+        write_attribute(dir, "commands", "run-forever stop", 0o444);
+        write_attribute(dir, "max_speed", "1050", 0o444);
+        write_attribute(dir, "polarity", "normal", 0o664);
+        write_attribute(dir, "speed_sp", "0", 0o664);
+        write_attribute(dir, "hold_pid/Kp", "0", 0o664);
+        write_attribute(dir, "uevent", "", 0o644);
+        fs::create_dir(dir.join("power")).unwrap();
+
+        let attributes = discover_attributes(dir).unwrap();
+        assert_eq!(
+            names(&attributes),
+            vec![
+                "address",
+                "command",
+                "commands",
+                "hold_pid/Kp",
+                "max_speed",
+                "polarity",
+                "speed_sp"
+            ]
+        );
+
+        let command = &attributes[1];
+        assert!(!command.is_readable && command.is_writable);
+        assert_eq!(command.allowed_values_from.as_deref(), Some("commands"));
+        let commands = &attributes[2];
+        assert!(commands.is_array && commands.is_readable && !commands.is_writable);
+        assert!(matches!(attributes[3].data_type, HalAttributeType::Int32));
+        assert!(matches!(attributes[4].data_type, HalAttributeType::Int32));
+        let polarity = &attributes[5];
+        assert!(matches!(polarity.data_type, HalAttributeType::String));
+        assert!(polarity.is_readable && polarity.is_writable);
+        assert_eq!(
+            attributes[6].bounds,
+            Some(HalBounds::Symmetric("max_speed".to_owned()))
+        );
+
+        write_attribute(dir, "count_per_rot", "360", 0o444);
+        let attributes = discover_attributes(dir).unwrap();
+        let speed_sp_rpm = attributes
+            .iter()
+            .find(|a| a.name == "speed_sp_rpm")
+            .unwrap();
+        assert!(speed_sp_rpm.is_readable && speed_sp_rpm.is_writable);
+        assert!(attributes.iter().all(|a| a.name != "position_deg"));
+    }
+
+    #[test]
+    fn test_discover_trims_values_to_num_values() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let dir = tempdir.path();
+        write_attribute(dir, "num_values", "2\n", 0o444);
+        for i in 0..8 {
+            write_attribute(dir, &format!("value{i}"), "0", 0o444);
+        }
+
+        let attributes = discover_attributes(dir).unwrap();
+        assert_eq!(names(&attributes), vec!["num_values", "value0", "value1"]);
+    }
+
+    #[test]
+    fn test_discover_units_follow_mode() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let dir = tempdir.path();
+        write_attribute(dir, "num_values", "1", 0o444);
+        write_attribute(dir, "value0", "213", 0o444);
+        write_attribute(dir, "units", "C\n", 0o444);
+        write_attribute(dir, "decimals", "1\n", 0o444);
+
+        let attributes = discover_attributes(dir).unwrap();
+        let value0 = attributes.iter().find(|a| a.name == "value0").unwrap();
+        assert_eq!(value0.units.as_deref(), Some("C"));
+        assert_eq!(value0.decimals, Some(1));
+        let num_values = attributes.iter().find(|a| a.name == "num_values").unwrap();
+        assert_eq!(num_values.decimals, None);
+
+        // Switching to a mode without units, e.g. the temperature sensor's raw mode.
+        write_attribute(dir, "units", "\n", 0o444);
+        write_attribute(dir, "decimals", "0\n", 0o444);
+        let attributes = discover_attributes(dir).unwrap();
+        let value0 = attributes.iter().find(|a| a.name == "value0").unwrap();
+        assert_eq!(value0.units, None);
+        assert_eq!(value0.decimals, Some(0));
+    }
+
+    #[test]
+    fn test_discover_values_from_bin_data() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let dir = tempdir.path();
+        write_attribute(dir, "bin_data", "", 0o444);
+        write_attribute(dir, "bin_data_format", "float\n", 0o444);
+        write_attribute(dir, "decimals", "1\n", 0o444);
+
+        let attributes = discover_attributes(dir).unwrap();
+        let values = attributes.iter().find(|a| a.name == "values").unwrap();
+        assert!(values.is_array && values.is_readable && !values.is_writable);
+        assert!(matches!(values.data_type, HalAttributeType::Float32));
+        assert_eq!(values.decimals, Some(1));
+    }
+}
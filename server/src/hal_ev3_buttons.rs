@@ -0,0 +1,156 @@
+use std::collections::BTreeSet;
+use std::fs::File;
+use std::io::Read;
+use std::os::raw::c_long;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use std::{io, mem, thread};
+
+use log::{debug, trace};
+
+use crate::hal::{
+    HalAttribute, HalAttributeType, HalDevice, HalDeviceType, HalError, HalResult, WatchHandle,
+};
+use crate::hal_buttons::{
+    button_attributes, get_button_attribute_str, BUTTONS_ADDRESS, BUTTONS_DRIVER_NAME,
+};
+
+/// evdev device exposed by the `gpio-keys` driver for the six brick buttons.
+pub const BUTTONS_EVENT_DEVICE: &str = "/dev/input/by-path/platform-gpio_keys-event";
+
+const EV_KEY: u16 = 0x01;
+
+/// Linux key codes reported by `gpio-keys` for each button.
+const BUTTON_KEY_CODES: [(&str, usize); 6] = [
+    ("up", 103),
+    ("down", 108),
+    ("left", 105),
+    ("right", 106),
+    ("enter", 28),
+    ("backspace", 14),
+];
+
+/// How often the event thread checks whether its watch was dropped while no keys are pressed.
+const CANCEL_CHECK_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Enough to hold a bit for every key code up to `KEY_MAX`.
+const KEY_BITMAP_LEN: usize = 96;
+
+pub struct HalDeviceEv3Buttons {}
+
+impl HalDeviceEv3Buttons {
+    pub fn is_present() -> bool {
+        Path::new(BUTTONS_EVENT_DEVICE).exists()
+    }
+
+    fn read_pressed(&self) -> io::Result<BTreeSet<&'static str>> {
+        let device = File::open(BUTTONS_EVENT_DEVICE)?;
+        let mut bitmap = [0u8; KEY_BITMAP_LEN];
+
+        // EVIOCGKEY(len): _IOC(_IOC_READ, 'E', 0x18, len)
+        let request = (2 << 30) | (KEY_BITMAP_LEN << 16) | ((b'E' as usize) << 8) | 0x18;
+        let ret = unsafe { libc::ioctl(device.as_raw_fd(), request as _, bitmap.as_mut_ptr()) };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(BUTTON_KEY_CODES
+            .iter()
+            .filter(|&&(_, code)| bitmap[code / 8] & (1 << (code % 8)) != 0)
+            .map(|&(name, _)| name)
+            .collect())
+    }
+}
+
+impl HalDevice for HalDeviceEv3Buttons {
+    fn get_type(&self) -> HalResult<HalDeviceType> {
+        Ok(HalDeviceType::Sensor)
+    }
+
+    fn get_driver_name(&self) -> HalResult<String> {
+        Ok(BUTTONS_DRIVER_NAME.to_owned())
+    }
+
+    fn get_address(&self) -> HalResult<String> {
+        Ok(BUTTONS_ADDRESS.to_owned())
+    }
+
+    fn get_applicable_attributes(&self) -> HalResult<Vec<HalAttribute>> {
+        let mut result = vec![
+            HalAttribute::new_readonly(HalAttributeType::String, "address"),
+            HalAttribute::new_readonly(HalAttributeType::String, "driver_name"),
+        ];
+        result.extend(button_attributes());
+        Ok(result)
+    }
+
+    fn get_attribute_str(&self, name: &str) -> HalResult<String> {
+        trace!("Reading attribute {}...", name);
+        match name {
+            "address" => self.get_address(),
+            "driver_name" => self.get_driver_name(),
+            name => {
+                let pressed = self.read_pressed().map_err(HalError::Io)?;
+                get_button_attribute_str(name, &pressed)
+            }
+        }
+    }
+
+    fn set_attribute_str(&mut self, name: &str, _value: &str) -> HalResult<()> {
+        Err(HalError::PermissionDenied(name.to_owned()))
+    }
+
+    fn watch_attributes(&self, _names: &[String]) -> anyhow::Result<WatchHandle> {
+        let mut device = File::open(BUTTONS_EVENT_DEVICE)?;
+        let (tx, rx) = std::sync::mpsc::channel();
+        let cancel_handle = Arc::new("buttons".to_string());
+
+        // Waits for events on the device, waking up now and then to exit once the handle is
+        // dropped so that the thread and device don't linger until the next key press.
+        let weak_handle = Arc::downgrade(&cancel_handle);
+        thread::spawn(move || {
+            debug!("Spawning new thread for button events...");
+            let mut event = [0u8; 2 * mem::size_of::<c_long>() + 8];
+            let type_offset = 2 * mem::size_of::<c_long>();
+            while weak_handle.upgrade().is_some() {
+                match wait_readable(&device, CANCEL_CHECK_INTERVAL) {
+                    Ok(true) => {}
+                    Ok(false) => continue,
+                    Err(e) => {
+                        debug!("Button event thread exiting: {:?}...", e);
+                        break;
+                    }
+                }
+                if let Err(e) = device.read_exact(&mut event) {
+                    debug!("Button event thread exiting: {:?}...", e);
+                    break;
+                }
+                let event_type = u16::from_ne_bytes([event[type_offset], event[type_offset + 1]]);
+                if event_type == EV_KEY && tx.send(()).is_err() {
+                    break;
+                }
+            }
+        });
+        Ok(WatchHandle::new(cancel_handle, rx))
+    }
+}
+
+/// Waits up to `timeout` for `file` to have something to read.
+fn wait_readable(file: &File, timeout: Duration) -> io::Result<bool> {
+    let mut poll_fd = libc::pollfd {
+        fd: file.as_raw_fd(),
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    let ret = unsafe { libc::poll(&mut poll_fd, 1, timeout.as_millis() as libc::c_int) };
+    if ret < 0 {
+        let error = io::Error::last_os_error();
+        return match error.kind() {
+            io::ErrorKind::Interrupted => Ok(false),
+            _ => Err(error),
+        };
+    }
+    Ok(ret > 0)
+}
@@ -0,0 +1,163 @@
+//! Open file handles for the attributes of a single sysfs device.  Opening a sysfs file costs a
+//! path lookup and an allocation in the kernel, which adds up for observers polling several
+//! attributes of several devices, so each attribute is opened once and then accessed with
+//! `pread`/`pwrite` at offset 0.  sysfs regenerates the contents on every read from the start of
+//! the file, so there's no need to seek or reopen.
+//!
+//! Handles are dropped along with the device's entry in the device index once it's unplugged.
+//! Devices still referring to them (e.g. an ongoing observation) get [`HalError::DeviceGone`]
+//! from the kernel, which evicts the handle so that nothing stale is ever retried.  Other errors
+//! (e.g. the driver rejecting a value) leave the handle alone.
+
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use log::trace;
+
+use crate::hal::{HalError, HalResult};
+use crate::hal_ev3::convert_to_hal_error;
+
+/// sysfs attributes are at most a page long.
+const READ_CHUNK_SIZE: usize = 4096;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+enum Access {
+    Read,
+    Write,
+}
+
+#[derive(Debug)]
+pub struct SysfsFiles {
+    device_path: PathBuf,
+
+    /// Keyed separately by access since some attributes (e.g. `command`) are write-only.
+    files: Mutex<HashMap<(String, Access), Arc<File>>>,
+}
+
+impl SysfsFiles {
+    pub fn new(device_path: PathBuf) -> Self {
+        Self {
+            device_path,
+            files: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn device_path(&self) -> &Path {
+        &self.device_path
+    }
+
+    pub fn read_bytes(&self, name: &str) -> HalResult<Vec<u8>> {
+        self.with_file(name, Access::Read, |file| {
+            let mut data = Vec::new();
+            let mut chunk = [0u8; READ_CHUNK_SIZE];
+            loop {
+                let n = file.read_at(&mut chunk, data.len() as u64)?;
+                if n == 0 {
+                    return Ok(data);
+                }
+                data.extend_from_slice(&chunk[..n]);
+            }
+        })
+    }
+
+    pub fn read(&self, name: &str) -> HalResult<String> {
+        let data = self.read_bytes(name)?;
+        let value = String::from_utf8(data)
+            .map_err(|_| HalError::InternalError(format!("{name} is not valid UTF-8")))?;
+        Ok(value.trim_end().to_owned())
+    }
+
+    pub fn write(&self, name: &str, value: &str) -> HalResult<()> {
+        self.with_file(name, Access::Write, |file| {
+            file.write_all_at(value.as_bytes(), 0)?;
+            // sysfs replaces the value on every write, but regular files (as in the fake trees of
+            // `hal_ev3_fixture`) would keep the tail of a longer previous one.  Whether sysfs
+            // accepts the truncation doesn't matter.
+            let _ = file.set_len(value.len() as u64);
+            Ok(())
+        })
+    }
+
+    /// Number of open handles, for tests.
+    #[cfg(test)]
+    fn open_count(&self) -> usize {
+        self.files.lock().unwrap().len()
+    }
+
+    fn with_file<T>(
+        &self,
+        name: &str,
+        access: Access,
+        op: impl FnOnce(&File) -> std::io::Result<T>,
+    ) -> HalResult<T> {
+        let path = self.device_path.join(name);
+        let key = (name.to_owned(), access);
+        let cached = self.files.lock().unwrap().get(&key).cloned();
+        let file = match cached {
+            Some(file) => file,
+            None => {
+                trace!("Opening {:?} for {:?}...", path, access);
+                let file = OpenOptions::new()
+                    .read(access == Access::Read)
+                    .write(access == Access::Write)
+                    .open(&path)
+                    .map_err(|e| convert_to_hal_error(e, &path))?;
+                let file = Arc::new(file);
+                self.files.lock().unwrap().insert(key.clone(), file.clone());
+                file
+            }
+        };
+
+        op(&file).map_err(|e| {
+            // The file went away along with the device.
+            if matches!(e.raw_os_error(), Some(libc::ENOENT | libc::ENODEV)) {
+                self.files.lock().unwrap().remove(&key);
+            }
+            convert_to_hal_error(e, &path)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_reuses_handles() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let files = SysfsFiles::new(tempdir.path().to_owned());
+        let position = tempdir.path().join("position");
+        fs::write(&position, "10\n").unwrap();
+
+        assert_eq!(files.read("position").unwrap(), "10");
+        // Rewrites the same inode just like sysfs regenerating the contents.
+        fs::write(&position, "-5\n").unwrap();
+        assert_eq!(files.read("position").unwrap(), "-5");
+        assert_eq!(files.open_count(), 1);
+
+        files.write("position", "-7").unwrap();
+        assert_eq!(files.read("position").unwrap(), "-7");
+        assert_eq!(files.open_count(), 2);
+
+        assert!(matches!(
+            files.read("bogus"),
+            Err(HalError::UnknownAttribute(_))
+        ));
+        assert_eq!(files.open_count(), 2);
+    }
+
+    #[test]
+    fn test_keeps_handles_on_errors() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let files = SysfsFiles::new(tempdir.path().to_owned());
+        // Opens fine but fails to read, unlike a file that's gone.
+        fs::create_dir(tempdir.path().join("speed_pid")).unwrap();
+
+        assert!(files.read("speed_pid").is_err());
+        assert_eq!(files.open_count(), 1);
+    }
+}
@@ -0,0 +1,222 @@
+//! Fake sysfs trees for running the EV3 HAL off the brick.  The devices mirror what ev3dev
+//! creates for LEGO hardware, down to the file permissions that attribute discovery relies on.
+//!
+//! Also built as the `sysfs_fixture` example, which generates a persistent tree for running the
+//! server against with `EV3_SYSFS_ROOT`.
+//!
+//! Unlike sysfs, the files are regular files and nothing reacts to writes, e.g. writing `command`
+//! leaves `state` alone.  The HAL truncates what it writes, so the files hold exactly the last
+//! value written.
+
+use std::fs::{self, Permissions};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+use tempfile::TempDir;
+
+const READ_ONLY: u32 = 0o444;
+const READ_WRITE: u32 = 0o664;
+const WRITE_ONLY: u32 = 0o220;
+
+pub struct SysfsFixture {
+    root: PathBuf,
+
+    /// Deletes the tree when the fixture is dropped, unless it was created with [`Self::at`].
+    _tempdir: Option<TempDir>,
+}
+
+impl SysfsFixture {
+    /// An empty tree in a temporary directory, i.e. one with the class directories but no
+    /// devices or ports.
+    pub fn new() -> Self {
+        let tempdir = tempfile::tempdir().unwrap();
+        let fixture = Self::at(tempdir.path());
+        Self {
+            _tempdir: Some(tempdir),
+            ..fixture
+        }
+    }
+
+    /// An empty tree at `root`, which is left in place.
+    pub fn at(root: &Path) -> Self {
+        for class in ["tacho-motor", "lego-sensor", "lego-port", "leds"] {
+            fs::create_dir_all(root.join("class").join(class)).unwrap();
+        }
+        Self {
+            root: root.to_owned(),
+            _tempdir: None,
+        }
+    }
+
+    /// To be passed to `HalEv3::with_sysfs_root`.
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    pub fn class_root(&self) -> PathBuf {
+        self.root().join("class")
+    }
+
+    /// Adds a `lego-ev3-l-motor` at `address` (e.g. `ev3-ports:outA`) as `motorN`.
+    pub fn add_tacho_motor(&self, name: &str, address: &str) -> PathBuf {
+        let dir = self.class_root().join("tacho-motor").join(name);
+        let attributes = [
+            ("address", address, READ_ONLY),
+            ("command", "", WRITE_ONLY),
+            (
+                "commands",
+                "run-forever run-to-abs-pos run-to-rel-pos run-timed run-direct stop reset",
+                READ_ONLY,
+            ),
+            ("count_per_rot", "360", READ_ONLY),
+            ("driver_name", "lego-ev3-l-motor", READ_ONLY),
+            ("duty_cycle", "0", READ_ONLY),
+            ("duty_cycle_sp", "0", READ_WRITE),
+            ("max_speed", "1050", READ_ONLY),
+            ("polarity", "normal", READ_WRITE),
+            ("position", "0", READ_WRITE),
+            ("position_sp", "0", READ_WRITE),
+            ("ramp_down_sp", "0", READ_WRITE),
+            ("ramp_up_sp", "0", READ_WRITE),
+            ("speed", "0", READ_ONLY),
+            ("speed_sp", "0", READ_WRITE),
+            ("state", "", READ_ONLY),
+            ("stop_action", "coast", READ_WRITE),
+            ("stop_actions", "coast brake hold", READ_ONLY),
+            ("time_sp", "0", READ_WRITE),
+            ("hold_pid/Kd", "0", READ_WRITE),
+            ("hold_pid/Ki", "0", READ_WRITE),
+            ("hold_pid/Kp", "20000", READ_WRITE),
+            ("speed_pid/Kd", "0", READ_WRITE),
+            ("speed_pid/Ki", "60", READ_WRITE),
+            ("speed_pid/Kp", "1000", READ_WRITE),
+            ("uevent", "", READ_WRITE),
+        ];
+        self.write_device(&dir, &attributes, &[]);
+        dir
+    }
+
+    /// Adds a `lego-ev3-color` sensor at `address` (e.g. `ev3-ports:in1`) as `sensorN`, in
+    /// `COL-REFLECT` mode.
+    pub fn add_color_sensor(&self, name: &str, address: &str) -> PathBuf {
+        let dir = self.class_root().join("lego-sensor").join(name);
+        let attributes = [
+            ("address", address, READ_ONLY),
+            ("bin_data_format", "s8", READ_ONLY),
+            ("command", "", WRITE_ONLY),
+            ("commands", "", READ_ONLY),
+            ("decimals", "0", READ_ONLY),
+            ("driver_name", "lego-ev3-color", READ_ONLY),
+            ("fw_version", "", READ_ONLY),
+            ("mode", "COL-REFLECT", READ_WRITE),
+            (
+                "modes",
+                "COL-REFLECT COL-AMBIENT COL-COLOR REF-RAW RGB-RAW COL-CAL",
+                READ_ONLY,
+            ),
+            ("num_values", "1", READ_ONLY),
+            ("poll_ms", "10", READ_WRITE),
+            ("units", "pct", READ_ONLY),
+            ("value0", "0", READ_ONLY),
+            ("value1", "0", READ_ONLY),
+            ("value2", "0", READ_ONLY),
+            ("uevent", "", READ_WRITE),
+        ];
+        // Raw bytes rather than text, matching `value0`.
+        self.write_device(&dir, &attributes, &[("bin_data", &[0])]);
+        dir
+    }
+
+    /// Adds an EV3 input or output port such as `ev3-ports:in1` as `portN`, in `auto` mode.
+    pub fn add_port(&self, name: &str, address: &str) -> PathBuf {
+        let dir = self.class_root().join("lego-port").join(name);
+        let (driver_name, modes, status) = if address.contains(":in") {
+            (
+                "ev3-input-port",
+                "auto ev3-analog ev3-uart ev3-i2c nxt-analog nxt-color nxt-i2c other-uart raw",
+                "no-sensor",
+            )
+        } else {
+            (
+                "ev3-output-port",
+                "auto tacho-motor dc-motor led raw",
+                "no-motor",
+            )
+        };
+        let attributes = [
+            ("address", address, READ_ONLY),
+            ("driver_name", driver_name, READ_ONLY),
+            ("mode", "auto", READ_WRITE),
+            ("modes", modes, READ_ONLY),
+            ("set_device", "", WRITE_ONLY),
+            ("status", status, READ_ONLY),
+            ("uevent", "", READ_WRITE),
+        ];
+        self.write_device(&dir, &attributes, &[]);
+        dir
+    }
+
+    /// Adds an LED of the generic Linux LED class, e.g. `led0:green:brick-status` or one that
+    /// isn't part of the brick such as `mmc0::`.
+    pub fn add_led(&self, name: &str) -> PathBuf {
+        let dir = self.class_root().join("leds").join(name);
+        let attributes = [
+            ("brightness", "0", READ_WRITE),
+            ("max_brightness", "255", READ_ONLY),
+            ("trigger", "[none] timer heartbeat default-on", READ_WRITE),
+            ("uevent", "", READ_WRITE),
+        ];
+        self.write_device(&dir, &attributes, &[]);
+        dir
+    }
+
+    /// Unplugs the device or port at `dir`, as returned by one of the `add_` methods.
+    pub fn remove(&self, dir: &Path) {
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    /// Replaces an attribute's value as the driver would, newline included, e.g. to simulate a
+    /// reading changing.  Works for read-only attributes too.
+    pub fn set(&self, dir: &Path, name: &str, value: &str) {
+        let path = dir.join(name);
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        fs::set_permissions(&path, Permissions::from_mode(mode | 0o200)).unwrap();
+        fs::write(&path, format!("{value}\n")).unwrap();
+        fs::set_permissions(&path, Permissions::from_mode(mode)).unwrap();
+    }
+
+    /// Reads back an attribute exactly as written, e.g. to check what the HAL wrote.
+    pub fn get(&self, dir: &Path, name: &str) -> String {
+        fs::read_to_string(dir.join(name)).unwrap()
+    }
+
+    fn write_device(
+        &self,
+        dir: &Path,
+        attributes: &[(&str, &str, u32)],
+        binary_attributes: &[(&str, &[u8])],
+    ) {
+        // Create the attributes outside of the class directory and move them into place, so that
+        // the HAL never sees a half-populated device.
+        let staging = self.root().join("staging").join(dir.file_name().unwrap());
+        for (name, value, mode) in attributes {
+            let path = staging.join(name);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            let contents = if value.is_empty() {
+                String::new()
+            } else {
+                format!("{value}\n")
+            };
+            fs::write(&path, contents).unwrap();
+            fs::set_permissions(&path, Permissions::from_mode(*mode)).unwrap();
+        }
+        for (name, data) in binary_attributes {
+            let path = staging.join(name);
+            fs::write(&path, data).unwrap();
+            fs::set_permissions(&path, Permissions::from_mode(READ_ONLY)).unwrap();
+        }
+        fs::rename(&staging, dir).unwrap();
+        // Only fails if another device is being staged.
+        let _ = fs::remove_dir(staging.parent().unwrap());
+    }
+}
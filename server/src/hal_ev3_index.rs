@@ -0,0 +1,270 @@
+//! Index of the sysfs class devices by address and driver name.  Looking a device up by address
+//! otherwise means listing every class directory and reading every device's `address` file, which
+//! is measurable on the EV3 when clients poll.
+//!
+//! The index is rebuilt lazily whenever the watch from [`watch_sysfs_classes`] (the same one
+//! behind [`crate::hal::Hal::watch_devices`]) reports a change, so it can lag behind devices
+//! coming and going by up to the poll interval, [`SYSFS_CLASS_POLL_INTERVAL`] by default.  Requests for a device that has just
+//! gone away fail with [`crate::hal::HalError::DeviceGone`] in the meantime, while looking up an
+//! address that isn't indexed rebuilds the index right away so that devices that have just been
+//! plugged in are found.
+//!
+//! Entries also own the device's open attribute files (see [`crate::hal_ev3_files`]), which
+//! carry over to the rebuilt index for devices that are still there and are closed for devices
+//! that aren't.
+
+use std::fs::{read_dir, DirEntry};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::TryRecvError;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use log::{debug, warn};
+
+use crate::hal::{HalDevice, WatchHandle};
+use crate::hal_ev3::{is_exposed_device, watch_paths, HalDeviceEv3, DEVICE_SYSFS_CLASSES};
+use crate::hal_ev3_files::SysfsFiles;
+
+/// How often [`watch_sysfs_classes`] polls for devices coming and going unless told otherwise.
+pub const SYSFS_CLASS_POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Clone)]
+pub struct IndexEntry {
+    pub sysfs_class: &'static str,
+    pub device_name: String,
+    pub address: String,
+    pub driver_name: String,
+    files: Arc<SysfsFiles>,
+}
+
+impl IndexEntry {
+    pub fn to_device(&self) -> HalDeviceEv3 {
+        HalDeviceEv3::with_files(self.sysfs_class, &self.device_name, self.files.clone())
+    }
+}
+
+pub struct DeviceIndex {
+    class_root: PathBuf,
+    poll_interval: Duration,
+    cache: Mutex<Option<CachedIndex>>,
+}
+
+struct CachedIndex {
+    entries: Arc<Vec<IndexEntry>>,
+    watch: WatchHandle,
+}
+
+impl DeviceIndex {
+    pub fn new(class_root: PathBuf) -> Self {
+        Self {
+            class_root,
+            poll_interval: SYSFS_CLASS_POLL_INTERVAL,
+            cache: Mutex::new(None),
+        }
+    }
+
+    pub fn with_poll_interval(mut self, poll_interval: Duration) -> Self {
+        self.poll_interval = poll_interval;
+        self
+    }
+
+    pub fn class_root(&self) -> &Path {
+        &self.class_root
+    }
+
+    pub fn poll_interval(&self) -> Duration {
+        self.poll_interval
+    }
+
+    /// All devices, from the cache unless something changed since it was built.
+    pub fn entries(&self) -> io::Result<Arc<Vec<IndexEntry>>> {
+        self.refresh(false).map(|(entries, _)| entries)
+    }
+
+    /// Like [`Self::entries`] but rebuilds the index regardless if `force` is set.  Also returns
+    /// whether the index was rebuilt.
+    fn refresh(&self, force: bool) -> io::Result<(Arc<Vec<IndexEntry>>, bool)> {
+        let mut cache = self.cache.lock().unwrap();
+        let previous = match cache.take() {
+            Some(cached) if !force && !has_changed(&cached.watch) => {
+                let entries = cached.entries.clone();
+                *cache = Some(cached);
+                return Ok((entries, false));
+            }
+            Some(cached) => cached.entries,
+            None => Arc::new(Vec::new()),
+        };
+
+        // Start watching before scanning so that changes made during the scan aren't missed.
+        debug!("Rebuilding device index...");
+        let watch = watch_sysfs_classes(&self.class_root, self.poll_interval);
+        let entries = Arc::new(rescan(&self.class_root, &previous)?);
+        *cache = match watch {
+            Ok(watch) => Some(CachedIndex {
+                entries: entries.clone(),
+                watch,
+            }),
+            Err(e) => {
+                warn!("Cannot watch devices, not caching them: {e}");
+                None
+            }
+        };
+        Ok((entries, true))
+    }
+
+    /// Looks up the device at `address`, rebuilding the index first if it isn't there since
+    /// the watch may not have noticed the device yet.
+    pub fn by_address(&self, address: &str) -> io::Result<Option<IndexEntry>> {
+        let find = |entries: &[IndexEntry]| entries.iter().find(|e| e.address == address).cloned();
+        let (entries, rebuilt) = self.refresh(false)?;
+        match find(&entries) {
+            None if !rebuilt => Ok(find(&self.refresh(true)?.0)),
+            found => Ok(found),
+        }
+    }
+
+    pub fn by_driver(&self, driver_name: &str) -> io::Result<Vec<IndexEntry>> {
+        Ok(self
+            .entries()?
+            .iter()
+            .filter(|e| e.driver_name == driver_name)
+            .cloned()
+            .collect())
+    }
+}
+
+/// Watches the class directories for devices coming and going.
+pub fn watch_sysfs_classes(
+    class_root: &Path,
+    poll_interval: Duration,
+) -> anyhow::Result<WatchHandle> {
+    let paths = DEVICE_SYSFS_CLASSES
+        .map(|class| class_root.join(class).to_string_lossy().into_owned())
+        .into_iter()
+        .filter(|path| Path::new(path).exists())
+        .collect::<Vec<_>>();
+    watch_paths(&paths, poll_interval)
+}
+
+/// Reads the address and driver name of every device, bypassing the cache.
+pub fn scan(class_root: &Path) -> io::Result<Vec<IndexEntry>> {
+    rescan(class_root, &[])
+}
+
+/// Like [`scan`] but keeps the open files of devices in `previous` that are still present.  The
+/// address and driver name are always read afresh so that replaced devices get new files.
+fn rescan(class_root: &Path, previous: &[IndexEntry]) -> io::Result<Vec<IndexEntry>> {
+    let mut entries = Vec::new();
+    for sysfs_class in DEVICE_SYSFS_CLASSES {
+        for dir_entry in read_sysfs_class_dir(class_root, sysfs_class)? {
+            let device_name = match dir_entry.file_name().to_str() {
+                Some(name) if is_exposed_device(sysfs_class, name) => name.to_owned(),
+                _ => continue,
+            };
+            let device = HalDeviceEv3::new(class_root, sysfs_class, &device_name);
+            // Devices can go away mid-scan, in which case they simply aren't indexed.
+            let (address, driver_name) = match (device.get_address(), device.get_driver_name()) {
+                (Ok(address), Ok(driver_name)) => (address, driver_name),
+                _ => continue,
+            };
+            // A device replaced by another one since the last scan leaves stale handles behind,
+            // which either fail or still describe the device that went away.
+            let kept = previous
+                .iter()
+                .find(|e| e.sysfs_class == sysfs_class && e.device_name == device_name)
+                .filter(|e| e.driver_name == driver_name)
+                .filter(|e| matches!(e.to_device().get_address(), Ok(kept) if kept == address));
+            let files = match kept {
+                Some(entry) => entry.files.clone(),
+                None => device.files().clone(),
+            };
+            entries.push(IndexEntry {
+                sysfs_class,
+                device_name,
+                address,
+                driver_name,
+                files,
+            });
+        }
+    }
+    Ok(entries)
+}
+
+/// Lists the entries of a sysfs class directory.  A class that isn't registered at all (e.g.
+/// because no driver for it has been loaded) is treated as having no devices.
+fn read_sysfs_class_dir(class_root: &Path, sysfs_class: &str) -> io::Result<Vec<DirEntry>> {
+    match read_dir(class_root.join(sysfs_class)) {
+        Ok(entries) => Ok(entries.flatten().collect()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e),
+    }
+}
+
+fn has_changed(watch: &WatchHandle) -> bool {
+    match watch.receiver.try_recv() {
+        Ok(()) => {
+            // Collapse bursts of events, e.g. a sensor and its port showing up together.
+            while watch.receiver.try_recv().is_ok() {}
+            true
+        }
+        Err(TryRecvError::Empty) => false,
+        Err(TryRecvError::Disconnected) => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hal_ev3_fixture::SysfsFixture;
+
+    /// Creates a fake sysfs tree with `count` motors and as many sensors.
+    fn fake_sysfs(count: usize) -> SysfsFixture {
+        let fixture = SysfsFixture::new();
+        for i in 0..count {
+            fixture.add_tacho_motor(&format!("motor{i}"), &format!("ev3-ports:out{i}"));
+            fixture.add_color_sensor(&format!("sensor{i}"), &format!("ev3-ports:in{i}"));
+        }
+        fixture
+    }
+
+    #[test]
+    fn test_lookup() {
+        let sysfs = fake_sysfs(2);
+        let index = DeviceIndex::new(sysfs.class_root());
+
+        let entry = index.by_address("ev3-ports:in1").unwrap().unwrap();
+        assert_eq!(entry.sysfs_class, "lego-sensor");
+        assert_eq!(entry.device_name, "sensor1");
+        assert_eq!(index.by_driver("lego-ev3-l-motor").unwrap().len(), 2);
+        assert!(index.by_address("ev3-ports:bogus").unwrap().is_none());
+        assert_eq!(index.entries().unwrap().len(), 4);
+    }
+
+    #[test]
+    fn test_lookup_finds_new_device() {
+        let sysfs = fake_sysfs(1);
+        let index = DeviceIndex::new(sysfs.class_root());
+        assert!(index.by_address("ev3-ports:in1").unwrap().is_none());
+
+        // Well within the poll interval, so only the lookup miss can have picked it up.
+        sysfs.add_color_sensor("sensor1", "ev3-ports:in1");
+        let entry = index.by_address("ev3-ports:in1").unwrap().unwrap();
+        assert_eq!(entry.device_name, "sensor1");
+    }
+
+    #[test]
+    fn test_replaced_device_gets_new_files() {
+        let sysfs = fake_sysfs(2);
+        let index = DeviceIndex::new(sysfs.class_root());
+        let old = index.by_address("ev3-ports:in1").unwrap().unwrap();
+        assert_eq!(old.to_device().get_address().unwrap(), "ev3-ports:in1");
+
+        // Replugged elsewhere before the watch noticed, so the name stays the same.
+        sysfs.remove(&sysfs.class_root().join("lego-sensor/sensor1"));
+        sysfs.add_color_sensor("sensor1", "ev3-ports:in2");
+        let new = index.by_address("ev3-ports:in2").unwrap().unwrap();
+        assert_eq!(new.device_name, "sensor1");
+        assert_eq!(new.to_device().get_address().unwrap(), "ev3-ports:in2");
+    }
+}
@@ -0,0 +1,184 @@
+//! Runs the real EV3 HAL against fake sysfs trees from [`crate::hal_ev3_fixture`].
+
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::hal::{Hal, HalError};
+use crate::hal_ev3::HalEv3;
+use crate::hal_ev3_fixture::SysfsFixture;
+
+/// Poll interval of the watches, much shorter than on the brick to keep the tests fast.
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// Generous compared to [`POLL_INTERVAL`] since tests run in parallel.
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+fn hal_for(fixture: &SysfsFixture) -> HalEv3 {
+    HalEv3::with_sysfs_root(fixture.root()).with_poll_interval(POLL_INTERVAL)
+}
+
+fn wait_until(mut condition: impl FnMut() -> bool) {
+    let start = Instant::now();
+    while !condition() {
+        assert!(start.elapsed() < TIMEOUT, "Timed out");
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+#[test]
+fn test_devices_and_attributes() {
+    let fixture = SysfsFixture::new();
+    fixture.add_tacho_motor("motor0", "ev3-ports:outA");
+    let sensor = fixture.add_color_sensor("sensor0", "ev3-ports:in1");
+    fixture.set(&sensor, "value0", "37");
+    let hal = hal_for(&fixture);
+
+    assert_eq!(hal.list_devices().unwrap().len(), 2);
+    assert_eq!(hal.by_driver("lego-ev3-color").unwrap().len(), 1);
+    assert!(hal.by_address("ev3-ports:outB").unwrap().is_none());
+
+    let motor = hal.by_address("ev3-ports:outA").unwrap().unwrap();
+    let attributes = motor.get_applicable_attributes().unwrap();
+    let names: Vec<_> = attributes.iter().map(|a| a.name.as_str()).collect();
+    for name in [
+        "command",
+        "hold_pid/Kp",
+        "position_deg",
+        "speed_sp",
+        "speed_sp_rpm",
+    ] {
+        assert!(names.contains(&name), "{name} missing from {names:?}");
+    }
+    assert_eq!(motor.get_attribute_str("max_speed").unwrap(), "1050");
+
+    let sensor = hal.by_address("ev3-ports:in1").unwrap().unwrap();
+    let attributes = sensor.get_applicable_attributes().unwrap();
+    let value0 = attributes.iter().find(|a| a.name == "value0").unwrap();
+    assert_eq!(value0.units.as_deref(), Some("pct"));
+    assert!(attributes.iter().all(|a| a.name != "value1"));
+    assert_eq!(sensor.get_attribute_str("value0").unwrap(), "37");
+    assert_eq!(sensor.get_attribute_str("values").unwrap(), "0");
+}
+
+#[test]
+fn test_leds() {
+    let fixture = SysfsFixture::new();
+    let dir = fixture.add_led("led0:green:brick-status");
+    fixture.add_led("mmc0::");
+    let hal = hal_for(&fixture);
+
+    let leds = hal.by_driver("leds").unwrap();
+    assert_eq!(leds.len(), 1);
+    assert!(hal.by_address("mmc0::").unwrap().is_none());
+
+    let mut led = hal.by_address("led0:green:brick-status").unwrap().unwrap();
+    assert_eq!(led.get_attribute_str("trigger").unwrap(), "none");
+    assert_eq!(
+        led.get_attribute_str("triggers").unwrap(),
+        "none timer heartbeat default-on"
+    );
+    led.set_attribute_str("brightness", "255").unwrap();
+    assert_eq!(fixture.get(&dir, "brightness"), "255");
+}
+
+#[test]
+fn test_attribute_writes() {
+    let fixture = SysfsFixture::new();
+    let dir = fixture.add_tacho_motor("motor0", "ev3-ports:outA");
+    let hal = hal_for(&fixture);
+    let mut motor = hal.by_address("ev3-ports:outA").unwrap().unwrap();
+
+    motor.set_attribute_str("speed_sp", "500").unwrap();
+    assert_eq!(fixture.get(&dir, "speed_sp"), "500");
+    motor.set_attribute_str("speed_sp", "50").unwrap();
+    assert_eq!(fixture.get(&dir, "speed_sp"), "50");
+    motor.set_attribute_str("position_sp_deg", "90").unwrap();
+    assert_eq!(fixture.get(&dir, "position_sp"), "90");
+    motor
+        .set_attribute_str("command", "run-to-abs-pos")
+        .unwrap();
+    assert_eq!(fixture.get(&dir, "command"), "run-to-abs-pos");
+
+    assert!(matches!(
+        motor.set_attribute_str("bogus", "1"),
+        Err(HalError::UnknownAttribute(_))
+    ));
+
+    // Values written by the driver show up through already open files.
+    assert_eq!(motor.get_attribute_str("position").unwrap(), "0");
+    fixture.set(&dir, "position", "720");
+    assert_eq!(motor.get_attribute_str("position").unwrap(), "720");
+    assert_eq!(motor.get_attribute_str("position_deg").unwrap(), "720");
+}
+
+#[test]
+fn test_hot_plug() {
+    let fixture = SysfsFixture::new();
+    fixture.add_tacho_motor("motor0", "ev3-ports:outA");
+    let hal = hal_for(&fixture);
+    assert!(hal.by_address("ev3-ports:in2").unwrap().is_none());
+    let watch = hal.watch_devices().unwrap();
+
+    let dir = fixture.add_color_sensor("sensor0", "ev3-ports:in2");
+    watch.receiver.recv_timeout(TIMEOUT).unwrap();
+    wait_until(|| hal.by_address("ev3-ports:in2").unwrap().is_some());
+    let sensor = hal.by_address("ev3-ports:in2").unwrap().unwrap();
+    assert_eq!(hal.list_devices().unwrap().len(), 2);
+
+    fixture.remove(&dir);
+    watch.receiver.recv_timeout(TIMEOUT).unwrap();
+    assert!(matches!(
+        sensor.get_attribute_str("mode"),
+        Err(HalError::DeviceGone)
+    ));
+    wait_until(|| hal.by_address("ev3-ports:in2").unwrap().is_none());
+    assert_eq!(hal.list_devices().unwrap().len(), 1);
+}
+
+#[test]
+fn test_watch_attributes() {
+    let fixture = SysfsFixture::new();
+    let dir = fixture.add_color_sensor("sensor0", "ev3-ports:in1");
+    let hal = hal_for(&fixture);
+    let sensor = hal.by_address("ev3-ports:in1").unwrap().unwrap();
+    let watch = sensor.watch_attributes(&["value0".to_owned()]).unwrap();
+
+    fixture.set(&dir, "value0", "12");
+    watch.receiver.recv_timeout(TIMEOUT).unwrap();
+    assert_eq!(sensor.get_attribute_str("value0").unwrap(), "12");
+
+    // Scaled attributes are also watched for mode changes.
+    fixture.set(&dir, "mode", "COL-AMBIENT");
+    watch.receiver.recv_timeout(TIMEOUT).unwrap();
+}
+
+#[test]
+fn test_ports() {
+    let fixture = SysfsFixture::new();
+    let in1 = fixture.add_port("port0", "ev3-ports:in1");
+    fixture.add_port("port4", "ev3-ports:outA");
+    let hal = hal_for(&fixture);
+
+    assert_eq!(hal.list_ports().unwrap().len(), 2);
+    let mut port = hal.port_by_address("ev3-ports:in1").unwrap().unwrap();
+    assert_eq!(port.get_driver_name().unwrap(), "ev3-input-port");
+    assert_eq!(port.get_status().unwrap(), "no-sensor");
+    assert!(port.get_modes().unwrap().contains(&"nxt-i2c".to_owned()));
+
+    let watch = hal.watch_ports().unwrap();
+    port.set_mode("nxt-i2c").unwrap();
+    assert_eq!(fixture.get(&in1, "mode"), "nxt-i2c");
+    watch.receiver.recv_timeout(TIMEOUT).unwrap();
+    port.set_device("ht-nxt-compass 0x01").unwrap();
+    assert_eq!(fixture.get(&in1, "set_device"), "ht-nxt-compass 0x01");
+
+    let i2c1 = fixture.add_port("port8", "ev3-ports:in1:i2c1");
+    watch.receiver.recv_timeout(TIMEOUT).unwrap();
+    assert!(hal.port_by_address("ev3-ports:in1:i2c1").unwrap().is_some());
+
+    // Ports that showed up after the watch started are watched as well.  Wait for the changes
+    // so far to settle so that they can't be mistaken for the one below.
+    while watch.receiver.recv_timeout(POLL_INTERVAL * 10).is_ok() {}
+    fixture.set(&i2c1, "mode", "nxt-i2c");
+    watch.receiver.recv_timeout(TIMEOUT).unwrap();
+}
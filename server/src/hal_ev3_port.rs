@@ -0,0 +1,182 @@
+use std::fs::read_dir;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{RecvTimeoutError, Sender};
+use std::sync::{Arc, Weak};
+use std::thread;
+use std::time::Duration;
+
+use log::{debug, trace, warn};
+
+use crate::hal::{HalError, HalPort, HalResult, WatchHandle};
+use crate::hal_ev3::{read_sysfs_attribute, watch_paths, write_sysfs_attribute};
+
+/// sysfs class of the ports, under the class root.
+pub(crate) const LEGO_PORT_SYSFS_CLASS: &str = "lego-port";
+
+/// Port attributes whose changes are reported by [`watch_ports`].
+const WATCHED_PORT_ATTRIBUTES: [&str; 2] = ["mode", "status"];
+
+/// How often [`watch_ports`] polls for changes unless told otherwise.
+pub(crate) const PORT_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How often the thread behind [`watch_ports`] checks whether the watch was dropped.
+const CANCEL_CHECK_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Entry in `/sys/class/lego-port`, e.g. `port0`.
+pub struct HalPortEv3 {
+    full_port_path: PathBuf,
+}
+
+impl HalPortEv3 {
+    fn new(port_root: &Path, port_name: &str) -> Self {
+        Self {
+            full_port_path: port_root.join(port_name),
+        }
+    }
+
+    fn read_attribute(&self, name: &str) -> HalResult<String> {
+        trace!("Reading port attribute {}...", name);
+        read_sysfs_attribute(&self.full_port_path.join(name))
+    }
+
+    fn write_attribute(&self, name: &str, value: &str) -> HalResult<()> {
+        debug!("Writing port attribute {}={}...", name, value);
+        write_sysfs_attribute(&self.full_port_path.join(name), value)
+    }
+}
+
+impl HalPort for HalPortEv3 {
+    fn get_address(&self) -> HalResult<String> {
+        self.read_attribute("address")
+    }
+
+    fn get_driver_name(&self) -> HalResult<String> {
+        self.read_attribute("driver_name")
+    }
+
+    fn get_modes(&self) -> HalResult<Vec<String>> {
+        let modes = self.read_attribute("modes")?;
+        Ok(modes.split_whitespace().map(|m| m.to_owned()).collect())
+    }
+
+    fn get_mode(&self) -> HalResult<String> {
+        self.read_attribute("mode")
+    }
+
+    fn get_status(&self) -> HalResult<String> {
+        self.read_attribute("status")
+    }
+
+    fn set_mode(&mut self, mode: &str) -> HalResult<()> {
+        self.write_attribute("mode", mode)
+    }
+
+    fn set_device(&mut self, driver_name: &str) -> HalResult<()> {
+        self.write_attribute("set_device", driver_name)
+    }
+}
+
+/// Ports listed in `port_root`, i.e. the `lego-port` class directory.
+pub fn list_ports(port_root: &Path) -> HalResult<Vec<Box<dyn HalPort>>> {
+    let mut results = Vec::<Box<dyn HalPort>>::new();
+    for port_name in read_port_names(port_root).map_err(HalError::Io)? {
+        results.push(Box::new(HalPortEv3::new(port_root, &port_name)));
+    }
+    Ok(results)
+}
+
+pub fn port_by_address(port_root: &Path, address: &str) -> HalResult<Option<Box<dyn HalPort>>> {
+    for port_name in read_port_names(port_root).map_err(HalError::Io)? {
+        let port = HalPortEv3::new(port_root, &port_name);
+        if port.get_address().ok().as_deref() == Some(address) {
+            return Ok(Some(Box::new(port)));
+        }
+    }
+    Ok(None)
+}
+
+/// Watches for ports coming and going (which happens e.g. when a port is switched to a mode that
+/// registers a new port for a sensor mux) as well as mode and status changes of existing ports.
+/// The ports are re-listed whenever something changes so that those that show up later are
+/// watched too.
+pub fn watch_ports(port_root: &Path, poll_interval: Duration) -> anyhow::Result<WatchHandle> {
+    let port_names = read_port_names(port_root)?;
+    let inner = watch_port_paths(port_root, &port_names, poll_interval)?;
+    let (tx, rx) = std::sync::mpsc::channel();
+    let cancel_handle = Arc::new("ports".to_string());
+    let weak_handle = Arc::downgrade(&cancel_handle);
+    let port_root = port_root.to_owned();
+    thread::spawn(move || {
+        forward_port_changes(
+            &port_root,
+            poll_interval,
+            port_names,
+            inner,
+            tx,
+            weak_handle,
+        )
+    });
+    Ok(WatchHandle::new(cancel_handle, rx))
+}
+
+/// Passes changes from `inner` on to `tx` until `weak_handle` is dropped, replacing `inner` with
+/// a watch of the current ports whenever they differ from `port_names`.
+fn forward_port_changes(
+    port_root: &Path,
+    poll_interval: Duration,
+    mut port_names: Vec<String>,
+    mut inner: WatchHandle,
+    tx: Sender<()>,
+    weak_handle: Weak<String>,
+) {
+    while weak_handle.upgrade().is_some() {
+        match inner.receiver.recv_timeout(CANCEL_CHECK_INTERVAL) {
+            Ok(()) => {
+                let current = read_port_names(port_root).unwrap_or_default();
+                if current != port_names {
+                    // Watch the new ports before reporting the change so that a client reacting
+                    // to it doesn't miss their first mode change.
+                    match watch_port_paths(port_root, &current, poll_interval) {
+                        Ok(watch) => inner = watch,
+                        Err(e) => warn!("Cannot watch new ports, keeping the old watch: {e}"),
+                    }
+                    port_names = current;
+                }
+                if tx.send(()).is_err() {
+                    break;
+                }
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+    }
+}
+
+/// Watches the port directory itself and the [`WATCHED_PORT_ATTRIBUTES`] of `port_names`.
+fn watch_port_paths(
+    port_root: &Path,
+    port_names: &[String],
+    poll_interval: Duration,
+) -> anyhow::Result<WatchHandle> {
+    let mut paths = vec![port_root.to_string_lossy().into_owned()];
+    for port_name in port_names {
+        for attribute in WATCHED_PORT_ATTRIBUTES {
+            let path = port_root.join(port_name).join(attribute);
+            paths.push(path.to_string_lossy().into_owned());
+        }
+    }
+    watch_paths(&paths, poll_interval)
+}
+
+fn read_port_names(port_root: &Path) -> io::Result<Vec<String>> {
+    if !port_root.exists() {
+        return Ok(Vec::new());
+    }
+    let mut names = read_dir(port_root)?
+        .flatten()
+        .filter_map(|e| e.file_name().to_str().map(|s| s.to_owned()))
+        .collect::<Vec<_>>();
+    names.sort();
+    Ok(names)
+}
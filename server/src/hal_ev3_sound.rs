@@ -0,0 +1,227 @@
+use std::fs::{read_dir, File, OpenOptions};
+use std::io::Write;
+use std::os::raw::c_long;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::mpsc::{sync_channel, SyncSender, TrySendError};
+use std::sync::Mutex;
+use std::time::Duration;
+use std::{fs, io, mem, thread};
+
+use anyhow::anyhow;
+use lazy_static::lazy_static;
+use log::{debug, error};
+
+use crate::hal::{
+    HalAttribute, HalAttributeType, HalDevice, HalDeviceType, HalError, HalResult, WatchHandle,
+};
+use crate::hal_ev3::watch_paths;
+use crate::hal_sound::{sound_attributes, validate_clip_name, SoundRequest, Tone, MAX_CLIP_BYTES};
+
+/// evdev device exposed by the EV3 sound driver which accepts `EV_SND` events.
+pub const SOUND_EVENT_DEVICE: &str = "/dev/input/by-path/platform-sound-event";
+
+pub const SOUND_ADDRESS: &str = "sound";
+const SOUND_DRIVER_NAME: &str = "ev3-sound";
+
+const EV_SND: u16 = 0x12;
+const SND_TONE: u16 = 0x02;
+
+/// How many requests may wait for the player before new ones are rejected as busy.
+const MAX_QUEUED_REQUESTS: usize = 8;
+
+lazy_static! {
+    /// Queue of the single player thread, started on first use.  Playing everything from one
+    /// thread serializes overlapping requests rather than garbling them, and bounds the number
+    /// of threads however many requests come in.
+    static ref PLAYER: Mutex<Option<SyncSender<(SoundRequest, PathBuf)>>> = Mutex::new(None);
+}
+
+pub struct HalDeviceEv3Sound {
+    clips_dir: PathBuf,
+}
+
+impl HalDeviceEv3Sound {
+    pub fn with_clips_dir(clips_dir: PathBuf) -> Self {
+        Self { clips_dir }
+    }
+
+    pub fn is_present() -> bool {
+        Path::new(SOUND_EVENT_DEVICE).exists()
+    }
+
+    fn list_clips(&self) -> io::Result<Vec<String>> {
+        let mut clips = match read_dir(&self.clips_dir) {
+            Ok(entries) => entries
+                .flatten()
+                .filter_map(|e| e.file_name().to_str().map(|s| s.to_owned()))
+                .collect(),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e),
+        };
+        clips.sort();
+        Ok(clips)
+    }
+}
+
+impl HalDevice for HalDeviceEv3Sound {
+    fn get_type(&self) -> HalResult<HalDeviceType> {
+        Ok(HalDeviceType::Actuator)
+    }
+
+    fn get_driver_name(&self) -> HalResult<String> {
+        Ok(SOUND_DRIVER_NAME.to_owned())
+    }
+
+    fn get_address(&self) -> HalResult<String> {
+        Ok(SOUND_ADDRESS.to_owned())
+    }
+
+    fn get_applicable_attributes(&self) -> HalResult<Vec<HalAttribute>> {
+        let mut result = vec![
+            HalAttribute::new_readonly(HalAttributeType::String, "address"),
+            HalAttribute::new_readonly(HalAttributeType::String, "driver_name"),
+        ];
+        result.extend(sound_attributes());
+        Ok(result)
+    }
+
+    fn get_attribute_str(&self, name: &str) -> HalResult<String> {
+        match name {
+            "address" => self.get_address(),
+            "driver_name" => self.get_driver_name(),
+            "clips" => self
+                .list_clips()
+                .map(|clips| clips.join(" "))
+                .map_err(HalError::Io),
+            _ => Err(HalError::UnknownAttribute(name.to_owned())),
+        }
+    }
+
+    fn set_attribute_str(&mut self, name: &str, value: &str) -> HalResult<()> {
+        debug!("Writing attribute {}={}...", name, value);
+        let request = SoundRequest::parse(name, value)?;
+        if let SoundRequest::PlayClip(clip) = &request {
+            if !self.clips_dir.join(clip).exists() {
+                return Err(HalError::InvalidValue(format!("No such clip: {clip}")));
+            }
+        }
+
+        // Playback can take a while, don't hold up the request.
+        enqueue(request, self.clips_dir.clone())
+    }
+
+    fn put_blob(&mut self, name: &str, data: &[u8]) -> HalResult<()> {
+        validate_clip_name(name)?;
+        if data.len() > MAX_CLIP_BYTES {
+            return Err(HalError::InvalidValue(format!(
+                "Clip too large: {} > {} bytes",
+                data.len(),
+                MAX_CLIP_BYTES
+            )));
+        }
+        debug!("Storing clip {} ({} bytes)...", name, data.len());
+        fs::create_dir_all(&self.clips_dir)
+            .and_then(|_| fs::write(self.clips_dir.join(name), data))
+            .map_err(HalError::Io)
+    }
+
+    fn watch_attributes(&self, names: &[String]) -> anyhow::Result<WatchHandle> {
+        if names.iter().any(|name| name == "clips") {
+            fs::create_dir_all(&self.clips_dir)?;
+            let clips_dir = self.clips_dir.to_string_lossy().into_owned();
+            watch_paths(&[clips_dir], Duration::from_secs(1))
+        } else {
+            Err(anyhow!("No watchable attribute in {names:?}"))
+        }
+    }
+}
+
+/// Hands `request` to the player thread, (re)starting it if needed.
+fn enqueue(request: SoundRequest, clips_dir: PathBuf) -> HalResult<()> {
+    let mut player = PLAYER.lock().unwrap_or_else(|e| e.into_inner());
+    let queue = player.get_or_insert_with(start_player);
+    match queue.try_send((request, clips_dir)) {
+        Ok(()) => Ok(()),
+        Err(TrySendError::Full(_)) => Err(HalError::Busy),
+        Err(TrySendError::Disconnected((request, clips_dir))) => {
+            let queue = player.insert(start_player());
+            queue
+                .try_send((request, clips_dir))
+                .map_err(|_| HalError::Busy)
+        }
+    }
+}
+
+fn start_player() -> SyncSender<(SoundRequest, PathBuf)> {
+    let (tx, rx) = sync_channel::<(SoundRequest, PathBuf)>(MAX_QUEUED_REQUESTS);
+    thread::spawn(move || {
+        for (request, clips_dir) in rx {
+            if let Err(e) = play(&request, &clips_dir) {
+                error!("Failed to play {request:?}: {e:?}");
+            }
+        }
+    });
+    tx
+}
+
+fn play(request: &SoundRequest, clips_dir: &Path) -> anyhow::Result<()> {
+    match request {
+        SoundRequest::Tone(tone) => play_tones(&[*tone]),
+        SoundRequest::ToneSequence(tones) => play_tones(tones),
+        SoundRequest::PlayClip(clip) => {
+            let status = Command::new("aplay")
+                .arg("-q")
+                .arg(clips_dir.join(clip))
+                .status()?;
+            if status.success() {
+                Ok(())
+            } else {
+                Err(anyhow!("aplay exited with {status}"))
+            }
+        }
+        SoundRequest::Speak(text) => {
+            let mut espeak = Command::new("espeak")
+                .args(["-a", "200", "-s", "130", "--stdout"])
+                .arg(text)
+                .stdout(Stdio::piped())
+                .spawn()?;
+            let espeak_stdout = espeak
+                .stdout
+                .take()
+                .ok_or_else(|| anyhow!("espeak has no stdout"))?;
+            let status = Command::new("aplay")
+                .arg("-q")
+                .stdin(Stdio::from(espeak_stdout))
+                .status()?;
+            espeak.wait()?;
+            if status.success() {
+                Ok(())
+            } else {
+                Err(anyhow!("aplay exited with {status}"))
+            }
+        }
+    }
+}
+
+fn play_tones(tones: &[Tone]) -> anyhow::Result<()> {
+    let mut device = OpenOptions::new().write(true).open(SOUND_EVENT_DEVICE)?;
+    for tone in tones {
+        write_tone_event(&mut device, tone.frequency_hz)?;
+        thread::sleep(Duration::from_millis(tone.duration_ms.into()));
+        write_tone_event(&mut device, 0)?;
+        thread::sleep(Duration::from_millis(tone.delay_ms.into()));
+    }
+    Ok(())
+}
+
+/// Writes a `struct input_event` requesting a tone at the given frequency, or silence for 0.
+fn write_tone_event(device: &mut File, frequency_hz: u32) -> io::Result<()> {
+    // struct input_event { struct timeval time; __u16 type; __u16 code; __s32 value; }, where
+    // the timestamp is ignored for events written by userspace.
+    let mut event = vec![0u8; 2 * mem::size_of::<c_long>()];
+    event.extend(EV_SND.to_ne_bytes());
+    event.extend(SND_TONE.to_ne_bytes());
+    event.extend((frequency_hz as i32).to_ne_bytes());
+    device.write_all(&event)
+}
@@ -0,0 +1,650 @@
+//! Decorator making any HAL misbehave on purpose, to see how clients cope with slow sysfs reads,
+//! failing devices and devices that keep dropping off.  Wraps the HAL the server would use
+//! otherwise, so it works just as well on a brick as with the mock.
+//!
+//! Faults are configured per kind of operation in a TOML file (see [`FaultConfig`]):
+//!
+//! ```toml
+//! seed = 7
+//!
+//! [operations.read]
+//! latency_ms = 20
+//! jitter_ms = 30
+//! error_rate = 0.05
+//! error = "io"
+//!
+//! [operations.write]
+//! error_every = 10
+//! error = "busy"
+//!
+//! [[flapping]]
+//! address = "ev3-ports:in1"
+//! present_ms = 10000
+//! absent_ms = 2000
+//! ```
+//!
+//! Operations are `list` (looking up devices and ports), `read` (attribute and port reads),
+//! `write` (attribute writes, uploads, port changes) and `watch`.  Each can be slowed down by
+//! `latency_ms` plus up to `jitter_ms`, and fail with `error` (named like the error kinds reported
+//! to clients) either at random with probability `error_rate` or on every `error_every`th call.
+//! Flapping devices are present for `present_ms`, then gone for `absent_ms`, and so on.  While
+//! gone, the port at the same address reports the status `no-device`.
+//!
+//! Latency blocks the thread calling into the HAL, just as a slow sysfs read does.  Request
+//! handlers make their HAL calls on the async runtime's worker threads, so a long latency also
+//! holds up other requests waiting for the same worker, not just the one being slowed down.
+
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::mpsc::{RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::bail;
+use serde::Deserialize;
+
+use crate::clock::Clock;
+use crate::hal::{
+    FaultKind, Hal, HalAttribute, HalDevice, HalDeviceType, HalError, HalPort, HalResult,
+    WatchHandle,
+};
+use crate::hal_display::Display;
+use crate::rng::{next_rng, seed_rng};
+
+/// Status reported by the port of a device that has flapped away.
+const NO_DEVICE_STATUS: &str = "no-device";
+
+/// How often threads forwarding changes from the wrapped HAL check whether their watch was
+/// dropped.
+const CANCEL_CHECK_INTERVAL: Duration = Duration::from_millis(500);
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Operation {
+    List,
+    Read,
+    Write,
+    Watch,
+}
+
+const OPERATIONS: [Operation; 4] = [
+    Operation::List,
+    Operation::Read,
+    Operation::Write,
+    Operation::Watch,
+];
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FaultConfig {
+    /// Seeds the random errors and jitter, so that runs can be repeated.
+    #[serde(default)]
+    pub seed: u64,
+
+    #[serde(default)]
+    pub operations: OperationsFaults,
+
+    #[serde(default)]
+    pub flapping: Vec<Flapping>,
+}
+
+/// Faults per kind of operation, none for those left out.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct OperationsFaults {
+    pub list: Option<OperationFaults>,
+    pub read: Option<OperationFaults>,
+    pub write: Option<OperationFaults>,
+    pub watch: Option<OperationFaults>,
+}
+
+impl OperationsFaults {
+    pub fn get(&self, operation: Operation) -> Option<&OperationFaults> {
+        match operation {
+            Operation::List => self.list.as_ref(),
+            Operation::Read => self.read.as_ref(),
+            Operation::Write => self.write.as_ref(),
+            Operation::Watch => self.watch.as_ref(),
+        }
+    }
+
+    fn get_or_default(&mut self, operation: Operation) -> &mut OperationFaults {
+        let faults = match operation {
+            Operation::List => &mut self.list,
+            Operation::Read => &mut self.read,
+            Operation::Write => &mut self.write,
+            Operation::Watch => &mut self.watch,
+        };
+        faults.get_or_insert_with(OperationFaults::default)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct OperationFaults {
+    #[serde(default)]
+    pub latency_ms: u64,
+    #[serde(default)]
+    pub jitter_ms: u64,
+    #[serde(default)]
+    pub error_rate: f64,
+    pub error_every: Option<u32>,
+    #[serde(default = "default_error")]
+    pub error: FaultKind,
+}
+
+impl Default for OperationFaults {
+    fn default() -> Self {
+        Self {
+            latency_ms: 0,
+            jitter_ms: 0,
+            error_rate: 0.0,
+            error_every: None,
+            error: default_error(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Flapping {
+    pub address: String,
+    pub present_ms: u64,
+    pub absent_ms: u64,
+}
+
+fn default_error() -> FaultKind {
+    FaultKind::Io
+}
+
+impl FaultConfig {
+    /// Reads and validates the configuration at `path`.
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        Self::from_toml(&std::fs::read_to_string(path)?)
+    }
+
+    pub fn from_toml(contents: &str) -> anyhow::Result<Self> {
+        let config: Self = toml::from_str(contents)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Delays every operation by `latency_ms` instead of the latency configured so far, e.g. as
+    /// given on the command line.
+    pub fn with_latency(mut self, latency_ms: u64) -> Self {
+        for operation in OPERATIONS {
+            self.operations.get_or_default(operation).latency_ms = latency_ms;
+        }
+        self
+    }
+
+    /// Makes every operation fail with probability `error_rate` instead of the rate configured so
+    /// far, e.g. as given on the command line.
+    pub fn with_error_rate(mut self, error_rate: f64) -> anyhow::Result<Self> {
+        for operation in OPERATIONS {
+            self.operations.get_or_default(operation).error_rate = error_rate;
+        }
+        self.validate()?;
+        Ok(self)
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        for operation in OPERATIONS {
+            let faults = match self.operations.get(operation) {
+                Some(faults) => faults,
+                None => continue,
+            };
+            if !(0.0..=1.0).contains(&faults.error_rate) {
+                bail!("{operation:?}: error_rate must be between 0 and 1");
+            }
+            if faults.error_every == Some(0) {
+                bail!("{operation:?}: error_every must be at least 1");
+            }
+        }
+        for flapping in &self.flapping {
+            if flapping.present_ms == 0 || flapping.absent_ms == 0 {
+                bail!(
+                    "{}: present_ms and absent_ms must be positive",
+                    flapping.address
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+/// State shared by the HAL and every device and port handed out by it.
+#[derive(Debug)]
+struct FaultState {
+    config: FaultConfig,
+    clock: Arc<dyn Clock>,
+    started_at: Instant,
+    rng: Mutex<u64>,
+
+    /// Calls made since the last error, for `error_every`.
+    calls: Mutex<BTreeMap<Operation, u32>>,
+}
+
+impl FaultState {
+    /// Waits out the latency configured for `operation`, blocking the calling thread, then fails
+    /// if an error is due.
+    /// `address` and `what` describe what's being accessed for the error.
+    fn apply(&self, operation: Operation, address: &str, what: &str) -> HalResult<()> {
+        let faults = match self.config.operations.get(operation) {
+            Some(faults) => faults,
+            None => return Ok(()),
+        };
+
+        let jitter_ms = match faults.jitter_ms {
+            0 => 0,
+            jitter_ms => self.next_random() % (jitter_ms + 1),
+        };
+        let latency = Duration::from_millis(faults.latency_ms + jitter_ms);
+        if !latency.is_zero() {
+            self.clock.sleep_until(self.clock.now() + latency);
+        }
+
+        let is_scheduled = match faults.error_every {
+            Some(every) => {
+                let mut calls = self.calls.lock().unwrap();
+                let count = calls.entry(operation).or_default();
+                *count += 1;
+                if *count == every {
+                    *count = 0;
+                }
+                *count == 0
+            }
+            None => false,
+        };
+        let is_random = faults.error_rate > 0.0 && self.next_random_fraction() < faults.error_rate;
+        if is_scheduled || is_random {
+            log::debug!(
+                "Injecting {:?} into {operation:?} of {address} {what}",
+                faults.error
+            );
+            return Err(faults.error.to_hal_error(address, what));
+        }
+        Ok(())
+    }
+
+    /// Whether the device at `address` is currently flapped away.
+    fn is_absent(&self, address: &str) -> bool {
+        let elapsed = self.elapsed_ms();
+        self.config
+            .flapping
+            .iter()
+            .filter(|f| f.address == address)
+            .any(|f| elapsed % (f.present_ms + f.absent_ms) >= f.present_ms)
+    }
+
+    /// When the next flapping device comes or goes, if there are any.
+    fn next_flap(&self) -> Option<Instant> {
+        let elapsed = self.elapsed_ms();
+        let until_next = self.config.flapping.iter().map(|f| {
+            let phase = elapsed % (f.present_ms + f.absent_ms);
+            if phase < f.present_ms {
+                f.present_ms - phase
+            } else {
+                f.present_ms + f.absent_ms - phase
+            }
+        });
+        let until_next = until_next.min()?;
+        Some(self.started_at + Duration::from_millis(elapsed + until_next))
+    }
+
+    fn elapsed_ms(&self) -> u64 {
+        let elapsed = self.clock.now().saturating_duration_since(self.started_at);
+        elapsed.as_millis() as u64
+    }
+
+    fn next_random(&self) -> u64 {
+        let mut rng = self.rng.lock().unwrap();
+        *rng = next_rng(*rng);
+        *rng
+    }
+
+    /// Uniformly distributed in `0.0..1.0`.
+    fn next_random_fraction(&self) -> f64 {
+        (self.next_random() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+pub struct HalFaulty {
+    inner: Box<dyn Hal + Sync>,
+    state: Arc<FaultState>,
+}
+
+impl HalFaulty {
+    pub fn new(inner: Box<dyn Hal + Sync>, config: FaultConfig, clock: Arc<dyn Clock>) -> Self {
+        let state = FaultState {
+            rng: Mutex::new(seed_rng(config.seed)),
+            config,
+            started_at: clock.now(),
+            clock,
+            calls: Mutex::new(BTreeMap::new()),
+        };
+        Self {
+            inner,
+            state: Arc::new(state),
+        }
+    }
+
+    /// Wraps the devices that are currently present.
+    fn present(&self, devices: Vec<Box<dyn HalDevice>>) -> Vec<Box<dyn HalDevice>> {
+        devices
+            .into_iter()
+            .filter_map(|device| self.wrap_device(device))
+            .collect()
+    }
+
+    fn wrap_device(&self, device: Box<dyn HalDevice>) -> Option<Box<dyn HalDevice>> {
+        let address = device.get_address().unwrap_or_default();
+        if self.state.is_absent(&address) {
+            return None;
+        }
+        Some(Box::new(HalDeviceFaulty {
+            inner: device,
+            address,
+            state: self.state.clone(),
+        }))
+    }
+
+    /// Passes on the changes reported by `inner`, adding one whenever a flapping device comes or
+    /// goes.
+    fn with_flapping(&self, inner: WatchHandle) -> WatchHandle {
+        let (tx, rx) = std::sync::mpsc::channel();
+        let cancel_handle = Arc::new("faulty".to_string());
+        forward_changes(inner, tx.clone(), Arc::downgrade(&cancel_handle));
+        watch_flapping(self.state.clone(), tx, Arc::downgrade(&cancel_handle));
+        WatchHandle::new(cancel_handle, rx)
+    }
+
+    fn wrap_port(&self, port: Box<dyn HalPort>) -> Box<dyn HalPort> {
+        Box::new(HalPortFaulty {
+            address: port.get_address().unwrap_or_default(),
+            inner: port,
+            state: self.state.clone(),
+        })
+    }
+}
+
+impl Hal for HalFaulty {
+    fn list_devices(&self) -> HalResult<Vec<Box<dyn HalDevice>>> {
+        self.state.apply(Operation::List, "devices", "list")?;
+        Ok(self.present(self.inner.list_devices()?))
+    }
+
+    fn by_driver(&self, driver: &str) -> HalResult<Vec<Box<dyn HalDevice>>> {
+        self.state.apply(Operation::List, "devices", driver)?;
+        Ok(self.present(self.inner.by_driver(driver)?))
+    }
+
+    fn by_address(&self, address: &str) -> HalResult<Option<Box<dyn HalDevice>>> {
+        self.state.apply(Operation::List, address, "lookup")?;
+        let device = self.inner.by_address(address)?;
+        Ok(device.and_then(|device| self.wrap_device(device)))
+    }
+
+    fn watch_devices(&self) -> anyhow::Result<WatchHandle> {
+        self.state.apply(Operation::Watch, "devices", "watch")?;
+        Ok(self.with_flapping(self.inner.watch_devices()?))
+    }
+
+    fn list_ports(&self) -> HalResult<Vec<Box<dyn HalPort>>> {
+        self.state.apply(Operation::List, "ports", "list")?;
+        let ports = self.inner.list_ports()?;
+        Ok(ports.into_iter().map(|p| self.wrap_port(p)).collect())
+    }
+
+    fn port_by_address(&self, address: &str) -> HalResult<Option<Box<dyn HalPort>>> {
+        self.state.apply(Operation::List, address, "lookup")?;
+        let port = self.inner.port_by_address(address)?;
+        Ok(port.map(|p| self.wrap_port(p)))
+    }
+
+    fn watch_ports(&self) -> anyhow::Result<WatchHandle> {
+        self.state.apply(Operation::Watch, "ports", "watch")?;
+        Ok(self.with_flapping(self.inner.watch_ports()?))
+    }
+
+    fn display(&self) -> Option<Arc<Display>> {
+        self.inner.display()
+    }
+}
+
+struct HalDeviceFaulty {
+    inner: Box<dyn HalDevice>,
+    address: String,
+    state: Arc<FaultState>,
+}
+
+impl HalDeviceFaulty {
+    fn apply(&self, operation: Operation, name: &str) -> HalResult<()> {
+        if self.state.is_absent(&self.address) {
+            return Err(HalError::DeviceGone);
+        }
+        self.state.apply(operation, &self.address, name)
+    }
+}
+
+impl HalDevice for HalDeviceFaulty {
+    fn get_type(&self) -> HalResult<HalDeviceType> {
+        self.inner.get_type()
+    }
+
+    fn get_driver_name(&self) -> HalResult<String> {
+        self.inner.get_driver_name()
+    }
+
+    fn get_address(&self) -> HalResult<String> {
+        self.inner.get_address()
+    }
+
+    fn get_applicable_attributes(&self) -> HalResult<Vec<HalAttribute>> {
+        self.inner.get_applicable_attributes()
+    }
+
+    fn get_attribute_str(&self, name: &str) -> HalResult<String> {
+        self.apply(Operation::Read, name)?;
+        self.inner.get_attribute_str(name)
+    }
+
+    fn set_attribute_str(&mut self, name: &str, value: &str) -> HalResult<()> {
+        self.apply(Operation::Write, name)?;
+        self.inner.set_attribute_str(name, value)
+    }
+
+    fn put_blob(&mut self, name: &str, data: &[u8]) -> HalResult<()> {
+        self.apply(Operation::Write, name)?;
+        self.inner.put_blob(name, data)
+    }
+
+    fn watch_attributes(&self, names: &[String]) -> anyhow::Result<WatchHandle> {
+        self.apply(Operation::Watch, &names.join(","))?;
+        self.inner.watch_attributes(names)
+    }
+}
+
+struct HalPortFaulty {
+    inner: Box<dyn HalPort>,
+    address: String,
+    state: Arc<FaultState>,
+}
+
+impl HalPort for HalPortFaulty {
+    fn get_address(&self) -> HalResult<String> {
+        self.inner.get_address()
+    }
+
+    fn get_driver_name(&self) -> HalResult<String> {
+        self.inner.get_driver_name()
+    }
+
+    fn get_modes(&self) -> HalResult<Vec<String>> {
+        self.state.apply(Operation::Read, &self.address, "modes")?;
+        self.inner.get_modes()
+    }
+
+    fn get_mode(&self) -> HalResult<String> {
+        self.state.apply(Operation::Read, &self.address, "mode")?;
+        self.inner.get_mode()
+    }
+
+    fn get_status(&self) -> HalResult<String> {
+        self.state.apply(Operation::Read, &self.address, "status")?;
+        if self.state.is_absent(&self.address) {
+            return Ok(NO_DEVICE_STATUS.to_owned());
+        }
+        self.inner.get_status()
+    }
+
+    fn set_mode(&mut self, mode: &str) -> HalResult<()> {
+        self.state.apply(Operation::Write, &self.address, "mode")?;
+        self.inner.set_mode(mode)
+    }
+
+    fn set_device(&mut self, driver_name: &str) -> HalResult<()> {
+        self.state
+            .apply(Operation::Write, &self.address, "set_device")?;
+        self.inner.set_device(driver_name)
+    }
+}
+
+/// Passes changes from `inner` on to `tx` until `weak_handle` is dropped, which also cancels
+/// `inner`.
+fn forward_changes(inner: WatchHandle, tx: Sender<()>, weak_handle: Weak<String>) {
+    thread::spawn(move || {
+        while weak_handle.upgrade().is_some() {
+            match inner.receiver.recv_timeout(CANCEL_CHECK_INTERVAL) {
+                Ok(()) => {
+                    if tx.send(()).is_err() {
+                        break;
+                    }
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+        }
+    });
+}
+
+/// Emits a change on `tx` whenever a flapping device comes or goes, until `weak_handle` is
+/// dropped.
+fn watch_flapping(state: Arc<FaultState>, tx: Sender<()>, weak_handle: Weak<String>) {
+    if let Some(first_flap) = state.next_flap() {
+        let clock = state.clock.clone();
+        let tick = move |_| {
+            weak_handle.upgrade()?;
+            tx.send(()).ok()?;
+            state.next_flap()
+        };
+        clock.run_at(first_flap, Box::new(tick));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::{SystemClock, VirtualClock};
+    use crate::hal_mock::HalMock;
+
+    fn faulty(config: &str, clock: Arc<dyn Clock>) -> HalFaulty {
+        let config = FaultConfig::from_toml(config).unwrap();
+        let mock = HalMock::with_hardcoded_devices_and_clock(clock.clone());
+        HalFaulty::new(Box::new(mock), config, clock)
+    }
+
+    #[test]
+    fn test_scheduled_and_random_errors() {
+        let hal = faulty(
+            r#"
+            [operations.read]
+            error_every = 3
+            error = "busy"
+
+            [operations.write]
+            error_rate = 1.0
+            error = "not_connected"
+            "#,
+            SystemClock::shared(),
+        );
+        let mut motor = hal.by_address("ev3-ports:outA").unwrap().unwrap();
+
+        assert!(motor.get_attribute_str("speed_sp").is_ok());
+        assert!(motor.get_attribute_str("speed_sp").is_ok());
+        assert!(matches!(
+            motor.get_attribute_str("speed_sp"),
+            Err(HalError::Busy)
+        ));
+        assert!(motor.get_attribute_str("speed_sp").is_ok());
+        assert!(matches!(
+            motor.set_attribute_str("speed_sp", "100"),
+            Err(HalError::NotConnected { .. })
+        ));
+        // Listing is unaffected.
+        assert!(hal.list_ports().is_ok());
+    }
+
+    #[test]
+    fn test_latency() {
+        let hal = faulty(
+            r#"
+            [operations.list]
+            latency_ms = 50
+            jitter_ms = 10
+            "#,
+            SystemClock::shared(),
+        );
+        let start = Instant::now();
+        hal.list_devices().unwrap();
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(50), "{elapsed:?}");
+    }
+
+    #[test]
+    fn test_flapping() {
+        let clock = VirtualClock::new();
+        let hal = faulty(
+            r#"
+            [[flapping]]
+            address = "ev3-ports:in1"
+            present_ms = 1000
+            absent_ms = 500
+            "#,
+            clock.clone(),
+        );
+        let watch = hal.watch_devices().unwrap();
+        let ports_watch = hal.watch_ports().unwrap();
+        let sensor = hal.by_address("ev3-ports:in1").unwrap().unwrap();
+        let port = hal.port_by_address("ev3-ports:in1").unwrap().unwrap();
+        let status = port.get_status().unwrap();
+        assert_ne!(status, "no-device");
+        let count = hal.list_devices().unwrap().len();
+
+        clock.advance(Duration::from_millis(1000));
+        watch.receiver.try_recv().unwrap();
+        ports_watch.receiver.try_recv().unwrap();
+        assert_eq!(port.get_status().unwrap(), "no-device");
+        assert!(hal.by_address("ev3-ports:in1").unwrap().is_none());
+        assert_eq!(hal.list_devices().unwrap().len(), count - 1);
+        assert!(matches!(
+            sensor.get_attribute_str("value0"),
+            Err(HalError::DeviceGone)
+        ));
+
+        clock.advance(Duration::from_millis(500));
+        watch.receiver.try_recv().unwrap();
+        assert!(hal.by_address("ev3-ports:in1").unwrap().is_some());
+        assert!(sensor.get_attribute_str("value0").is_ok());
+        assert_eq!(port.get_status().unwrap(), status);
+    }
+
+    #[test]
+    fn test_invalid_config() {
+        assert!(FaultConfig::from_toml("[operations.read]\nerror_rate = 2.0").is_err());
+        assert!(FaultConfig::from_toml("[operations.read]\nerror_every = 0").is_err());
+        assert!(FaultConfig::from_toml("[operations.sideways]").is_err());
+        let flapping = "[[flapping]]\naddress = \"x\"\npresent_ms = 0\nabsent_ms = 1";
+        assert!(FaultConfig::from_toml(flapping).is_err());
+    }
+}
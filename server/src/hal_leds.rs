@@ -0,0 +1,47 @@
+//! Brick status LEDs exposed as actuator devices, one per color of each of the two LEDs (e.g.
+//! `led0:green:brick-status`), addressed by their Linux LED class name.
+//!
+//! Attributes:
+//!
+//! * **brightness**: `0` (off) up to `max_brightness`
+//! * **max_brightness**: brightest setting
+//! * **trigger**: kernel event driving the LED, e.g. `timer` or `heartbeat`, `none` for manual
+//!   control through `brightness`
+//! * **triggers**: available triggers
+
+use crate::hal::{HalAttribute, HalAttributeType, HalBounds};
+
+pub const LEDS_DRIVER_NAME: &str = "leds";
+
+/// Function of the brick status LEDs in their LED class names, as opposed to other LEDs the
+/// kernel knows about such as the SD card activity LED `mmc0::`.
+const EV3_LED_FUNCTION: &str = ":brick-status";
+
+/// Whether the LED class device `name` is one of the brick's status LEDs.
+pub fn is_ev3_led(name: &str) -> bool {
+    name.ends_with(EV3_LED_FUNCTION)
+}
+
+/// Attributes common to all LED devices, not including `address` and `driver_name`.
+pub fn led_attributes() -> Vec<HalAttribute> {
+    vec![
+        HalAttribute::new_rw(HalAttributeType::Int32, "brightness")
+            .with_bounds(HalBounds::UpTo("max_brightness".to_owned())),
+        HalAttribute::new_readonly(HalAttributeType::Int32, "max_brightness"),
+        HalAttribute::new_rw(HalAttributeType::String, "trigger")
+            .with_allowed_values_from("triggers"),
+        HalAttribute::new_readonly_array(HalAttributeType::String, "triggers"),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_ev3_led() {
+        assert!(is_ev3_led("led0:green:brick-status"));
+        assert!(is_ev3_led("led1:red:brick-status"));
+        assert!(!is_ev3_led("mmc0::"));
+    }
+}
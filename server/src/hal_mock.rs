@@ -1,80 +1,757 @@
-use anyhow::anyhow;
-use std::sync::Arc;
-use std::thread;
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use anyhow::{anyhow, bail};
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
 
+use crate::clock::{Clock, SystemClock};
+use crate::framebuffer::{Framebuffer, FramebufferInfo};
 use crate::hal::{
-    Hal, HalAttribute, HalAttributeType, HalDevice, HalDeviceType, HalError, HalResult, WatchHandle,
+    apply_bounds, FaultKind, Hal, HalAttribute, HalAttributeType, HalDevice, HalDeviceType,
+    HalError, HalPort, HalResult, WatchHandle,
 };
+use crate::hal_buttons::{
+    button_attributes, get_button_attribute_str, BUTTONS_ADDRESS, BUTTONS_DRIVER_NAME,
+};
+use crate::hal_display::{Display, HalDeviceDisplay};
+use crate::hal_leds::{led_attributes, LEDS_DRIVER_NAME};
+use crate::hal_mock_motor::{SimulatedMotor, SIMULATED_ATTRIBUTES};
+use crate::hal_mock_scenario::{DeviceClass, DeviceSpec, GeneratorState, Scenario};
+use crate::hal_motor_units::{add_unit_attributes, MotorUnitAttribute};
+use crate::hal_sound::{sound_attributes, validate_clip_name, SoundRequest, MAX_CLIP_BYTES};
+
+/// Time it takes the simulated battery to go from full to empty, at which point it is "swapped"
+/// for a fresh one and starts draining again.
+const MOCK_BATTERY_LIFETIME: Duration = Duration::from_secs(30 * 60);
+const MOCK_BATTERY_FULL_VOLTS: f64 = 8.4;
+const MOCK_BATTERY_EMPTY_VOLTS: f64 = 6.0;
+
+/// How often watched tacho motors are checked for changes in the simulated state.
+const MOTOR_WATCH_INTERVAL: Duration = Duration::from_millis(50);
+
+/// How often observers of generated scenario attributes are told to check for changes.
+const SCRIPTED_WATCH_INTERVAL: Duration = Duration::from_millis(250);
+
+const MOCK_INPUT_PORT_MODES: [&str; 8] = [
+    "auto",
+    "ev3-analog",
+    "ev3-uart",
+    "nxt-analog",
+    "nxt-color",
+    "nxt-i2c",
+    "other-i2c",
+    "raw",
+];
+const MOCK_OUTPUT_PORT_MODES: [&str; 5] = ["auto", "tacho-motor", "dc-motor", "led", "raw"];
+
+/// The brick status LEDs as named by ev3dev.
+const MOCK_LED_NAMES: [&str; 4] = [
+    "led0:green:brick-status",
+    "led0:red:brick-status",
+    "led1:green:brick-status",
+    "led1:red:brick-status",
+];
+const MOCK_LED_MAX_BRIGHTNESS: u32 = 255;
+const MOCK_LED_TRIGGERS: [&str; 4] = ["none", "default-on", "heartbeat", "timer"];
 
 pub struct HalMock {
+    devices: Arc<Mutex<MockDevices>>,
+
+    /// Renders into a regular file laid out like the EV3's framebuffer.
+    display: Option<Arc<Display>>,
+
+    ports: Arc<Mutex<MockPorts>>,
+
+    /// Drives all time-dependent behaviour, so that tests can run it faster than real time.
+    clock: Arc<dyn Clock>,
+}
+
+/// Everything the mock sound device has been asked to do, for inspection by tests.
+#[derive(Debug, Default)]
+struct MockSound {
+    requests: Vec<SoundRequest>,
+    clips: BTreeMap<String, Vec<u8>>,
+}
+
+/// Devices plugged in, plus everyone watching for them to come and go.
+#[derive(Debug, Default)]
+struct MockDevices {
     devices: Vec<HalDeviceMock>,
+    watchers: Vec<Sender<()>>,
+}
+
+/// Values and failures injected through [`MockControl`], which take precedence over what the
+/// device would report otherwise.  Shared by all handles to the device.
+#[derive(Debug, Default)]
+struct MockInjections {
+    values: BTreeMap<String, String>,
+
+    /// Failure and how many more reads of the attribute it applies to.
+    faults: BTreeMap<String, (FaultKind, u32)>,
+
+    /// Set once the device has been removed, failing any further access.
+    is_removed: bool,
+
+    /// Watches of the device along with the attributes each is interested in.
+    watchers: Vec<(Vec<String>, Sender<()>)>,
+}
+
+/// State of every port, plus everyone watching for mode/status changes.
+#[derive(Debug, Default)]
+struct MockPorts {
+    ports: Vec<MockPortState>,
+    watchers: Vec<Sender<()>>,
+}
+
+#[derive(Debug, Clone)]
+struct MockPortState {
+    address: String,
+    driver_name: &'static str,
+    modes: &'static [&'static str],
+    mode: String,
+    status: String,
+
+    /// What `status` reverts to when switching back to `auto`.
+    auto_status: String,
+}
+
+impl MockPortState {
+    fn new(
+        address: String,
+        driver_name: &'static str,
+        modes: &'static [&'static str],
+        auto_status: String,
+    ) -> Self {
+        Self {
+            address,
+            driver_name,
+            modes,
+            mode: "auto".to_owned(),
+            status: auto_status.clone(),
+            auto_status,
+        }
+    }
+
+    /// Updates what the port detects in `auto` mode, i.e. a device being plugged in or, with
+    /// `None`, unplugged.
+    fn set_auto_status(&mut self, auto_status: Option<String>) {
+        self.auto_status = auto_status.unwrap_or_else(|| {
+            let unplugged = if self.driver_name == "ev3-input-port" {
+                "no-sensor"
+            } else {
+                "no-motor"
+            };
+            unplugged.to_owned()
+        });
+        if self.mode == "auto" {
+            self.status = self.auto_status.clone();
+        }
+    }
+}
+
+/// Attribute values of a device described by a scenario.
+#[derive(Debug)]
+struct MockScripted {
+    started_at: Instant,
+    values: BTreeMap<String, MockValue>,
+}
+
+#[derive(Debug)]
+enum MockValue {
+    Fixed(String),
+    Generated(GeneratorState, HalAttributeType),
+}
+
+impl MockScripted {
+    fn get(&mut self, name: &str, now: Instant) -> Option<String> {
+        let elapsed = now.saturating_duration_since(self.started_at);
+        match self.values.get_mut(name)? {
+            MockValue::Fixed(value) => Some(value.clone()),
+            MockValue::Generated(generator, data_type) => {
+                Some(generator.value_at(elapsed, *data_type))
+            }
+        }
+    }
+}
+
+/// State of one of the brick status LEDs.
+#[derive(Debug)]
+struct MockLed {
+    brightness: u32,
+    trigger: String,
+}
+
+impl Default for MockLed {
+    fn default() -> Self {
+        Self {
+            brightness: 0,
+            trigger: "none".to_owned(),
+        }
+    }
+}
+
+/// Buttons currently held, plus everyone watching for presses.
+#[derive(Debug, Default)]
+struct MockButtons {
+    pressed: BTreeSet<&'static str>,
+    watchers: Vec<Sender<()>>,
 }
 
 impl HalMock {
     pub fn with_hardcoded_devices() -> Self {
-        let devices = vec![HalDeviceMock {
-            device_type: HalDeviceType::Sensor,
-            driver_name: "lego-ev3-ir".to_owned(),
-            address: "ev3-ports:in1".to_owned(),
-            attributes: vec![
-                HalAttribute::new_readonly(HalAttributeType::String, "mode"),
-                HalAttribute::new_readonly(HalAttributeType::UInt32, "value0"),
-            ],
-        }];
-        Self { devices }
+        Self::with_hardcoded_devices_and_clock(SystemClock::shared())
     }
-}
 
-impl Hal for HalMock {
-    fn list_devices(&self) -> HalResult<Vec<Box<dyn HalDevice>>> {
-        Ok(self
+    pub fn with_hardcoded_devices_and_clock(clock: Arc<dyn Clock>) -> Self {
+        let devices = vec![
+            HalDeviceMock {
+                kind: MockDeviceKind::InfraredSensor {
+                    installed_at: clock.now(),
+                },
+                device_type: HalDeviceType::Sensor,
+                driver_name: "lego-ev3-ir".to_owned(),
+                address: "ev3-ports:in1".to_owned(),
+                attributes: vec![
+                    HalAttribute::new_readonly(HalAttributeType::String, "mode"),
+                    HalAttribute::new_readonly(HalAttributeType::UInt32, "value0")
+                        .with_units(Some("pct".to_owned()), Some(0)),
+                    HalAttribute::new_readonly_array(HalAttributeType::UInt32, "values")
+                        .with_units(Some("pct".to_owned()), Some(0)),
+                ],
+                clock: clock.clone(),
+                injections: Default::default(),
+            },
+            HalDeviceMock {
+                kind: MockDeviceKind::TachoMotor(Arc::new(Mutex::new(SimulatedMotor::new(
+                    clock.now(),
+                )))),
+                device_type: HalDeviceType::Actuator,
+                driver_name: "lego-ev3-l-motor".to_owned(),
+                address: "ev3-ports:outA".to_owned(),
+                attributes: tacho_motor_attributes(),
+                clock: clock.clone(),
+                injections: Default::default(),
+            },
+        ];
+        // in1 has the IR sensor plugged in, which is a UART device.
+        let port_statuses = [("ev3-ports:in1".to_owned(), "ev3-uart".to_owned())];
+        Self::with_devices(devices, port_statuses.into_iter().collect(), clock)
+    }
+
+    /// Simulates the robot described by `scenario`, with time as told by `clock`.
+    pub fn from_scenario(scenario: &Scenario, clock: Arc<dyn Clock>) -> anyhow::Result<Self> {
+        let started_at = clock.now();
+        let mut devices = Vec::new();
+        let mut port_statuses = BTreeMap::new();
+        for spec in &scenario.devices {
+            devices.push(scenario_device(spec, &clock, started_at)?);
+            port_statuses.insert(port_address(&spec.address), spec.port_status());
+        }
+        Ok(Self::with_devices(devices, port_statuses, clock))
+    }
+
+    /// Adds the brick's own devices and ports to the `devices` plugged into it.  `port_statuses`
+    /// holds the `auto` mode status of the ports that have something plugged in.
+    fn with_devices(
+        mut devices: Vec<HalDeviceMock>,
+        port_statuses: BTreeMap<String, String>,
+        clock: Arc<dyn Clock>,
+    ) -> Self {
+        devices.extend([
+            HalDeviceMock {
+                kind: MockDeviceKind::Battery {
+                    installed_at: clock.now(),
+                },
+                device_type: HalDeviceType::Sensor,
+                driver_name: "power_supply".to_owned(),
+                address: "lego-ev3-battery".to_owned(),
+                attributes: vec![
+                    HalAttribute::new_readonly(HalAttributeType::Float32, "current_now"),
+                    HalAttribute::new_readonly(HalAttributeType::String, "technology"),
+                    HalAttribute::new_readonly(HalAttributeType::String, "type"),
+                    HalAttribute::new_readonly(HalAttributeType::Float32, "voltage_max_design"),
+                    HalAttribute::new_readonly(HalAttributeType::Float32, "voltage_min_design"),
+                    HalAttribute::new_readonly(HalAttributeType::Float32, "voltage_now"),
+                ],
+                clock: clock.clone(),
+                injections: Default::default(),
+            },
+            HalDeviceMock {
+                kind: MockDeviceKind::Sound(Arc::new(Mutex::new(MockSound::default()))),
+                device_type: HalDeviceType::Actuator,
+                driver_name: "ev3-sound".to_owned(),
+                address: "sound".to_owned(),
+                attributes: sound_attributes(),
+                clock: clock.clone(),
+                injections: Default::default(),
+            },
+            HalDeviceMock {
+                kind: MockDeviceKind::Buttons(Arc::new(Mutex::new(MockButtons::default()))),
+                device_type: HalDeviceType::Sensor,
+                driver_name: BUTTONS_DRIVER_NAME.to_owned(),
+                address: BUTTONS_ADDRESS.to_owned(),
+                attributes: button_attributes(),
+                clock: clock.clone(),
+                injections: Default::default(),
+            },
+        ]);
+        devices.extend(MOCK_LED_NAMES.map(|name| HalDeviceMock {
+            kind: MockDeviceKind::Led(Arc::new(Mutex::new(MockLed::default()))),
+            device_type: HalDeviceType::Actuator,
+            driver_name: LEDS_DRIVER_NAME.to_owned(),
+            address: name.to_owned(),
+            attributes: led_attributes(),
+            clock: clock.clone(),
+            injections: Default::default(),
+        }));
+        let framebuffer_path = std::env::temp_dir().join("ev3-remote-control").join("fb0");
+        let display = match Framebuffer::file_backed(framebuffer_path, FramebufferInfo::EV3) {
+            Ok(framebuffer) => Some(Arc::new(Display::new(framebuffer))),
+            Err(e) => {
+                log::warn!("Mock LCD not available: {e}");
+                None
+            }
+        };
+        let mut ports = Vec::new();
+        let auto_status = |address: &str, unplugged: &str| {
+            port_statuses
+                .get(address)
+                .cloned()
+                .unwrap_or_else(|| unplugged.to_owned())
+        };
+        for input in 1..=4 {
+            let address = format!("ev3-ports:in{input}");
+            let status = auto_status(&address, "no-sensor");
+            ports.push(MockPortState::new(
+                address,
+                "ev3-input-port",
+                &MOCK_INPUT_PORT_MODES,
+                status,
+            ));
+        }
+        for output in ['A', 'B', 'C', 'D'] {
+            let address = format!("ev3-ports:out{output}");
+            let status = auto_status(&address, "no-motor");
+            ports.push(MockPortState::new(
+                address,
+                "ev3-output-port",
+                &MOCK_OUTPUT_PORT_MODES,
+                status,
+            ));
+        }
+        let ports = Arc::new(Mutex::new(MockPorts {
+            ports,
+            watchers: Vec::new(),
+        }));
+        let devices = Arc::new(Mutex::new(MockDevices {
+            devices,
+            watchers: Vec::new(),
+        }));
+        Self {
+            devices,
+            display,
+            ports,
+            clock,
+        }
+    }
+
+    /// Handle for changing the devices while the mock is in use.
+    pub fn control(&self) -> MockControl {
+        MockControl {
+            devices: self.devices.clone(),
+            ports: self.ports.clone(),
+            clock: self.clock.clone(),
+        }
+    }
+
+    fn port_handle(&self, address: &str) -> Box<dyn HalPort> {
+        Box::new(HalPortMock {
+            address: address.to_owned(),
+            ports: self.ports.clone(),
+        })
+    }
+
+    fn all_devices(&self) -> Vec<Box<dyn HalDevice>> {
+        let display = self
+            .display
+            .as_ref()
+            .map(|d| Box::new(HalDeviceDisplay::new(d.clone())) as Box<dyn HalDevice>);
+        let devices = self.devices.lock().unwrap();
+        devices
             .devices
             .iter()
             .map(|d| Box::new(d.clone()) as Box<dyn HalDevice>)
-            .collect())
+            .chain(display)
+            .collect()
+    }
+
+    /// First device of the kind that `f` picks out, for test inspection.
+    #[cfg(test)]
+    fn find_kind<T>(&self, f: impl Fn(&MockDeviceKind) -> Option<T>) -> Option<T> {
+        let devices = self.devices.lock().unwrap();
+        devices.devices.iter().find_map(|d| f(&d.kind))
+    }
+
+    /// Sound requests made so far, in the order they were received.
+    #[cfg(test)]
+    pub fn sound_requests(&self) -> Vec<SoundRequest> {
+        self.find_kind(|kind| match kind {
+            MockDeviceKind::Sound(sound) => Some(sound.lock().unwrap().requests.clone()),
+            _ => None,
+        })
+        .unwrap_or_default()
+    }
+
+    /// Attribute writes made to the tacho motor so far, in the order they were received.
+    #[cfg(test)]
+    pub fn motor_writes(&self) -> Vec<(String, String)> {
+        self.find_kind(|kind| match kind {
+            MockDeviceKind::TachoMotor(motor) => Some(motor.lock().unwrap().writes.clone()),
+            _ => None,
+        })
+        .unwrap_or_default()
+    }
+
+    /// Simulates a brick button being pressed (`is_pressed`) or released, notifying watchers.
+    #[cfg(test)]
+    pub fn set_button(&self, button: &str, is_pressed: bool) {
+        let buttons = self.find_kind(|kind| match kind {
+            MockDeviceKind::Buttons(buttons) => Some(buttons.clone()),
+            _ => None,
+        });
+        let buttons = buttons.unwrap();
+        let mut buttons = buttons.lock().unwrap();
+        let button = crate::hal_buttons::BUTTON_NAMES
+            .into_iter()
+            .find(|&name| name == button)
+            .unwrap_or_else(|| panic!("Unknown button {button}"));
+        let changed = if is_pressed {
+            buttons.pressed.insert(button)
+        } else {
+            buttons.pressed.remove(button)
+        };
+        if changed {
+            buttons.watchers.retain(|tx| tx.send(()).is_ok());
+        }
+    }
+}
+
+impl Hal for HalMock {
+    fn list_devices(&self) -> HalResult<Vec<Box<dyn HalDevice>>> {
+        Ok(self.all_devices())
     }
 
     fn by_driver(&self, driver: &str) -> HalResult<Vec<Box<dyn HalDevice>>> {
         Ok(self
-            .devices
-            .iter()
-            .filter(|d| d.driver_name == driver)
-            .map(|d| Box::new(d.clone()) as Box<dyn HalDevice>)
+            .all_devices()
+            .into_iter()
+            .filter(|d| d.get_driver_name().as_deref().unwrap_or("") == driver)
             .collect())
     }
 
     fn by_address(&self, address: &str) -> HalResult<Option<Box<dyn HalDevice>>> {
         Ok(self
+            .all_devices()
+            .into_iter()
+            .find(|d| d.get_address().as_deref().unwrap_or("") == address))
+    }
+
+    fn watch_devices(&self) -> anyhow::Result<WatchHandle> {
+        let (tx, rx) = std::sync::mpsc::channel();
+        self.devices.lock().unwrap().watchers.push(tx);
+        Ok(WatchHandle::new(Arc::new("devices".to_string()), rx))
+    }
+
+    fn list_ports(&self) -> HalResult<Vec<Box<dyn HalPort>>> {
+        let ports = self.ports.lock().unwrap();
+        Ok(ports
+            .ports
+            .iter()
+            .map(|p| self.port_handle(&p.address))
+            .collect())
+    }
+
+    fn port_by_address(&self, address: &str) -> HalResult<Option<Box<dyn HalPort>>> {
+        let ports = self.ports.lock().unwrap();
+        Ok(ports
+            .ports
+            .iter()
+            .find(|p| p.address == address)
+            .map(|p| self.port_handle(&p.address)))
+    }
+
+    fn watch_ports(&self) -> anyhow::Result<WatchHandle> {
+        let (tx, rx) = std::sync::mpsc::channel();
+        self.ports.lock().unwrap().watchers.push(tx);
+        Ok(WatchHandle::new(Arc::new("ports".to_string()), rx))
+    }
+
+    fn display(&self) -> Option<Arc<Display>> {
+        self.display.clone()
+    }
+}
+
+/// Changes the mock's devices while the server is running, see [`crate::mock_resource`].
+/// Watchers are told about the changes just as they would be with real hardware.
+#[derive(Debug, Clone)]
+pub struct MockControl {
+    devices: Arc<Mutex<MockDevices>>,
+    ports: Arc<Mutex<MockPorts>>,
+    clock: Arc<dyn Clock>,
+}
+
+impl MockControl {
+    /// Plugs in a device, described the same way as in a scenario.
+    pub fn add_device(&self, spec: &DeviceSpec) -> anyhow::Result<()> {
+        spec.validate()?;
+        let device = scenario_device(spec, &self.clock, self.clock.now())?;
+        let mut devices = self.devices.lock().unwrap();
+        if devices.devices.iter().any(|d| d.address == spec.address) {
+            bail!("There already is a device at {}", spec.address);
+        }
+        devices.devices.push(device);
+        devices.watchers.retain(|tx| tx.send(()).is_ok());
+        drop(devices);
+        self.set_port_status(&spec.address, Some(spec.port_status()));
+        Ok(())
+    }
+
+    /// Unplugs the device at `address`, returning whether there was one.  Handles to the device
+    /// that are still around fail with [`HalError::DeviceGone`] from then on.
+    pub fn remove_device(&self, address: &str) -> bool {
+        let mut devices = self.devices.lock().unwrap();
+        let device = match devices.devices.iter().position(|d| d.address == address) {
+            Some(index) => devices.devices.remove(index),
+            None => return false,
+        };
+        devices.watchers.retain(|tx| tx.send(()).is_ok());
+        drop(devices);
+
+        let mut injections = device.injections.lock().unwrap();
+        injections.is_removed = true;
+        injections.watchers.retain(|(_, tx)| tx.send(()).is_ok());
+        drop(injections);
+        self.set_port_status(address, None);
+        true
+    }
+
+    /// Makes reads of attribute `name` yield `value` until the attribute is written, or stop
+    /// doing so with `None`.
+    pub fn inject_value(&self, address: &str, name: &str, value: Option<String>) -> HalResult<()> {
+        self.update_injections(address, name, |injections| match value {
+            Some(value) => injections.values.insert(name.to_owned(), value),
+            None => injections.values.remove(name),
+        })
+    }
+
+    /// Makes the next `count` reads of attribute `name` fail with `fault`.
+    pub fn inject_fault(
+        &self,
+        address: &str,
+        name: &str,
+        fault: FaultKind,
+        count: u32,
+    ) -> HalResult<()> {
+        self.update_injections(address, name, |injections| {
+            if count > 0 {
+                injections.faults.insert(name.to_owned(), (fault, count));
+            } else {
+                injections.faults.remove(name);
+            }
+        })
+    }
+
+    fn update_injections<T>(
+        &self,
+        address: &str,
+        name: &str,
+        update: impl FnOnce(&mut MockInjections) -> T,
+    ) -> HalResult<()> {
+        let devices = self.devices.lock().unwrap();
+        let device = devices
             .devices
             .iter()
             .find(|d| d.address == address)
-            .map(|d| Box::new(d.clone()) as Box<dyn HalDevice>))
+            .ok_or_else(|| HalError::NotConnected {
+                device: address.to_owned(),
+                port: None,
+            })?;
+        if !device.is_readable(name) {
+            return Err(HalError::UnknownAttribute(name.to_owned()));
+        }
+        let mut injections = device.injections.lock().unwrap();
+        update(&mut injections);
+        let name = name.to_owned();
+        injections
+            .watchers
+            .retain(|(names, tx)| !names.contains(&name) || tx.send(()).is_ok());
+        Ok(())
     }
 
-    fn watch_devices(&self) -> anyhow::Result<WatchHandle> {
-        let (tx, rx) = std::sync::mpsc::channel();
-        let cancel_handle = Arc::new("dummy".to_string());
-        let weak_handle = Arc::downgrade(&cancel_handle);
-        thread::spawn(move || loop {
-            thread::sleep(Duration::from_secs(60));
-            if weak_handle.upgrade().is_none() {
-                break;
+    /// Updates the port the device at `address` is plugged into, if it's on one.
+    fn set_port_status(&self, address: &str, auto_status: Option<String>) {
+        let address = port_address(address);
+        let mut ports = self.ports.lock().unwrap();
+        if let Some(port) = ports.ports.iter_mut().find(|p| p.address == address) {
+            port.set_auto_status(auto_status);
+            ports.watchers.retain(|tx| tx.send(()).is_ok());
+        }
+    }
+}
+
+/// Address of the port a device is plugged into.  I2C sensors have addresses like
+/// `ev3-ports:in1:i2c76`, for example.
+fn port_address(device_address: &str) -> String {
+    let port: Vec<_> = device_address.split(':').take(2).collect();
+    port.join(":")
+}
+
+struct HalPortMock {
+    address: String,
+    ports: Arc<Mutex<MockPorts>>,
+}
+
+impl HalPortMock {
+    fn with_state<T>(&self, f: impl FnOnce(&MockPortState) -> T) -> HalResult<T> {
+        let ports = self.ports.lock().unwrap();
+        ports
+            .ports
+            .iter()
+            .find(|p| p.address == self.address)
+            .map(f)
+            .ok_or_else(|| HalError::NotConnected {
+                device: "port".to_owned(),
+                port: Some(self.address.clone()),
+            })
+    }
+
+    /// Applies `f` to the port's state and notifies watchers if anything changed.
+    fn update_state(
+        &mut self,
+        f: impl FnOnce(&mut MockPortState) -> HalResult<()>,
+    ) -> HalResult<()> {
+        let mut ports = self.ports.lock().unwrap();
+        let state = ports
+            .ports
+            .iter_mut()
+            .find(|p| p.address == self.address)
+            .ok_or_else(|| HalError::NotConnected {
+                device: "port".to_owned(),
+                port: Some(self.address.clone()),
+            })?;
+        let (old_mode, old_status) = (state.mode.clone(), state.status.clone());
+        f(state)?;
+        if state.mode != old_mode || state.status != old_status {
+            ports.watchers.retain(|tx| tx.send(()).is_ok());
+        }
+        Ok(())
+    }
+}
+
+impl HalPort for HalPortMock {
+    fn get_address(&self) -> HalResult<String> {
+        Ok(self.address.clone())
+    }
+
+    fn get_driver_name(&self) -> HalResult<String> {
+        self.with_state(|p| p.driver_name.to_owned())
+    }
+
+    fn get_modes(&self) -> HalResult<Vec<String>> {
+        self.with_state(|p| p.modes.iter().map(|m| m.to_string()).collect())
+    }
+
+    fn get_mode(&self) -> HalResult<String> {
+        self.with_state(|p| p.mode.clone())
+    }
+
+    fn get_status(&self) -> HalResult<String> {
+        self.with_state(|p| p.status.clone())
+    }
+
+    fn set_mode(&mut self, mode: &str) -> HalResult<()> {
+        self.update_state(|p| {
+            if !p.modes.contains(&mode) {
+                return Err(HalError::InvalidValue(format!("Invalid mode: {mode}")));
             }
-            if tx.send(()).is_err() {
-                break;
+            p.mode = mode.to_owned();
+            p.status = if mode == "auto" {
+                p.auto_status.to_owned()
+            } else {
+                mode.to_owned()
+            };
+            Ok(())
+        })
+    }
+
+    fn set_device(&mut self, driver_name: &str) -> HalResult<()> {
+        self.update_state(|p| {
+            if p.mode == "auto" {
+                return Err(HalError::InvalidValue(
+                    "Cannot set device while in auto mode".to_owned(),
+                ));
             }
-        });
-        Ok(WatchHandle::new(cancel_handle, rx))
+            p.status = driver_name.to_owned();
+            Ok(())
+        })
     }
 }
 
+#[derive(Debug, Clone)]
+enum MockDeviceKind {
+    InfraredSensor { installed_at: Instant },
+    TachoMotor(Arc<Mutex<SimulatedMotor>>),
+    Battery { installed_at: Instant },
+    Sound(Arc<Mutex<MockSound>>),
+    Buttons(Arc<Mutex<MockButtons>>),
+    Led(Arc<Mutex<MockLed>>),
+    Scripted(Arc<Mutex<MockScripted>>),
+}
+
 #[derive(Debug, Clone)]
 struct HalDeviceMock {
+    kind: MockDeviceKind,
     device_type: HalDeviceType,
     driver_name: String,
     address: String,
     attributes: Vec<HalAttribute>,
+    clock: Arc<dyn Clock>,
+    injections: Arc<Mutex<MockInjections>>,
+}
+
+impl HalDeviceMock {
+    fn is_readable(&self, name: &str) -> bool {
+        self.attributes
+            .iter()
+            .any(|a| a.name == name && a.is_readable)
+    }
+
+    fn is_writable(&self, name: &str) -> bool {
+        self.attributes
+            .iter()
+            .any(|a| a.name == name && a.is_writable)
+    }
+
+    /// What's been injected for reading `name`, if anything.  Injected faults wear off with use.
+    fn injected(&self, name: &str) -> HalResult<Option<String>> {
+        let mut injections = self.injections.lock().unwrap();
+        if injections.is_removed {
+            return Err(HalError::DeviceGone);
+        }
+        if let Some((fault, remaining)) = injections.faults.get_mut(name) {
+            let fault = *fault;
+            *remaining -= 1;
+            if *remaining == 0 {
+                injections.faults.remove(name);
+            }
+            return Err(fault.to_hal_error(&self.address, name));
+        }
+        Ok(injections.values.get(name).cloned())
+    }
+
+    fn since(&self, instant: Instant) -> Duration {
+        self.clock.now().saturating_duration_since(instant)
+    }
 }
 
 impl HalDevice for HalDeviceMock {
@@ -95,52 +772,685 @@ impl HalDevice for HalDeviceMock {
     }
 
     fn get_attribute_str(&self, name: &str) -> HalResult<String> {
-        match name {
-            "mode" => Ok("IR-PROX".to_owned()),
-            "value0" => {
-                // Oscillate between 0 and 100, ticking once per second.
-                let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
-                let interval = now.as_secs() % 200;
-                let value = if interval > 100 {
-                    200 - interval
-                } else {
-                    interval
+        if let Some(value) = self.injected(name)? {
+            return Ok(value);
+        }
+        let value = match &self.kind {
+            MockDeviceKind::InfraredSensor { installed_at } => {
+                get_infrared_attribute(name, self.since(*installed_at))
+            }
+            MockDeviceKind::TachoMotor(motor) => {
+                let mut motor = motor.lock().unwrap();
+                motor.advance(self.clock.now());
+                return match MotorUnitAttribute::parse(name) {
+                    Some(unit) => unit.read(|name| motor.get(name)),
+                    None => motor.get(name),
+                };
+            }
+            MockDeviceKind::Battery { installed_at } => {
+                get_battery_attribute(name, self.since(*installed_at))
+            }
+            MockDeviceKind::Sound(sound) => match name {
+                "clips" => {
+                    let sound = sound.lock().unwrap();
+                    let clips: Vec<_> = sound.clips.keys().map(|c| c.as_str()).collect();
+                    Some(clips.join(" "))
+                }
+                _ => None,
+            },
+            MockDeviceKind::Buttons(buttons) => {
+                let buttons = buttons.lock().unwrap();
+                return get_button_attribute_str(name, &buttons.pressed);
+            }
+            MockDeviceKind::Led(led) => {
+                let led = led.lock().unwrap();
+                match name {
+                    "brightness" => Some(led.brightness.to_string()),
+                    "max_brightness" => Some(MOCK_LED_MAX_BRIGHTNESS.to_string()),
+                    "trigger" => Some(led.trigger.clone()),
+                    "triggers" => Some(MOCK_LED_TRIGGERS.join(" ")),
+                    _ => None,
+                }
+            }
+            MockDeviceKind::Scripted(scripted) if self.is_readable(name) => {
+                scripted.lock().unwrap().get(name, self.clock.now())
+            }
+            MockDeviceKind::Scripted(_) => None,
+        };
+        value.ok_or_else(|| HalError::UnknownAttribute(name.to_owned()))
+    }
+
+    fn set_attribute_str(&mut self, name: &str, value: &str) -> HalResult<()> {
+        {
+            let mut injections = self.injections.lock().unwrap();
+            if injections.is_removed {
+                return Err(HalError::DeviceGone);
+            }
+            // Writes take over from injected values, as they would from the driver's.
+            if self.is_writable(name) {
+                injections.values.remove(name);
+            }
+        }
+        match &self.kind {
+            MockDeviceKind::Sound(sound) => {
+                let request = SoundRequest::parse(name, value)?;
+                let mut sound = sound.lock().unwrap();
+                if let SoundRequest::PlayClip(clip) = &request {
+                    if !sound.clips.contains_key(clip) {
+                        return Err(HalError::InvalidValue(format!("No such clip: {clip}")));
+                    }
+                }
+                sound.requests.push(request);
+                Ok(())
+            }
+            MockDeviceKind::TachoMotor(motor) if self.is_writable(name) => {
+                let mut motor = motor.lock().unwrap();
+                motor.advance(self.clock.now());
+                let (name, value) = match MotorUnitAttribute::parse(name) {
+                    Some(unit) => (
+                        unit.native_attribute(),
+                        unit.native_value(value, |name| motor.get(name))?,
+                    ),
+                    None => (name, value.to_owned()),
                 };
-                Ok(value.to_string())
+                motor.set(name, &value)
             }
-            _ => Err(HalError::InternalError(format!(
-                "Invalid attribute: name={}",
-                name
-            ))),
+            MockDeviceKind::Led(led) => {
+                let mut led = led.lock().unwrap();
+                match name {
+                    "brightness" => match value.trim().parse() {
+                        Ok(brightness) if brightness <= MOCK_LED_MAX_BRIGHTNESS => {
+                            led.brightness = brightness;
+                            Ok(())
+                        }
+                        _ => Err(HalError::InvalidValue(format!(
+                            "Invalid brightness: {value}"
+                        ))),
+                    },
+                    "trigger" if MOCK_LED_TRIGGERS.contains(&value) => {
+                        led.trigger = value.to_owned();
+                        Ok(())
+                    }
+                    "trigger" => Err(HalError::InvalidValue(format!("No such trigger: {value}"))),
+                    _ => Err(HalError::PermissionDenied(name.to_owned())),
+                }
+            }
+            MockDeviceKind::Scripted(scripted) if self.is_writable(name) => {
+                let mut scripted = scripted.lock().unwrap();
+                let value = MockValue::Fixed(value.to_owned());
+                scripted.values.insert(name.to_owned(), value);
+                Ok(())
+            }
+            _ => Err(HalError::PermissionDenied(name.to_owned())),
         }
     }
 
-    fn set_attribute_str(&mut self, name: &str, _value: &str) -> HalResult<()> {
-        match name {
-            _ => Err(HalError::InternalError(format!(
-                "Attribute not writable: name={}",
-                name
-            ))),
+    fn put_blob(&mut self, name: &str, data: &[u8]) -> HalResult<()> {
+        match &self.kind {
+            MockDeviceKind::Sound(sound) => {
+                validate_clip_name(name)?;
+                if data.len() > MAX_CLIP_BYTES {
+                    return Err(HalError::InvalidValue(format!(
+                        "Clip too large: {} bytes",
+                        data.len()
+                    )));
+                }
+                let mut sound = sound.lock().unwrap();
+                sound.clips.insert(name.to_owned(), data.to_vec());
+                Ok(())
+            }
+            _ => Err(HalError::NotApplicable),
         }
     }
 
     fn watch_attributes(&self, names: &[String]) -> anyhow::Result<WatchHandle> {
-        if names.contains(&("value0".to_string())) {
-            let (tx, rx) = std::sync::mpsc::channel();
-            let cancel_handle = Arc::new("dummy".to_string());
-            let weak_handle = Arc::downgrade(&cancel_handle);
-            thread::spawn(move || loop {
-                thread::sleep(Duration::from_secs(1));
-                if weak_handle.upgrade().is_none() {
-                    break;
+        if !names.iter().any(|name| self.is_readable(name)) {
+            return Err(anyhow!("No readable attribute in {names:?}"));
+        }
+
+        // Anything readable can change through injection, on top of what changes by itself.
+        let (tx, rx) = std::sync::mpsc::channel();
+        let cancel_handle = Arc::new("mock".to_string());
+        let weak_handle = Arc::downgrade(&cancel_handle);
+        let watcher = (names.to_vec(), tx.clone());
+        self.injections.lock().unwrap().watchers.push(watcher);
+
+        match &self.kind {
+            MockDeviceKind::Buttons(buttons) => buttons.lock().unwrap().watchers.push(tx),
+            MockDeviceKind::Scripted(scripted) => {
+                let scripted = scripted.lock().unwrap();
+                let is_generated = |name: &String| {
+                    matches!(scripted.values.get(name), Some(MockValue::Generated(..)))
+                };
+                if names.iter().any(is_generated) {
+                    let clock = &*self.clock;
+                    watch_periodically(clock, SCRIPTED_WATCH_INTERVAL, tx, weak_handle);
+                }
+            }
+            MockDeviceKind::TachoMotor(motor) => {
+                let is_simulated = |name: &&String| {
+                    let native_name = match MotorUnitAttribute::parse(name) {
+                        Some(unit) => unit.native_attribute(),
+                        None => name.as_str(),
+                    };
+                    SIMULATED_ATTRIBUTES.contains(&native_name)
+                };
+                let simulated: Vec<_> = names.iter().filter(is_simulated).cloned().collect();
+                if !simulated.is_empty() {
+                    watch_motor(&*self.clock, motor.clone(), simulated, tx, weak_handle);
                 }
-                if tx.send(()).is_err() {
-                    break;
+            }
+            MockDeviceKind::InfraredSensor { .. } | MockDeviceKind::Battery { .. } => {
+                let watchable = ["value0", "values", "current_now", "voltage_now"];
+                if names.iter().any(|name| watchable.contains(&name.as_str())) {
+                    let clock = &*self.clock;
+                    watch_periodically(clock, Duration::from_secs(1), tx, weak_handle);
                 }
-            });
-            Ok(WatchHandle::new(cancel_handle, rx))
-        } else {
-            Err(anyhow!("No watchable attribute in {names:?}"))
+            }
+            // Only change when written.
+            MockDeviceKind::Sound(_) | MockDeviceKind::Led(_) => {}
+        }
+        Ok(WatchHandle::new(cancel_handle, rx))
+    }
+}
+
+fn tacho_motor_attributes() -> Vec<HalAttribute> {
+    let mut attributes = vec![
+        HalAttribute::new_writeonly(HalAttributeType::String, "command")
+            .with_allowed_values_from("commands"),
+        HalAttribute::new_readonly_array(HalAttributeType::String, "commands"),
+        HalAttribute::new_readonly(HalAttributeType::Int32, "count_per_rot"),
+        HalAttribute::new_readonly(HalAttributeType::Int8, "duty_cycle"),
+        HalAttribute::new_rw(HalAttributeType::Int8, "duty_cycle_sp"),
+        HalAttribute::new_readonly(HalAttributeType::Int32, "max_speed"),
+        HalAttribute::new_rw(HalAttributeType::String, "polarity"),
+        HalAttribute::new_readonly(HalAttributeType::Int32, "position"),
+        HalAttribute::new_rw(HalAttributeType::Int32, "position_sp"),
+        HalAttribute::new_rw(HalAttributeType::Int32, "ramp_down_sp"),
+        HalAttribute::new_rw(HalAttributeType::Int32, "ramp_up_sp"),
+        HalAttribute::new_readonly(HalAttributeType::Int32, "speed"),
+        HalAttribute::new_rw(HalAttributeType::Int32, "speed_sp"),
+        HalAttribute::new_readonly(HalAttributeType::String, "state"),
+        HalAttribute::new_rw(HalAttributeType::String, "stop_action")
+            .with_allowed_values_from("stop_actions"),
+        HalAttribute::new_readonly_array(HalAttributeType::String, "stop_actions"),
+        HalAttribute::new_rw(HalAttributeType::Int32, "time_sp"),
+    ];
+    apply_bounds(&mut attributes);
+    add_unit_attributes(&mut attributes);
+    attributes.sort_by(|a, b| a.name.cmp(&b.name));
+    attributes
+}
+
+/// Builds the mock for a device from a scenario.  Tacho motors are simulated like the hardcoded
+/// one, with the given attribute values instead of the defaults.
+fn scenario_device(
+    spec: &DeviceSpec,
+    clock: &Arc<dyn Clock>,
+    started_at: Instant,
+) -> anyhow::Result<HalDeviceMock> {
+    let (kind, attributes) = match spec.class {
+        DeviceClass::TachoMotor => {
+            let mut motor = SimulatedMotor::new(started_at);
+            for attribute in &spec.attributes {
+                let initial = attribute.value.as_ref().map(|v| v.to_string());
+                match initial {
+                    Some(initial) if motor.set_initial(&attribute.name, &initial) => {}
+                    _ => bail!(
+                        "{}: Unknown tacho motor attribute {}",
+                        spec.address,
+                        attribute.name
+                    ),
+                }
+            }
+            let motor = Arc::new(Mutex::new(motor));
+            (MockDeviceKind::TachoMotor(motor), tacho_motor_attributes())
+        }
+        _ => {
+            let mut values = BTreeMap::new();
+            for attribute in &spec.attributes {
+                let value = match (&attribute.value, &attribute.generator) {
+                    (Some(value), _) => MockValue::Fixed(value.to_string()),
+                    (None, Some(generator)) => MockValue::Generated(
+                        GeneratorState::new(generator.clone()),
+                        attribute.data_type,
+                    ),
+                    // Write-only.
+                    (None, None) => continue,
+                };
+                values.insert(attribute.name.clone(), value);
+            }
+            let scripted = Arc::new(Mutex::new(MockScripted { started_at, values }));
+            let attributes = spec.attributes.iter().map(|a| a.to_hal()).collect();
+            (MockDeviceKind::Scripted(scripted), attributes)
+        }
+    };
+    Ok(HalDeviceMock {
+        kind,
+        device_type: spec.device_type(),
+        driver_name: spec.driver_name.clone(),
+        address: spec.address.clone(),
+        attributes,
+        clock: clock.clone(),
+        injections: Default::default(),
+    })
+}
+
+fn get_infrared_attribute(name: &str, since_installed: Duration) -> Option<String> {
+    match name {
+        "mode" => Some("IR-PROX".to_owned()),
+        // IR-PROX only has the one value.
+        "value0" | "values" => {
+            // Oscillate between 0 and 100, ticking once per second.
+            let interval = since_installed.as_secs() % 200;
+            let value = if interval > 100 {
+                200 - interval
+            } else {
+                interval
+            };
+            Some(value.to_string())
+        }
+        _ => None,
+    }
+}
+
+/// Simulates a battery that drains linearly over [`MOCK_BATTERY_LIFETIME`], drawing slightly
+/// more current as the voltage sags.
+fn get_battery_attribute(name: &str, since_installed: Duration) -> Option<String> {
+    let lifetime = MOCK_BATTERY_LIFETIME.as_secs_f64();
+    let discharged = (since_installed.as_secs_f64() % lifetime) / lifetime;
+    match name {
+        "current_now" => Some(format!("{:.3}", 0.15 + 0.05 * discharged)),
+        "technology" => Some("Unknown".to_owned()),
+        "type" => Some("Battery".to_owned()),
+        "voltage_max_design" => Some("9".to_owned()),
+        "voltage_min_design" => Some("4.8".to_owned()),
+        "voltage_now" => {
+            let volts = MOCK_BATTERY_FULL_VOLTS
+                - (MOCK_BATTERY_FULL_VOLTS - MOCK_BATTERY_EMPTY_VOLTS) * discharged;
+            Some(format!("{:.3}", volts))
+        }
+        _ => None,
+    }
+}
+
+/// Emits a change on `tx` whenever one of `names` changes as the motor moves, until
+/// `weak_handle` is dropped.
+fn watch_motor(
+    clock: &dyn Clock,
+    motor: Arc<Mutex<SimulatedMotor>>,
+    names: Vec<String>,
+    tx: Sender<()>,
+    weak_handle: Weak<String>,
+) {
+    let read_all = move |now| {
+        let mut motor = motor.lock().unwrap();
+        motor.advance(now);
+        let read = |name: &String| match MotorUnitAttribute::parse(name) {
+            Some(unit) => unit.read(|name| motor.get(name)),
+            None => motor.get(name),
+        };
+        names.iter().map(read).map(Result::ok).collect::<Vec<_>>()
+    };
+    let now = clock.now();
+    let mut previous = read_all(now);
+    let tick = move |now| {
+        weak_handle.upgrade()?;
+        let current = read_all(now);
+        if current != previous && tx.send(()).is_err() {
+            return None;
+        }
+        previous = current;
+        Some(now + MOTOR_WATCH_INTERVAL)
+    };
+    clock.run_at(now + MOTOR_WATCH_INTERVAL, Box::new(tick));
+}
+
+/// Emits a change on `tx` every `interval` until `weak_handle` is dropped.
+fn watch_periodically(
+    clock: &dyn Clock,
+    interval: Duration,
+    tx: Sender<()>,
+    weak_handle: Weak<String>,
+) {
+    let tick = move |now| {
+        weak_handle.upgrade()?;
+        tx.send(()).ok()?;
+        Some(now + interval)
+    };
+    clock.run_at(clock.now() + interval, Box::new(tick));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::VirtualClock;
+
+    fn voltage_at(since_installed: Duration) -> f64 {
+        get_battery_attribute("voltage_now", since_installed)
+            .unwrap()
+            .parse()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_sound_requests_are_recorded() {
+        let hal = HalMock::with_hardcoded_devices();
+        let mut sound = hal.by_address("sound").unwrap().unwrap();
+
+        sound.set_attribute_str("tone", "440 100").unwrap();
+        assert!(sound.set_attribute_str("play", "beep.wav").is_err());
+        sound.put_blob("beep.wav", b"RIFF").unwrap();
+        sound.set_attribute_str("play", "beep.wav").unwrap();
+        sound.set_attribute_str("speak", "Hello").unwrap();
+
+        assert_eq!(sound.get_attribute_str("clips").unwrap(), "beep.wav");
+        assert_eq!(
+            hal.sound_requests(),
+            vec![
+                SoundRequest::parse("tone", "440 100").unwrap(),
+                SoundRequest::PlayClip("beep.wav".to_owned()),
+                SoundRequest::Speak("Hello".to_owned()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_leds() {
+        let hal = HalMock::with_hardcoded_devices();
+        assert_eq!(hal.by_driver(LEDS_DRIVER_NAME).unwrap().len(), 4);
+        let mut led = hal.by_address("led0:green:brick-status").unwrap().unwrap();
+
+        led.set_attribute_str("brightness", "255").unwrap();
+        assert_eq!(led.get_attribute_str("brightness").unwrap(), "255");
+        assert!(led.set_attribute_str("brightness", "256").is_err());
+        led.set_attribute_str("trigger", "heartbeat").unwrap();
+        assert_eq!(led.get_attribute_str("trigger").unwrap(), "heartbeat");
+        assert!(led.set_attribute_str("trigger", "disco").is_err());
+
+        // LEDs are independent of each other.
+        let other = hal.by_address("led1:green:brick-status").unwrap().unwrap();
+        assert_eq!(other.get_attribute_str("brightness").unwrap(), "0");
+    }
+
+    #[test]
+    fn test_button_presses_are_observable() {
+        let hal = HalMock::with_hardcoded_devices();
+        let buttons = hal.by_address(BUTTONS_ADDRESS).unwrap().unwrap();
+        let handle = buttons.watch_attributes(&["pressed".to_owned()]).unwrap();
+
+        hal.set_button("enter", true);
+        handle.receiver.try_recv().unwrap();
+        assert_eq!(buttons.get_attribute_str("enter").unwrap(), "1");
+        assert_eq!(buttons.get_attribute_str("pressed").unwrap(), "enter");
+
+        hal.set_button("enter", false);
+        handle.receiver.try_recv().unwrap();
+        assert_eq!(buttons.get_attribute_str("pressed").unwrap(), "");
+    }
+
+    #[test]
+    fn test_port_mode_changes_are_observable() {
+        let hal = HalMock::with_hardcoded_devices();
+        let handle = hal.watch_ports().unwrap();
+        let mut port = hal.port_by_address("ev3-ports:in2").unwrap().unwrap();
+
+        assert!(port.set_device("ms-ev3-smux").is_err());
+        assert!(port.set_mode("bogus").is_err());
+        port.set_mode("other-i2c").unwrap();
+        handle.receiver.try_recv().unwrap();
+        port.set_device("ms-ev3-smux").unwrap();
+        handle.receiver.try_recv().unwrap();
+
+        let port = hal.port_by_address("ev3-ports:in2").unwrap().unwrap();
+        assert_eq!(port.get_mode().unwrap(), "other-i2c");
+        assert_eq!(port.get_status().unwrap(), "ms-ev3-smux");
+    }
+
+    #[test]
+    fn test_motor_unit_attributes() {
+        let hal = HalMock::with_hardcoded_devices();
+        let mut motor = hal.by_address("ev3-ports:outA").unwrap().unwrap();
+
+        motor.set_attribute_str("speed_sp_rpm", "87.5").unwrap();
+        motor.set_attribute_str("position_sp_deg", "-90").unwrap();
+        assert_eq!(motor.get_attribute_str("speed_sp").unwrap(), "525");
+        assert_eq!(motor.get_attribute_str("position_sp").unwrap(), "-90");
+        assert_eq!(motor.get_attribute_str("speed_sp_rpm").unwrap(), "87.5");
+        assert_eq!(motor.get_attribute_str("position_deg").unwrap(), "0");
+        assert!(motor.set_attribute_str("speed_rpm", "10").is_err());
+    }
+
+    #[test]
+    fn test_motor_moves_and_is_observable() {
+        let clock = VirtualClock::new();
+        let hal = HalMock::with_hardcoded_devices_and_clock(clock.clone());
+        let mut motor = hal.by_address("ev3-ports:outA").unwrap().unwrap();
+        let handle = motor
+            .watch_attributes(&["position_deg".to_owned()])
+            .unwrap();
+        assert!(motor.watch_attributes(&["command".to_owned()]).is_err());
+
+        motor.set_attribute_str("speed_sp", "360").unwrap();
+        motor.set_attribute_str("position_sp", "720").unwrap();
+        motor
+            .set_attribute_str("command", "run-to-abs-pos")
+            .unwrap();
+        assert_eq!(motor.get_attribute_str("position").unwrap(), "0");
+        clock.advance(Duration::from_secs(1));
+        handle.receiver.try_recv().unwrap();
+        let position: i32 = motor
+            .get_attribute_str("position")
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert!((300..360).contains(&position), "{position}");
+
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(motor.get_attribute_str("position_deg").unwrap(), "720");
+        assert_eq!(motor.get_attribute_str("state").unwrap(), "");
+        assert!(motor.set_attribute_str("command", "jump").is_err());
+    }
+
+    #[test]
+    fn test_dropped_watch_stops() {
+        let clock = VirtualClock::new();
+        let hal = HalMock::with_hardcoded_devices_and_clock(clock.clone());
+        let motor = hal.by_address("ev3-ports:outA").unwrap().unwrap();
+        let handle = motor.watch_attributes(&["position".to_owned()]).unwrap();
+        assert_eq!(clock.pending_timers(), 1);
+
+        drop(handle);
+        clock.advance(MOTOR_WATCH_INTERVAL);
+        assert_eq!(clock.pending_timers(), 0);
+    }
+
+    #[test]
+    fn test_virtual_time() {
+        let scenario = Scenario::from_toml(
+            r#"
+            [[devices]]
+            class = "lego-sensor"
+            driver_name = "lego-ev3-us"
+            address = "ev3-ports:in4"
+            attributes = [
+                { name = "value0", type = "int32", generator = { kind = "ramp", from = 0, to = 100, duration_ms = 10000 } },
+            ]
+            "#,
+        )
+        .unwrap();
+        let clock = VirtualClock::new();
+        let hal = HalMock::from_scenario(&scenario, clock.clone()).unwrap();
+        let sensor = hal.by_address("ev3-ports:in4").unwrap().unwrap();
+        let handle = sensor.watch_attributes(&["value0".to_owned()]).unwrap();
+        let battery = hal.by_address("lego-ev3-battery").unwrap().unwrap();
+        let full_voltage = battery.get_attribute_str("voltage_now").unwrap();
+
+        clock.advance(Duration::from_secs(5));
+        assert_eq!(sensor.get_attribute_str("value0").unwrap(), "50");
+        handle.receiver.try_recv().unwrap();
+        clock.advance(MOCK_BATTERY_LIFETIME / 2);
+        assert_eq!(sensor.get_attribute_str("value0").unwrap(), "100");
+        assert_ne!(
+            battery.get_attribute_str("voltage_now").unwrap(),
+            full_voltage
+        );
+    }
+
+    #[test]
+    fn test_shipped_scenarios() {
+        let driving_base = include_str!("../scenarios/driving-base.toml");
+        let hal = HalMock::from_scenario(
+            &Scenario::from_toml(driving_base).unwrap(),
+            SystemClock::shared(),
+        )
+        .unwrap();
+        assert_eq!(hal.by_driver("lego-ev3-l-motor").unwrap().len(), 2);
+        let medium = hal.by_address("ev3-ports:outA").unwrap().unwrap();
+        assert_eq!(medium.get_attribute_str("max_speed").unwrap(), "1560");
+        let touch = hal.by_address("ev3-ports:in1").unwrap().unwrap();
+        assert_eq!(touch.get_attribute_str("value0").unwrap(), "0");
+        let port = hal.port_by_address("ev3-ports:in1").unwrap().unwrap();
+        assert_eq!(port.get_status().unwrap(), "ev3-analog");
+
+        let weather_station = include_str!("../scenarios/weather-station.toml");
+        let hal = HalMock::from_scenario(
+            &Scenario::from_toml(weather_station).unwrap(),
+            SystemClock::shared(),
+        )
+        .unwrap();
+        // Plus the battery, sound and buttons (and the display, if available).
+        assert!(hal.list_devices().unwrap().len() >= 5);
+        let port = hal.port_by_address("ev3-ports:in1").unwrap().unwrap();
+        assert_eq!(port.get_status().unwrap(), "nxt-i2c");
+        let port = hal.port_by_address("ev3-ports:outA").unwrap().unwrap();
+        assert_eq!(port.get_status().unwrap(), "no-motor");
+    }
+
+    #[test]
+    fn test_scenario_device_attributes() {
+        let scenario = Scenario::from_toml(
+            r#"
+            [[devices]]
+            class = "lego-sensor"
+            driver_name = "lego-ev3-gyro"
+            address = "ev3-ports:in2"
+            attributes = [
+                { name = "mode", writable = true, value = "GYRO-ANG" },
+                { name = "value0", type = "int32", generator = { kind = "constant", value = -4 } },
+                { name = "command", readable = false, writable = true },
+            ]
+            "#,
+        )
+        .unwrap();
+        let hal = HalMock::from_scenario(&scenario, SystemClock::shared()).unwrap();
+        let mut gyro = hal.by_address("ev3-ports:in2").unwrap().unwrap();
+
+        assert_eq!(gyro.get_attribute_str("value0").unwrap(), "-4");
+        gyro.set_attribute_str("mode", "GYRO-RATE").unwrap();
+        assert_eq!(gyro.get_attribute_str("mode").unwrap(), "GYRO-RATE");
+        gyro.set_attribute_str("command", "RESET").unwrap();
+        assert!(gyro.get_attribute_str("command").is_err());
+        assert!(gyro.set_attribute_str("value0", "1").is_err());
+        assert!(gyro.watch_attributes(&["value0".to_owned()]).is_ok());
+        assert!(gyro.watch_attributes(&["command".to_owned()]).is_err());
+
+        let bogus_motor = Scenario::from_toml(
+            r#"
+            [[devices]]
+            class = "tacho-motor"
+            driver_name = "lego-ev3-l-motor"
+            address = "ev3-ports:outA"
+            attributes = [{ name = "bogus", value = 1 }]
+            "#,
+        )
+        .unwrap();
+        assert!(HalMock::from_scenario(&bogus_motor, SystemClock::shared()).is_err());
+    }
+
+    #[test]
+    fn test_control_injections() {
+        let hal = HalMock::with_hardcoded_devices();
+        let control = hal.control();
+        let mut motor = hal.by_address("ev3-ports:outA").unwrap().unwrap();
+        let handle = motor.watch_attributes(&["speed_sp".to_owned()]).unwrap();
+
+        control
+            .inject_value("ev3-ports:outA", "speed_sp", Some("100".to_owned()))
+            .unwrap();
+        handle.receiver.try_recv().unwrap();
+        assert_eq!(motor.get_attribute_str("speed_sp").unwrap(), "100");
+        motor.set_attribute_str("speed_sp", "200").unwrap();
+        assert_eq!(motor.get_attribute_str("speed_sp").unwrap(), "200");
+
+        control
+            .inject_fault("ev3-ports:outA", "state", FaultKind::NotConnected, 2)
+            .unwrap();
+        for _ in 0..2 {
+            assert!(matches!(
+                motor.get_attribute_str("state"),
+                Err(HalError::NotConnected { .. })
+            ));
         }
+        assert_eq!(motor.get_attribute_str("state").unwrap(), "");
+
+        assert!(matches!(
+            control.inject_value("ev3-ports:outA", "command", None),
+            Err(HalError::UnknownAttribute(_))
+        ));
+        assert!(matches!(
+            control.inject_value("ev3-ports:outD", "state", None),
+            Err(HalError::NotConnected { .. })
+        ));
+    }
+
+    #[test]
+    fn test_control_plugging() {
+        let hal = HalMock::with_hardcoded_devices();
+        let control = hal.control();
+        let devices = hal.watch_devices().unwrap();
+        let ports = hal.watch_ports().unwrap();
+        let touch = Scenario::from_json(
+            r#"{"devices": [{
+                "class": "lego-sensor",
+                "driver_name": "lego-ev3-touch",
+                "address": "ev3-ports:in3",
+                "port_status": "ev3-analog",
+                "attributes": [{"name": "value0", "type": "uint8", "value": 0}]
+            }]}"#,
+        )
+        .unwrap();
+
+        control.add_device(&touch.devices[0]).unwrap();
+        assert!(control.add_device(&touch.devices[0]).is_err());
+        devices.receiver.try_recv().unwrap();
+        ports.receiver.try_recv().unwrap();
+        let port = hal.port_by_address("ev3-ports:in3").unwrap().unwrap();
+        assert_eq!(port.get_status().unwrap(), "ev3-analog");
+        let sensor = hal.by_address("ev3-ports:in3").unwrap().unwrap();
+        assert_eq!(sensor.get_attribute_str("value0").unwrap(), "0");
+
+        assert!(control.remove_device("ev3-ports:in3"));
+        assert!(!control.remove_device("ev3-ports:in3"));
+        devices.receiver.try_recv().unwrap();
+        assert!(hal.by_address("ev3-ports:in3").unwrap().is_none());
+        assert_eq!(port.get_status().unwrap(), "no-sensor");
+        assert!(matches!(
+            sensor.get_attribute_str("value0"),
+            Err(HalError::DeviceGone)
+        ));
+    }
+
+    #[test]
+    fn test_battery_drains_then_is_replaced() {
+        let full = voltage_at(Duration::ZERO);
+        let half = voltage_at(MOCK_BATTERY_LIFETIME / 2);
+        let nearly_empty = voltage_at(MOCK_BATTERY_LIFETIME - Duration::from_secs(1));
+        let replaced = voltage_at(MOCK_BATTERY_LIFETIME);
+
+        assert_eq!(full, MOCK_BATTERY_FULL_VOLTS);
+        assert!(full > half && half > nearly_empty);
+        assert!(nearly_empty >= MOCK_BATTERY_EMPTY_VOLTS);
+        assert_eq!(replaced, MOCK_BATTERY_FULL_VOLTS);
     }
 }
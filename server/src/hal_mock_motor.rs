@@ -0,0 +1,509 @@
+//! Simulated tacho motor for the mock HAL, so that motor control flows can be exercised without
+//! hardware.  It follows the ev3dev tacho motor interface closely enough for the app to not tell
+//! the difference, while keeping the physics simple:
+//!
+//! * Commands pick a target speed, which `ramp_up_sp`/`ramp_down_sp` limit how quickly it can
+//!   change (as the time to go between 0 and `max_speed`).
+//! * The actual speed follows the ramped target with a first-order lag of [`RUNNING_LAG`], or of
+//!   [`COAST_LAG`]/[`BRAKE_LAG`] when stopping.  `position` integrates the speed.
+//! * `run-to-*-pos` and `hold` steer towards the target position proportionally, capped at
+//!   `speed_sp` (or `max_speed` when holding).
+//!
+//! Setpoints are taken when a command is written, so changing e.g. `speed_sp` while running has
+//! no effect until the next command, just like on the brick.  The simulation advances lazily
+//! whenever the motor is accessed.
+
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+use crate::hal::{HalError, HalResult};
+
+/// Time constant of the speed following the target while running.
+const RUNNING_LAG: Duration = Duration::from_millis(80);
+
+/// Time constant of the speed decaying with `stop_action` `coast`.
+const COAST_LAG: Duration = Duration::from_millis(400);
+
+/// Time constant of the speed decaying with `stop_action` `brake`.
+const BRAKE_LAG: Duration = Duration::from_millis(30);
+
+/// Speed (counts/s) per count of position error when steering towards a position.
+const POSITION_GAIN: f64 = 8.0;
+
+/// Integration step, well below the time constants above.
+const STEP: Duration = Duration::from_millis(2);
+
+/// Speeds (counts/s) and positions (counts) closer than this are considered equal.
+const EPSILON: f64 = 0.5;
+
+const DEFAULT_VALUES: [(&str, &str); 12] = [
+    (
+        "commands",
+        "run-forever run-to-abs-pos run-to-rel-pos run-timed run-direct stop reset",
+    ),
+    ("count_per_rot", "360"),
+    ("duty_cycle_sp", "0"),
+    ("max_speed", "1050"),
+    ("polarity", "normal"),
+    ("position_sp", "0"),
+    ("ramp_down_sp", "0"),
+    ("ramp_up_sp", "0"),
+    ("speed_sp", "0"),
+    ("stop_action", "coast"),
+    ("stop_actions", "coast brake hold"),
+    ("time_sp", "0"),
+];
+
+const READ_ONLY_VALUES: [&str; 4] = ["commands", "count_per_rot", "max_speed", "stop_actions"];
+
+/// Attributes that change as the motor moves, rather than by being written.
+pub const SIMULATED_ATTRIBUTES: [&str; 4] = ["duty_cycle", "position", "speed", "state"];
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum RunState {
+    Stopped,
+    Forever { speed: f64 },
+    Timed { speed: f64, until: Duration },
+    ToPosition { target: f64, speed: f64 },
+    Direct,
+    Holding { target: f64 },
+    Coasting,
+    Braking,
+}
+
+#[derive(Debug)]
+pub struct SimulatedMotor {
+    /// Configuration and setpoints, as last written.
+    values: BTreeMap<String, String>,
+
+    /// Attribute writes made so far, in the order they were received.
+    pub writes: Vec<(String, String)>,
+
+    run_state: RunState,
+    position: f64,
+    speed: f64,
+
+    /// Target speed after applying the ramps.
+    ramped_speed: f64,
+
+    /// Simulated time, which has been advanced up to `last_update`.
+    time: Duration,
+    last_update: Instant,
+}
+
+impl SimulatedMotor {
+    pub fn new(now: Instant) -> Self {
+        Self {
+            values: default_values(),
+            writes: Vec::new(),
+            run_state: RunState::Stopped,
+            position: 0.0,
+            speed: 0.0,
+            ramped_speed: 0.0,
+            time: Duration::ZERO,
+            last_update: now,
+        }
+    }
+
+    /// Overrides the default value of a configuration attribute, e.g. `max_speed` for motors
+    /// other than the EV3 large motor.  Returns false if there's no such attribute.
+    pub fn set_initial(&mut self, name: &str, value: &str) -> bool {
+        match self.values.get_mut(name) {
+            Some(current) => {
+                *current = value.to_owned();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Value of a native attribute as of the last [`Self::advance`].
+    pub fn get(&self, name: &str) -> HalResult<String> {
+        let value = match name {
+            "position" => format_counts(self.position),
+            "speed" => format_counts(self.speed),
+            "duty_cycle" => format_counts(self.speed / self.max_speed() * 100.0),
+            "state" => self.state(),
+            "command" => return Err(HalError::PermissionDenied(name.to_owned())),
+            name => self.value(name)?.to_owned(),
+        };
+        Ok(value)
+    }
+
+    /// Writes a native attribute, which should have been advanced to the present first.
+    pub fn set(&mut self, name: &str, value: &str) -> HalResult<()> {
+        if name == "command" {
+            self.run_command(value)?;
+        } else if name == "stop_action" && !self.get_list("stop_actions")?.contains(&value) {
+            return Err(HalError::InvalidValue(format!(
+                "Invalid stop action: {value}"
+            )));
+        } else {
+            self.values.insert(name.to_owned(), value.to_owned());
+        }
+        self.writes.push((name.to_owned(), value.to_owned()));
+        Ok(())
+    }
+
+    /// Simulates the motor up to `now`.
+    pub fn advance(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last_update);
+        self.last_update = now;
+        let end = self.time + elapsed;
+        while self.time < end {
+            if self.is_settled() {
+                // Nothing changes from here on but the position, at a constant speed.
+                self.speed = self.ramped_speed;
+                self.position += self.speed * (end - self.time).as_secs_f64();
+                self.time = end;
+                break;
+            }
+            let dt = STEP.min(end - self.time);
+            self.step(dt.as_secs_f64());
+            self.time += dt;
+        }
+    }
+
+    fn run_command(&mut self, command: &str) -> HalResult<()> {
+        let speed_sp = self.get_number("speed_sp")?;
+        let max_speed = self.max_speed();
+        let speed = speed_sp.clamp(-max_speed, max_speed) * self.polarity();
+        self.run_state = match command {
+            "run-forever" => RunState::Forever { speed },
+            "run-timed" => RunState::Timed {
+                speed,
+                until: self.time + Duration::from_millis(self.get_number("time_sp")? as u64),
+            },
+            "run-to-abs-pos" => RunState::ToPosition {
+                target: self.get_number("position_sp")? * self.polarity(),
+                speed: speed.abs(),
+            },
+            "run-to-rel-pos" => RunState::ToPosition {
+                target: self.position + self.get_number("position_sp")? * self.polarity(),
+                speed: speed.abs(),
+            },
+            "run-direct" => RunState::Direct,
+            "stop" => self.stopping_state(),
+            "reset" => {
+                let mut reset = Self {
+                    writes: std::mem::take(&mut self.writes),
+                    time: self.time,
+                    ..Self::new(self.last_update)
+                };
+                // These come from the driver rather than being settings.
+                for name in READ_ONLY_VALUES {
+                    if let Some(value) = self.values.get(name) {
+                        reset.values.insert(name.to_owned(), value.clone());
+                    }
+                }
+                *self = reset;
+                return Ok(());
+            }
+            _ => {
+                return Err(HalError::InvalidValue(format!(
+                    "Invalid command: {command}"
+                )))
+            }
+        };
+        Ok(())
+    }
+
+    /// What happens after a `stop` command or a run command completing.
+    fn stopping_state(&self) -> RunState {
+        match self.value("stop_action").unwrap_or_default() {
+            "hold" => RunState::Holding {
+                target: self.position.round(),
+            },
+            "brake" => RunState::Braking,
+            _ => RunState::Coasting,
+        }
+    }
+
+    /// Speed the command is aiming for, before ramping.
+    fn target_speed(&self) -> f64 {
+        let max_speed = self.max_speed();
+        match self.run_state {
+            RunState::Forever { speed } | RunState::Timed { speed, .. } => speed,
+            RunState::ToPosition { target, speed } => {
+                ((target - self.position) * POSITION_GAIN).clamp(-speed, speed)
+            }
+            RunState::Holding { target } => {
+                ((target - self.position) * POSITION_GAIN).clamp(-max_speed, max_speed)
+            }
+            RunState::Direct => {
+                let duty_cycle = self.get_number("duty_cycle_sp").unwrap_or(0.0);
+                duty_cycle.clamp(-100.0, 100.0) / 100.0 * max_speed * self.polarity()
+            }
+            RunState::Stopped | RunState::Coasting | RunState::Braking => 0.0,
+        }
+    }
+
+    fn is_ramped(&self) -> bool {
+        matches!(
+            self.run_state,
+            RunState::Forever { .. } | RunState::Timed { .. } | RunState::ToPosition { .. }
+        )
+    }
+
+    fn step(&mut self, dt: f64) {
+        let target = self.target_speed();
+        self.ramped_speed = if self.is_ramped() {
+            self.ramp_towards(target, dt)
+        } else {
+            target
+        };
+        let lag = match self.run_state {
+            RunState::Coasting => COAST_LAG,
+            RunState::Braking => BRAKE_LAG,
+            _ => RUNNING_LAG,
+        };
+        self.speed += (self.ramped_speed - self.speed) * (dt / lag.as_secs_f64()).min(1.0);
+        self.position += self.speed * dt;
+
+        match self.run_state {
+            RunState::Timed { until, .. } if self.time >= until => {
+                self.run_state = self.stopping_state();
+            }
+            RunState::ToPosition { target, .. }
+                if (target - self.position).abs() < EPSILON && self.speed.abs() < EPSILON =>
+            {
+                self.run_state = self.stopping_state();
+                if let RunState::Holding { .. } = self.run_state {
+                    self.run_state = RunState::Holding { target };
+                }
+            }
+            RunState::Coasting | RunState::Braking if self.speed.abs() < EPSILON => {
+                self.speed = 0.0;
+                self.ramped_speed = 0.0;
+                self.run_state = RunState::Stopped;
+            }
+            _ => {}
+        }
+    }
+
+    /// Moves the ramped speed towards `target` no faster than the ramp setpoints allow.
+    fn ramp_towards(&self, target: f64, dt: f64) -> f64 {
+        let current = self.ramped_speed;
+        let speeding_up = target.abs() > current.abs() && target * current >= 0.0;
+        let ramp_sp = if speeding_up {
+            "ramp_up_sp"
+        } else {
+            "ramp_down_sp"
+        };
+        let ramp_ms = self.get_number(ramp_sp).unwrap_or(0.0);
+        if ramp_ms <= 0.0 {
+            return target;
+        }
+        let max_change = self.max_speed() / (ramp_ms / 1000.0) * dt;
+        current + (target - current).clamp(-max_change, max_change)
+    }
+
+    fn is_settled(&self) -> bool {
+        let is_steady = (self.ramped_speed - self.speed).abs() < EPSILON / 10.0
+            && (self.target_speed() - self.ramped_speed).abs() < EPSILON / 10.0;
+        match self.run_state {
+            RunState::Stopped | RunState::Forever { .. } | RunState::Direct => is_steady,
+            RunState::Holding { target } => {
+                is_steady && (target - self.position).abs() < EPSILON / 10.0
+            }
+            _ => false,
+        }
+    }
+
+    fn state(&self) -> String {
+        let ramping = self.is_ramped()
+            && (self.target_speed() - self.ramped_speed).abs() > EPSILON
+            && !matches!(self.run_state, RunState::ToPosition { .. });
+        let state = match self.run_state {
+            RunState::Stopped | RunState::Coasting | RunState::Braking => "",
+            RunState::Holding { .. } => "holding",
+            _ if ramping => "running ramping",
+            _ => "running",
+        };
+        state.to_owned()
+    }
+
+    fn max_speed(&self) -> f64 {
+        self.get_number("max_speed").unwrap_or(1.0).max(1.0)
+    }
+
+    /// -1 for `inversed` polarity, which flips the direction of everything.
+    fn polarity(&self) -> f64 {
+        if self.value("polarity").ok() == Some("inversed") {
+            -1.0
+        } else {
+            1.0
+        }
+    }
+
+    fn value(&self, name: &str) -> HalResult<&str> {
+        self.values
+            .get(name)
+            .map(|value| value.as_str())
+            .ok_or_else(|| HalError::UnknownAttribute(name.to_owned()))
+    }
+
+    fn get_number(&self, name: &str) -> HalResult<f64> {
+        let value = self.value(name)?;
+        value
+            .parse()
+            .map_err(|_| HalError::InvalidValue(format!("{name} is not a number: {value}")))
+    }
+
+    fn get_list(&self, name: &str) -> HalResult<Vec<&str>> {
+        Ok(self.value(name)?.split_whitespace().collect())
+    }
+}
+
+fn default_values() -> BTreeMap<String, String> {
+    DEFAULT_VALUES
+        .into_iter()
+        .map(|(k, v)| (k.to_owned(), v.to_owned()))
+        .collect()
+}
+
+fn format_counts(value: f64) -> String {
+    // Avoid "-0".
+    (value.round() as i64).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn motor_after(writes: &[(&str, &str)], elapsed_ms: u64) -> SimulatedMotor {
+        let start = Instant::now();
+        let mut motor = SimulatedMotor::new(start);
+        for (name, value) in writes {
+            motor.set(name, value).unwrap();
+        }
+        motor.advance(start + Duration::from_millis(elapsed_ms));
+        motor
+    }
+
+    fn number(motor: &SimulatedMotor, name: &str) -> i64 {
+        motor.get(name).unwrap().parse().unwrap()
+    }
+
+    #[test]
+    fn test_run_forever_then_stop() {
+        let start = Instant::now();
+        let mut motor = SimulatedMotor::new(start);
+        motor.set("speed_sp", "500").unwrap();
+        motor.set("command", "run-forever").unwrap();
+        motor.advance(start + Duration::from_secs(1));
+        assert_eq!(number(&motor, "speed"), 500);
+        assert_eq!(motor.get("state").unwrap(), "running");
+        // Trailing by speed * RUNNING_LAG from starting up.
+        let position = number(&motor, "position");
+        assert!((455..=465).contains(&position), "{position}");
+
+        // An hour later, without simulating every step of it.
+        motor.advance(start + Duration::from_secs(3601));
+        assert_eq!(number(&motor, "position"), position + 3600 * 500);
+
+        motor.set("stop_action", "brake").unwrap();
+        motor.set("command", "stop").unwrap();
+        motor.advance(start + Duration::from_secs(3602));
+        assert_eq!(number(&motor, "speed"), 0);
+        assert_eq!(motor.get("state").unwrap(), "");
+    }
+
+    #[test]
+    fn test_run_timed_with_ramps() {
+        let writes = [
+            ("speed_sp", "1050"),
+            ("time_sp", "2000"),
+            ("ramp_up_sp", "1000"),
+            ("command", "run-timed"),
+        ];
+        let motor = motor_after(&writes, 500);
+        assert_eq!(motor.get("state").unwrap(), "running ramping");
+        let speed = number(&motor, "speed");
+        assert!((400..=525).contains(&speed), "{speed}");
+
+        let motor = motor_after(&writes, 1500);
+        assert_eq!(motor.get("state").unwrap(), "running");
+        assert_eq!(number(&motor, "speed"), 1050);
+
+        // Coasting takes a while.
+        let motor = motor_after(&writes, 4000);
+        assert_eq!(motor.get("state").unwrap(), "");
+        assert!(number(&motor, "speed") > 0);
+        let motor = motor_after(&writes, 6000);
+        assert_eq!(number(&motor, "speed"), 0);
+    }
+
+    #[test]
+    fn test_run_to_position() {
+        let motor = motor_after(
+            &[
+                ("speed_sp", "500"),
+                ("position_sp", "-360"),
+                ("stop_action", "hold"),
+                ("command", "run-to-abs-pos"),
+            ],
+            3000,
+        );
+        assert_eq!(number(&motor, "position"), -360);
+        assert_eq!(motor.get("state").unwrap(), "holding");
+
+        let motor = motor_after(
+            &[
+                ("speed_sp", "500"),
+                ("position_sp", "90"),
+                ("command", "run-to-rel-pos"),
+                ("command", "run-to-rel-pos"),
+            ],
+            3000,
+        );
+        // The second command starts out from where the first one did.
+        assert_eq!(number(&motor, "position"), 90);
+        assert_eq!(motor.get("state").unwrap(), "");
+    }
+
+    #[test]
+    fn test_direct_and_reset() {
+        let start = Instant::now();
+        let mut motor = SimulatedMotor::new(start);
+        motor.set("polarity", "inversed").unwrap();
+        motor.set("duty_cycle_sp", "50").unwrap();
+        motor.set("command", "run-direct").unwrap();
+        motor.advance(start + Duration::from_secs(1));
+        assert_eq!(number(&motor, "speed"), -525);
+        assert_eq!(number(&motor, "duty_cycle"), -50);
+
+        // Duty cycle changes apply right away in run-direct.
+        motor.set("duty_cycle_sp", "0").unwrap();
+        motor.advance(start + Duration::from_secs(2));
+        assert_eq!(number(&motor, "speed"), 0);
+
+        motor.set("command", "reset").unwrap();
+        assert_eq!(number(&motor, "position"), 0);
+        assert_eq!(motor.get("polarity").unwrap(), "normal");
+        assert!(matches!(
+            motor.set("command", "fly"),
+            Err(HalError::InvalidValue(_))
+        ));
+        assert!(motor.set("stop_action", "bogus").is_err());
+    }
+
+    #[test]
+    fn test_missing_values() {
+        let mut motor = SimulatedMotor::new(Instant::now());
+        motor.values.remove("speed_sp");
+        motor.values.remove("stop_actions");
+        assert!(matches!(
+            motor.set("command", "run-forever"),
+            Err(HalError::UnknownAttribute(_))
+        ));
+        assert!(matches!(
+            motor.set("stop_action", "hold"),
+            Err(HalError::UnknownAttribute(_))
+        ));
+        assert!(matches!(
+            motor.get("bogus"),
+            Err(HalError::UnknownAttribute(_))
+        ));
+    }
+}
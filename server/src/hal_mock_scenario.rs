@@ -0,0 +1,544 @@
+//! Robot descriptions for the mock HAL, so that the app can be developed against something
+//! resembling a real robot without one at hand.  A scenario lists the devices plugged into the
+//! brick's ports; the brick's own devices (battery, sound, buttons, display) are always present.
+//!
+//! Scenarios are TOML, or JSON if the file name ends in `.json`:
+//!
+//! ```toml
+//! [[devices]]
+//! class = "tacho-motor"
+//! driver_name = "lego-ev3-l-motor"
+//! address = "ev3-ports:outA"
+//!
+//! [[devices]]
+//! class = "lego-sensor"
+//! driver_name = "lego-ev3-color"
+//! address = "ev3-ports:in3"
+//! attributes = [
+//!     { name = "mode", value = "COL-REFLECT" },
+//!     { name = "value0", type = "uint8", units = "pct", generator = { kind = "sine", min = 5, max = 60, period_ms = 4000 } },
+//! ]
+//! ```
+//!
+//! Tacho motors are simulated by the mock itself, so their attributes can only be given initial
+//! values.  Attributes of any other device either hold a fixed `value` (which writes replace, for
+//! writable ones) or follow a `generator`:
+//!
+//! * **constant**: `value`
+//! * **sine**: oscillates between `min` and `max` every `period_ms`, shifted by `phase_ms`
+//! * **ramp**: goes from `from` to `to` over `duration_ms`, then stays at `to` or starts over if
+//!   `repeat` is set
+//! * **random_walk**: starts at `start` and moves up or down by up to `step` every `interval_ms`,
+//!   staying within `min..=max`.  The same `seed` gives the same walk.
+//! * **timeline**: takes on the `value` of each of `steps` from its `at_ms` onwards, starting over
+//!   every `period_ms` if given
+//!
+//! Times are relative to when the scenario was loaded.
+
+use std::collections::BTreeSet;
+use std::fmt;
+use std::path::Path;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Context};
+use serde::Deserialize;
+
+use crate::hal::{HalAttribute, HalAttributeType, HalDeviceType};
+use crate::rng::{next_rng, seed_rng};
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Scenario {
+    #[serde(default)]
+    pub devices: Vec<DeviceSpec>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DeviceSpec {
+    pub class: DeviceClass,
+    pub driver_name: String,
+    pub address: String,
+
+    /// What the port the device is plugged into reports in `auto` mode, e.g. `ev3-analog` for a
+    /// touch sensor.  Defaults to what's typical for the class.
+    pub port_status: Option<String>,
+
+    #[serde(default)]
+    pub attributes: Vec<AttributeSpec>,
+}
+
+/// sysfs class the device would show up in on the brick.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DeviceClass {
+    TachoMotor,
+    DcMotor,
+    ServoMotor,
+    LegoSensor,
+    Leds,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AttributeSpec {
+    pub name: String,
+    #[serde(rename = "type", default = "default_attribute_type")]
+    pub data_type: HalAttributeType,
+    #[serde(default)]
+    pub array: bool,
+    #[serde(default = "default_true")]
+    pub readable: bool,
+    #[serde(default)]
+    pub writable: bool,
+    pub units: Option<String>,
+    pub decimals: Option<u8>,
+    pub value: Option<Scalar>,
+    pub generator: Option<Generator>,
+}
+
+/// Attribute value as written in the scenario, which may be any TOML/JSON scalar.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(untagged)]
+pub enum Scalar {
+    Integer(i64),
+    Float(f64),
+    Bool(bool),
+    String(String),
+}
+
+impl fmt::Display for Scalar {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Scalar::Integer(value) => write!(f, "{value}"),
+            Scalar::Float(value) => write!(f, "{value}"),
+            // sysfs booleans are 0/1.
+            Scalar::Bool(value) => write!(f, "{}", u8::from(*value)),
+            Scalar::String(value) => f.write_str(value),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Generator {
+    Constant {
+        value: Scalar,
+    },
+    Sine {
+        min: f64,
+        max: f64,
+        period_ms: u64,
+        #[serde(default)]
+        phase_ms: u64,
+    },
+    Ramp {
+        from: f64,
+        to: f64,
+        duration_ms: u64,
+        #[serde(default)]
+        repeat: bool,
+    },
+    RandomWalk {
+        start: f64,
+        step: f64,
+        min: f64,
+        max: f64,
+        interval_ms: u64,
+        #[serde(default)]
+        seed: u64,
+    },
+    Timeline {
+        steps: Vec<TimelineStep>,
+        period_ms: Option<u64>,
+    },
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TimelineStep {
+    pub at_ms: u64,
+    pub value: Scalar,
+}
+
+fn default_attribute_type() -> HalAttributeType {
+    HalAttributeType::String
+}
+
+fn default_true() -> bool {
+    true
+}
+
+impl Scenario {
+    /// Reads and validates the scenario at `path`.
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let contents = std::fs::read_to_string(path)?;
+        let is_json = path.extension().and_then(|ext| ext.to_str()) == Some("json");
+        let scenario = if is_json {
+            Self::from_json(&contents)?
+        } else {
+            Self::from_toml(&contents)?
+        };
+        Ok(scenario)
+    }
+
+    pub fn from_toml(contents: &str) -> anyhow::Result<Self> {
+        let scenario: Self = toml::from_str(contents)?;
+        scenario.validate()?;
+        Ok(scenario)
+    }
+
+    pub fn from_json(contents: &str) -> anyhow::Result<Self> {
+        let scenario: Self = serde_json::from_str(contents)?;
+        scenario.validate()?;
+        Ok(scenario)
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        let mut addresses = BTreeSet::new();
+        for device in &self.devices {
+            if !addresses.insert(device.address.as_str()) {
+                bail!("Duplicate device address {}", device.address);
+            }
+            device
+                .validate()
+                .with_context(|| format!("Invalid device {}", device.address))?;
+        }
+        Ok(())
+    }
+}
+
+impl DeviceSpec {
+    pub fn device_type(&self) -> HalDeviceType {
+        match self.class {
+            DeviceClass::TachoMotor
+            | DeviceClass::DcMotor
+            | DeviceClass::ServoMotor
+            | DeviceClass::Leds => HalDeviceType::Actuator,
+            DeviceClass::LegoSensor => HalDeviceType::Sensor,
+        }
+    }
+
+    pub fn port_status(&self) -> String {
+        let default = match self.class {
+            DeviceClass::TachoMotor => "tacho-motor",
+            DeviceClass::DcMotor => "dc-motor",
+            DeviceClass::ServoMotor => "servo-motor",
+            DeviceClass::LegoSensor => "ev3-uart",
+            DeviceClass::Leds => "led",
+        };
+        self.port_status
+            .clone()
+            .unwrap_or_else(|| default.to_owned())
+    }
+
+    pub fn validate(&self) -> anyhow::Result<()> {
+        let mut names = BTreeSet::new();
+        for attribute in &self.attributes {
+            if !names.insert(attribute.name.as_str()) {
+                bail!("Duplicate attribute {}", attribute.name);
+            }
+            let is_motor = self.class == DeviceClass::TachoMotor;
+            attribute
+                .validate(is_motor)
+                .with_context(|| format!("Invalid attribute {}", attribute.name))?;
+        }
+        Ok(())
+    }
+}
+
+impl AttributeSpec {
+    pub fn to_hal(&self) -> HalAttribute {
+        let mut attribute = HalAttribute::new_rw(self.data_type, &self.name)
+            .with_units(self.units.clone(), self.decimals);
+        attribute.is_array = self.array;
+        attribute.is_readable = self.readable;
+        attribute.is_writable = self.writable;
+        attribute
+    }
+
+    fn validate(&self, is_motor: bool) -> anyhow::Result<()> {
+        if is_motor {
+            return match (&self.value, &self.generator) {
+                (Some(_), None) => Ok(()),
+                _ => Err(anyhow!("Tacho motor attributes only take a value")),
+            };
+        }
+        match (&self.value, &self.generator) {
+            (Some(_), Some(_)) => bail!("Either value or generator, not both"),
+            (None, None) if self.readable => bail!("Readable attributes need a value"),
+            (None, Some(generator)) => generator.validate(),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Generator {
+    fn validate(&self) -> anyhow::Result<()> {
+        match self {
+            Generator::Constant { .. } => {}
+            Generator::Sine { period_ms, .. } if *period_ms == 0 => {
+                bail!("period_ms must be positive")
+            }
+            Generator::Sine { .. } => {}
+            Generator::Ramp { .. } => {}
+            Generator::RandomWalk { interval_ms, .. } if *interval_ms == 0 => {
+                bail!("interval_ms must be positive")
+            }
+            Generator::RandomWalk {
+                start, min, max, ..
+            } if !(min <= start && start <= max) => bail!("start must be within min..=max"),
+            Generator::RandomWalk { .. } => {}
+            Generator::Timeline { steps, .. } if steps.is_empty() => bail!("No steps"),
+            Generator::Timeline {
+                period_ms: Some(0), ..
+            } => bail!("period_ms must be positive"),
+            Generator::Timeline { .. } => {}
+        }
+        Ok(())
+    }
+}
+
+/// A [`Generator`] along with the state needed to evaluate it.
+#[derive(Debug, Clone)]
+pub struct GeneratorState {
+    generator: Generator,
+
+    /// Steps taken so far by a random walk, and where they got to.
+    walk: Option<(u64, f64, u64)>,
+}
+
+impl GeneratorState {
+    pub fn new(generator: Generator) -> Self {
+        let walk = match &generator {
+            Generator::RandomWalk { start, seed, .. } => Some((0, *start, seed_rng(*seed))),
+            _ => None,
+        };
+        Self { generator, walk }
+    }
+
+    /// Value at `elapsed` since the scenario was loaded, formatted for `data_type`.
+    pub fn value_at(&mut self, elapsed: Duration, data_type: HalAttributeType) -> String {
+        let elapsed_ms = elapsed.as_millis() as u64;
+        let value = match &self.generator {
+            Generator::Constant { value } => return value.to_string(),
+            Generator::Timeline { steps, period_ms } => {
+                let at_ms = match period_ms {
+                    Some(period_ms) => elapsed_ms % period_ms,
+                    None => elapsed_ms,
+                };
+                let current = steps
+                    .iter()
+                    .filter(|s| s.at_ms <= at_ms)
+                    .max_by_key(|s| s.at_ms)
+                    .or_else(|| steps.iter().min_by_key(|s| s.at_ms));
+                return current.map(|s| s.value.to_string()).unwrap_or_default();
+            }
+            Generator::Sine {
+                min,
+                max,
+                period_ms,
+                phase_ms,
+            } => {
+                let turns = ((elapsed_ms + phase_ms) % period_ms) as f64 / *period_ms as f64;
+                let unit = (turns * std::f64::consts::TAU).sin();
+                min + (max - min) * (unit + 1.0) / 2.0
+            }
+            Generator::Ramp {
+                from,
+                to,
+                duration_ms,
+                repeat,
+            } => {
+                let done = match (*duration_ms, repeat) {
+                    (0, _) => 1.0,
+                    (duration_ms, true) => (elapsed_ms % duration_ms) as f64 / duration_ms as f64,
+                    (duration_ms, false) => (elapsed_ms as f64 / duration_ms as f64).min(1.0),
+                };
+                from + (to - from) * done
+            }
+            Generator::RandomWalk {
+                step,
+                min,
+                max,
+                interval_ms,
+                ..
+            } => {
+                let (mut steps, mut value, mut rng) = self.walk.unwrap();
+                while steps < elapsed_ms / interval_ms {
+                    rng = next_rng(rng);
+                    // Uniform in -1..=1.
+                    let unit = (rng >> 11) as f64 / (1u64 << 52) as f64 - 1.0;
+                    value = (value + unit * step).clamp(*min, *max);
+                    steps += 1;
+                }
+                self.walk = Some((steps, value, rng));
+                value
+            }
+        };
+        format_number(value, data_type)
+    }
+}
+
+fn format_number(value: f64, data_type: HalAttributeType) -> String {
+    match data_type {
+        HalAttributeType::Float32 | HalAttributeType::Float64 => {
+            ((value * 1000.0).round() / 1000.0).to_string()
+        }
+        HalAttributeType::String => value.to_string(),
+        _ => (value.round() as i64).to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EXAMPLE: &str = r#"
+        [[devices]]
+        class = "tacho-motor"
+        driver_name = "lego-ev3-m-motor"
+        address = "ev3-ports:outB"
+        attributes = [{ name = "max_speed", value = 1560 }]
+
+        [[devices]]
+        class = "lego-sensor"
+        driver_name = "lego-ev3-touch"
+        address = "ev3-ports:in1"
+        port_status = "ev3-analog"
+
+        [[devices.attributes]]
+        name = "value0"
+        type = "uint8"
+        generator = { kind = "timeline", steps = [{ at_ms = 0, value = 0 }, { at_ms = 500, value = 1 }], period_ms = 1000 }
+
+        [[devices.attributes]]
+        name = "mode"
+        writable = true
+        value = "TOUCH"
+    "#;
+
+    fn sample(generator: Generator, data_type: HalAttributeType, ms: &[u64]) -> Vec<String> {
+        let mut state = GeneratorState::new(generator);
+        ms.iter()
+            .map(|&ms| state.value_at(Duration::from_millis(ms), data_type))
+            .collect()
+    }
+
+    #[test]
+    fn test_parse() {
+        let scenario = Scenario::from_toml(EXAMPLE).unwrap();
+        assert_eq!(scenario.devices.len(), 2);
+        let motor = &scenario.devices[0];
+        assert_eq!(motor.port_status(), "tacho-motor");
+        assert_eq!(motor.attributes[0].value, Some(Scalar::Integer(1560)));
+        let touch = &scenario.devices[1];
+        assert_eq!(touch.port_status(), "ev3-analog");
+        assert!(matches!(touch.device_type(), HalDeviceType::Sensor));
+        let value0 = touch.attributes[0].to_hal();
+        assert!(value0.is_readable && !value0.is_writable);
+        assert!(matches!(value0.data_type, HalAttributeType::UInt8));
+
+        let json = r#"{"devices": [{"class": "leds", "driver_name": "leds",
+            "address": "led0", "attributes": [{"name": "brightness", "value": 255}]}]}"#;
+        assert_eq!(Scenario::from_json(json).unwrap().devices.len(), 1);
+    }
+
+    #[test]
+    fn test_invalid() {
+        let duplicate = r#"
+            [[devices]]
+            class = "lego-sensor"
+            driver_name = "lego-ev3-touch"
+            address = "ev3-ports:in1"
+            [[devices]]
+            class = "lego-sensor"
+            driver_name = "lego-ev3-touch"
+            address = "ev3-ports:in1"
+        "#;
+        assert!(Scenario::from_toml(duplicate).is_err());
+
+        let motor_generator = r#"
+            [[devices]]
+            class = "tacho-motor"
+            driver_name = "lego-ev3-l-motor"
+            address = "ev3-ports:outA"
+            attributes = [{ name = "position", generator = { kind = "constant", value = 1 } }]
+        "#;
+        assert!(Scenario::from_toml(motor_generator).is_err());
+
+        let no_value = r#"
+            [[devices]]
+            class = "lego-sensor"
+            driver_name = "lego-ev3-touch"
+            address = "ev3-ports:in1"
+            attributes = [{ name = "value0" }]
+        "#;
+        assert!(Scenario::from_toml(no_value).is_err());
+    }
+
+    #[test]
+    fn test_generators() {
+        let sine = Generator::Sine {
+            min: 0.0,
+            max: 100.0,
+            period_ms: 4000,
+            phase_ms: 0,
+        };
+        let ms = [0, 1000, 2000, 3000, 4000];
+        assert_eq!(
+            sample(sine, HalAttributeType::Int32, &ms),
+            vec!["50", "100", "50", "0", "50"]
+        );
+
+        let ramp = Generator::Ramp {
+            from: 1.0,
+            to: 2.0,
+            duration_ms: 1000,
+            repeat: false,
+        };
+        assert_eq!(
+            sample(ramp, HalAttributeType::Float32, &[0, 250, 1000, 5000]),
+            vec!["1", "1.25", "2", "2"]
+        );
+
+        let timeline = Generator::Timeline {
+            steps: vec![
+                TimelineStep {
+                    at_ms: 0,
+                    value: Scalar::String("idle".to_owned()),
+                },
+                TimelineStep {
+                    at_ms: 100,
+                    value: Scalar::String("pressed".to_owned()),
+                },
+            ],
+            period_ms: None,
+        };
+        assert_eq!(
+            sample(timeline, HalAttributeType::String, &[0, 99, 100, 5000]),
+            vec!["idle", "idle", "pressed", "pressed"]
+        );
+    }
+
+    #[test]
+    fn test_random_walk() {
+        let walk = Generator::RandomWalk {
+            start: 50.0,
+            step: 10.0,
+            min: 40.0,
+            max: 60.0,
+            interval_ms: 10,
+            seed: 7,
+        };
+        let ms: Vec<_> = (0..100).map(|i| i * 10).collect();
+        let values = sample(walk.clone(), HalAttributeType::Int32, &ms);
+        assert_eq!(values[0], "50");
+        assert!(values.iter().any(|v| v != "50"));
+        for value in &values {
+            let value: i32 = value.parse().unwrap();
+            assert!((40..=60).contains(&value));
+        }
+        assert_eq!(sample(walk, HalAttributeType::Int32, &ms), values);
+    }
+}
@@ -0,0 +1,281 @@
+//! Virtual tacho motor attributes in physical units.  Native position and speed attributes count
+//! encoder ticks, and how many ticks make up a rotation (`count_per_rot`) or, for linear
+//! actuators, a meter (`count_per_m`) differs between motors.  The attributes below convert to
+//! and from the native ones on every access, so they behave just like them:
+//!
+//! * **position_deg**: `position` in degrees, read-only
+//! * **speed_rpm**: `speed` in rotations per minute, read-only
+//! * **position_sp_deg**: `position_sp` in degrees
+//! * **speed_sp_rpm**: `speed_sp` in rotations per minute.  Like `speed_sp`, values beyond the
+//!   motor's `max_speed` are rejected.
+//! * **distance_mm**: `position` in millimeters, read-only
+//!
+//! The angular attributes are only provided for rotational motors (with `count_per_rot`) and
+//! `distance_mm` only for linear actuators (with `count_per_m`).  Only the setpoints can be
+//! written, and only if their native attribute can.
+
+use crate::hal::{HalAttribute, HalAttributeType, HalError, HalResult};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum MotorUnitAttribute {
+    PositionDeg,
+    SpeedRpm,
+    PositionSpDeg,
+    SpeedSpRpm,
+    DistanceMm,
+}
+
+const ALL: [MotorUnitAttribute; 5] = [
+    MotorUnitAttribute::PositionDeg,
+    MotorUnitAttribute::SpeedRpm,
+    MotorUnitAttribute::PositionSpDeg,
+    MotorUnitAttribute::SpeedSpRpm,
+    MotorUnitAttribute::DistanceMm,
+];
+
+impl MotorUnitAttribute {
+    pub fn parse(name: &str) -> Option<Self> {
+        ALL.into_iter().find(|a| a.name() == name)
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::PositionDeg => "position_deg",
+            Self::SpeedRpm => "speed_rpm",
+            Self::PositionSpDeg => "position_sp_deg",
+            Self::SpeedSpRpm => "speed_sp_rpm",
+            Self::DistanceMm => "distance_mm",
+        }
+    }
+
+    /// Native attribute holding the value in encoder counts.
+    pub fn native_attribute(&self) -> &'static str {
+        match self {
+            Self::PositionDeg | Self::DistanceMm => "position",
+            Self::SpeedRpm => "speed",
+            Self::PositionSpDeg => "position_sp",
+            Self::SpeedSpRpm => "speed_sp",
+        }
+    }
+
+    /// Whether this is a setpoint, which unlike the current readings can be written.
+    pub fn is_setpoint(&self) -> bool {
+        matches!(self, Self::PositionSpDeg | Self::SpeedSpRpm)
+    }
+
+    /// Native attribute holding the number of encoder counts per unit of [`Self::per_count`].
+    fn scale_attribute(&self) -> &'static str {
+        match self {
+            Self::DistanceMm => "count_per_m",
+            _ => "count_per_rot",
+        }
+    }
+
+    fn units(&self) -> &'static str {
+        match self {
+            Self::PositionDeg | Self::PositionSpDeg => "deg",
+            Self::SpeedRpm | Self::SpeedSpRpm => "rpm",
+            Self::DistanceMm => "mm",
+        }
+    }
+
+    /// Value of a single encoder count given the value of [`Self::scale_attribute`].
+    fn per_count(&self, scale: f64) -> f64 {
+        match self {
+            Self::PositionDeg | Self::PositionSpDeg => 360.0 / scale,
+            // Native speeds are in counts per second.
+            Self::SpeedRpm | Self::SpeedSpRpm => 60.0 / scale,
+            Self::DistanceMm => 1000.0 / scale,
+        }
+    }
+
+    /// Reads the value, with `read` reading native attributes.
+    pub fn read(&self, read: impl Fn(&str) -> HalResult<String>) -> HalResult<String> {
+        let counts = parse_native(self.native_attribute(), &read(self.native_attribute())?)?;
+        let scale = self.read_scale(&read)?;
+        Ok((counts * self.per_count(scale)).to_string())
+    }
+
+    /// Converts `value` to what should be written to [`Self::native_attribute`].
+    pub fn native_value(
+        &self,
+        value: &str,
+        read: impl Fn(&str) -> HalResult<String>,
+    ) -> HalResult<String> {
+        if !self.is_setpoint() {
+            return Err(HalError::PermissionDenied(self.name().to_owned()));
+        }
+        let value = value
+            .trim()
+            .parse::<f64>()
+            .map_err(|_| HalError::InvalidValue(format!("Expected a number, got {value}")))?;
+        let scale = self.read_scale(&read)?;
+        let counts = (value / self.per_count(scale)).round();
+        // The native attribute's bounds, as validation only sees the virtual one.
+        let (min, max) = match self {
+            Self::SpeedSpRpm => match read("max_speed") {
+                Ok(max_speed) => {
+                    let limit = parse_native("max_speed", &max_speed)?;
+                    (-limit, limit)
+                }
+                Err(_) => (i32::MIN.into(), i32::MAX.into()),
+            },
+            _ => (i32::MIN.into(), i32::MAX.into()),
+        };
+        if counts < min || counts > max {
+            return Err(HalError::InvalidValue(format!(
+                "{value} {} is out of range",
+                self.units()
+            )));
+        }
+        Ok((counts as i32).to_string())
+    }
+
+    fn read_scale(&self, read: impl Fn(&str) -> HalResult<String>) -> HalResult<f64> {
+        let name = self.scale_attribute();
+        let scale = parse_native(name, &read(name)?)?;
+        if scale <= 0.0 {
+            return Err(HalError::InternalError(format!(
+                "Unexpected {name}: {scale}"
+            )));
+        }
+        Ok(scale)
+    }
+}
+
+/// Adds the virtual attributes backed by the native `attributes` present.  Setpoints are
+/// writable if their native attribute is, everything else is read-only.
+pub fn add_unit_attributes(attributes: &mut Vec<HalAttribute>) {
+    let find = |name: &str| attributes.iter().find(|a| a.name == name);
+    let mut added = Vec::new();
+    for unit in ALL {
+        let native = find(unit.native_attribute());
+        if let (Some(native), Some(_)) = (native, find(unit.scale_attribute())) {
+            let attribute = match (native.is_readable, unit.is_setpoint() && native.is_writable) {
+                (true, true) => HalAttribute::new_rw(HalAttributeType::Float32, unit.name()),
+                (true, false) => HalAttribute::new_readonly(HalAttributeType::Float32, unit.name()),
+                (false, true) => {
+                    HalAttribute::new_writeonly(HalAttributeType::Float32, unit.name())
+                }
+                (false, false) => continue,
+            };
+            added.push(attribute.with_units(Some(unit.units().to_owned()), None));
+        }
+    }
+    attributes.extend(added);
+}
+
+fn parse_native(name: &str, value: &str) -> HalResult<f64> {
+    value
+        .trim()
+        .parse()
+        .map_err(|_| HalError::InternalError(format!("Unexpected {name} value: {value}")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn reader(values: &[(&str, &str)]) -> impl Fn(&str) -> HalResult<String> {
+        let values: HashMap<_, _> = values
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        move |name| {
+            values
+                .get(name)
+                .cloned()
+                .ok_or_else(|| HalError::UnknownAttribute(name.to_owned()))
+        }
+    }
+
+    #[test]
+    fn test_rotation_units() {
+        // LEGO motors have 360 counts per rotation, use something else to catch mixups.
+        let read = reader(&[
+            ("count_per_rot", "180"),
+            ("max_speed", "1050"),
+            ("position", "90"),
+            ("speed", "-30"),
+        ]);
+        assert_eq!(MotorUnitAttribute::PositionDeg.read(&read).unwrap(), "180");
+        assert_eq!(MotorUnitAttribute::SpeedRpm.read(&read).unwrap(), "-10");
+        assert_eq!(
+            MotorUnitAttribute::PositionSpDeg
+                .native_value("45", &read)
+                .unwrap(),
+            "23"
+        );
+        assert_eq!(
+            MotorUnitAttribute::SpeedSpRpm
+                .native_value("100", &read)
+                .unwrap(),
+            "300"
+        );
+        assert!(MotorUnitAttribute::SpeedSpRpm
+            .native_value("-350", &read)
+            .is_ok());
+        assert!(matches!(
+            MotorUnitAttribute::SpeedSpRpm.native_value("100000", &read),
+            Err(HalError::InvalidValue(_))
+        ));
+        assert!(matches!(
+            MotorUnitAttribute::SpeedSpRpm.native_value("fast", &read),
+            Err(HalError::InvalidValue(_))
+        ));
+        assert!(MotorUnitAttribute::DistanceMm.read(&read).is_err());
+    }
+
+    #[test]
+    fn test_linear_units() {
+        let read = reader(&[
+            ("count_per_m", "2000"),
+            ("full_travel_count", "200"),
+            ("position", "150"),
+        ]);
+        assert_eq!(MotorUnitAttribute::DistanceMm.read(&read).unwrap(), "75");
+        assert!(matches!(
+            MotorUnitAttribute::DistanceMm.native_value("50", &read),
+            Err(HalError::PermissionDenied(_))
+        ));
+        assert!(MotorUnitAttribute::PositionDeg.read(&read).is_err());
+    }
+
+    #[test]
+    fn test_add_unit_attributes() {
+        let mut attributes = vec![
+            HalAttribute::new_readonly(HalAttributeType::Int32, "count_per_rot"),
+            HalAttribute::new_rw(HalAttributeType::Int32, "position"),
+            HalAttribute::new_readonly(HalAttributeType::Int32, "speed"),
+            HalAttribute::new_rw(HalAttributeType::Int32, "speed_sp"),
+        ];
+        add_unit_attributes(&mut attributes);
+
+        let names: Vec<_> = attributes[4..].iter().map(|a| a.name.as_str()).collect();
+        assert_eq!(names, vec!["position_deg", "speed_rpm", "speed_sp_rpm"]);
+        let speed_sp_rpm = &attributes[6];
+        assert!(speed_sp_rpm.is_readable && speed_sp_rpm.is_writable);
+        assert_eq!(speed_sp_rpm.units.as_deref(), Some("rpm"));
+        assert!(speed_sp_rpm.bounds.is_none());
+        let position_deg = &attributes[4];
+        assert!(position_deg.is_readable && !position_deg.is_writable);
+        assert_eq!(position_deg.units.as_deref(), Some("deg"));
+        assert!(!attributes[5].is_writable);
+    }
+
+    #[test]
+    fn test_add_unit_attributes_linear() {
+        let mut attributes = vec![
+            HalAttribute::new_readonly(HalAttributeType::Int32, "count_per_m"),
+            HalAttribute::new_rw(HalAttributeType::Int32, "position"),
+            HalAttribute::new_rw(HalAttributeType::Int32, "position_sp"),
+        ];
+        add_unit_attributes(&mut attributes);
+
+        let names: Vec<_> = attributes[3..].iter().map(|a| a.name.as_str()).collect();
+        assert_eq!(names, vec!["distance_mm"]);
+        assert!(!attributes[3].is_writable);
+        assert_eq!(attributes[3].units.as_deref(), Some("mm"));
+    }
+}
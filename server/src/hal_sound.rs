@@ -0,0 +1,144 @@
+//! Sound requests understood by sound devices.  Clients drive sound devices by writing to one of
+//! the write-only attributes below; each write is parsed into a [`SoundRequest`] which the HAL
+//! implementation then plays back.
+//!
+//! * **tone**: `<frequency_hz> <duration_ms>` - single beep
+//! * **tone_sequence**: `<frequency_hz> <duration_ms> <delay_ms>,...` - beeps played back to back,
+//!   pausing for `delay_ms` after each one
+//! * **play**: `<clip>` - play a WAV clip previously uploaded via [`crate::hal::HalDevice::put_blob`]
+//! * **speak**: `<text>` - text to speech
+//!
+//! Requests are played back one after the other.  Writes made while too many requests are still
+//! waiting are rejected as busy.
+
+use crate::hal::{HalAttribute, HalAttributeType, HalError, HalResult};
+
+/// Upper bound on uploaded clip size, the EV3 doesn't have a whole lot of storage to spare.
+pub const MAX_CLIP_BYTES: usize = 4 * 1024 * 1024;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SoundRequest {
+    Tone(Tone),
+    ToneSequence(Vec<Tone>),
+    PlayClip(String),
+    Speak(String),
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Tone {
+    pub frequency_hz: u32,
+    pub duration_ms: u32,
+    pub delay_ms: u32,
+}
+
+impl SoundRequest {
+    pub fn parse(attribute: &str, value: &str) -> HalResult<Self> {
+        match attribute {
+            "tone" => Ok(Self::Tone(parse_tone(value, false)?)),
+            "tone_sequence" => {
+                let tones: HalResult<Vec<_>> = value
+                    .split(',')
+                    .map(|tone| parse_tone(tone, true))
+                    .collect();
+                Ok(Self::ToneSequence(tones?))
+            }
+            "play" => {
+                validate_clip_name(value)?;
+                Ok(Self::PlayClip(value.to_owned()))
+            }
+            "speak" => Ok(Self::Speak(value.to_owned())),
+            _ => Err(HalError::PermissionDenied(attribute.to_owned())),
+        }
+    }
+}
+
+/// Attributes common to all sound devices, not including `address` and `driver_name`.
+pub fn sound_attributes() -> Vec<HalAttribute> {
+    vec![
+        HalAttribute::new_readonly_array(HalAttributeType::String, "clips"),
+        HalAttribute::new_writeonly(HalAttributeType::String, "play"),
+        HalAttribute::new_writeonly(HalAttributeType::String, "speak"),
+        HalAttribute::new_writeonly(HalAttributeType::String, "tone"),
+        HalAttribute::new_writeonly(HalAttributeType::String, "tone_sequence"),
+    ]
+}
+
+/// Clip names end up as file names on the brick so keep them boring.
+pub fn validate_clip_name(name: &str) -> HalResult<()> {
+    let is_valid = !name.is_empty()
+        && !name.starts_with('.')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+    if is_valid {
+        Ok(())
+    } else {
+        Err(HalError::InvalidValue(format!("Invalid clip name: {name}")))
+    }
+}
+
+fn parse_tone(value: &str, with_delay: bool) -> HalResult<Tone> {
+    let parts: Vec<_> = value.split_whitespace().collect();
+    let parsed: Result<Vec<u32>, _> = parts.iter().map(|p| p.parse::<u32>()).collect();
+    match (parsed.as_deref(), with_delay) {
+        (Ok(&[frequency_hz, duration_ms]), false) => Ok(Tone {
+            frequency_hz,
+            duration_ms,
+            delay_ms: 0,
+        }),
+        (Ok(&[frequency_hz, duration_ms, delay_ms]), true) => Ok(Tone {
+            frequency_hz,
+            duration_ms,
+            delay_ms,
+        }),
+        _ => Err(HalError::InvalidValue(format!("Invalid tone: {value}"))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_tone() {
+        let request = SoundRequest::parse("tone", "440 250").unwrap();
+        assert_eq!(
+            request,
+            SoundRequest::Tone(Tone {
+                frequency_hz: 440,
+                duration_ms: 250,
+                delay_ms: 0
+            })
+        );
+        assert!(SoundRequest::parse("tone", "440").is_err());
+        assert!(SoundRequest::parse("tone", "440 250 10").is_err());
+    }
+
+    #[test]
+    fn test_parse_tone_sequence() {
+        let request = SoundRequest::parse("tone_sequence", "392 100 50, 523 200 0").unwrap();
+        assert_eq!(
+            request,
+            SoundRequest::ToneSequence(vec![
+                Tone {
+                    frequency_hz: 392,
+                    duration_ms: 100,
+                    delay_ms: 50
+                },
+                Tone {
+                    frequency_hz: 523,
+                    duration_ms: 200,
+                    delay_ms: 0
+                },
+            ])
+        );
+    }
+
+    #[test]
+    fn test_clip_names() {
+        assert!(SoundRequest::parse("play", "hello-world_2.wav").is_ok());
+        assert!(SoundRequest::parse("play", "../etc/passwd").is_err());
+        assert!(SoundRequest::parse("play", ".hidden").is_err());
+        assert!(SoundRequest::parse("play", "").is_err());
+    }
+}
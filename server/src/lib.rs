@@ -0,0 +1,40 @@
+//! CoAP server exposing the EV3's motors, sensors and other devices, see [`device_resource`] and
+//! [`port_resource`] for the API.  The binary merely wires this up; the library exists so that
+//! benchmarks can get at the internals.
+
+pub mod anyhow_error_wrapper;
+pub mod attribute_validation;
+pub mod attribute_writes;
+pub mod attributes_observable;
+pub mod bin_data;
+pub mod clock;
+pub mod device_resource;
+pub mod devices_observable;
+pub mod framebuffer;
+pub mod hal;
+pub mod hal_buttons;
+pub mod hal_display;
+pub mod hal_ev3;
+pub mod hal_ev3_attributes;
+pub mod hal_ev3_buttons;
+pub mod hal_ev3_files;
+#[cfg(test)]
+mod hal_ev3_fixture;
+pub mod hal_ev3_index;
+#[cfg(test)]
+mod hal_ev3_integration_tests;
+pub mod hal_ev3_port;
+pub mod hal_ev3_sound;
+pub mod hal_faulty;
+pub mod hal_leds;
+pub mod hal_mock;
+pub mod hal_mock_motor;
+pub mod hal_mock_scenario;
+pub mod hal_motor_units;
+pub mod hal_sound;
+pub mod layout_resource;
+pub mod mock_resource;
+pub mod port_resource;
+pub mod ports_observable;
+mod rng;
+pub mod status_screen;
@@ -1,20 +1,19 @@
-use crate::device_resource::device_resources;
-use anyhow::anyhow;
+use anyhow::{anyhow, Context};
 use clap::Parser;
 use coap_server::{app, CoapServer, UdpTransport};
+use ev3_remote_control_server::clock::SystemClock;
+use ev3_remote_control_server::device_resource::device_resources;
+use ev3_remote_control_server::hal_faulty::FaultConfig;
+use ev3_remote_control_server::hal_mock::{HalMock, MockControl};
+use ev3_remote_control_server::hal_mock_scenario::Scenario;
+use ev3_remote_control_server::mock_resource::mock_resources;
+use ev3_remote_control_server::port_resource::port_resources;
+use ev3_remote_control_server::{hal, status_screen};
 use log::info;
+use std::path::PathBuf;
 use tokio::process::Command;
 use tokio::runtime::Runtime;
 
-mod anyhow_error_wrapper;
-mod attributes_observable;
-mod device_resource;
-mod devices_observable;
-mod hal;
-mod hal_ev3;
-mod hal_mock;
-mod layout_resource;
-
 #[derive(Parser)]
 #[clap(author, version, about, long_about = None)]
 struct Opts {
@@ -23,15 +22,99 @@ struct Opts {
 
     #[clap(short, long)]
     port: Option<u16>,
+
+    /// Simulate the robot described by this scenario file (TOML, or JSON if named `*.json`)
+    /// instead of using the brick's devices.
+    #[clap(long)]
+    scenario: Option<PathBuf>,
+
+    /// Serve `/mock` for changing the simulated devices while running, e.g. to unplug a motor
+    /// from a test script.  Needs the mock HAL, so it's refused where there are real devices
+    /// unless `--scenario` is given too.
+    #[clap(long)]
+    mock_control: bool,
+
+    /// Inject latency, errors and flapping devices as described by this file (TOML), to see how
+    /// clients cope.  Works with the brick's devices as well as the mock.
+    #[clap(long)]
+    faults: Option<PathBuf>,
+
+    /// Delay every HAL operation by this many milliseconds, replacing the `latency_ms` of every
+    /// operation in `--faults`.
+    #[clap(long)]
+    fault_latency_ms: Option<u64>,
+
+    /// Fail every HAL operation with this probability (0 to 1), replacing the `error_rate` of
+    /// every operation in `--faults`.
+    #[clap(long)]
+    fault_error_rate: Option<f64>,
 }
 
 fn main() {
     logging_init();
 
     let opts: Opts = Opts::parse();
+    let mock = match &opts.scenario {
+        Some(path) => {
+            let mock = Scenario::load(path)
+                .and_then(|scenario| HalMock::from_scenario(&scenario, SystemClock::shared()));
+            match mock {
+                Ok(mock) => Some(mock),
+                Err(e) => {
+                    eprintln!("Cannot load scenario {}: {e:#}", path.display());
+                    std::process::exit(2);
+                }
+            }
+        }
+        None if opts.mock_control => {
+            if hal::has_real_devices() {
+                eprintln!(
+                    "Cannot use --mock-control with real devices, pass --scenario to simulate them"
+                );
+                std::process::exit(2);
+            }
+            Some(HalMock::with_hardcoded_devices())
+        }
+        None => None,
+    };
+    let mock_control = match &mock {
+        Some(mock) if opts.mock_control => Some(mock.control()),
+        _ => None,
+    };
+    if let Some(mock) = mock {
+        if let Err(e) = hal::use_mock(mock) {
+            eprintln!("Cannot use the mock HAL: {e:#}");
+            std::process::exit(2);
+        }
+    }
+    match load_faults(&opts) {
+        Ok(Some(config)) => hal::use_faults(config),
+        Ok(None) => {}
+        Err(e) => {
+            eprintln!("Cannot set up fault injection: {e:#}");
+            std::process::exit(2);
+        }
+    }
 
     let bind_addr = determine_bind_address(opts);
-    run_server_forever(bind_addr);
+    run_server_forever(bind_addr, mock_control);
+}
+
+fn load_faults(opts: &Opts) -> anyhow::Result<Option<FaultConfig>> {
+    if opts.faults.is_none() && opts.fault_latency_ms.is_none() && opts.fault_error_rate.is_none() {
+        return Ok(None);
+    }
+    let mut config = match &opts.faults {
+        Some(path) => FaultConfig::load(path).with_context(|| path.display().to_string())?,
+        None => FaultConfig::default(),
+    };
+    if let Some(latency_ms) = opts.fault_latency_ms {
+        config = config.with_latency(latency_ms);
+    }
+    if let Some(error_rate) = opts.fault_error_rate {
+        config = config.with_error_rate(error_rate)?;
+    }
+    Ok(Some(config))
 }
 
 fn logging_init() {
@@ -47,10 +130,11 @@ fn determine_bind_address(opts: Opts) -> (String, u16) {
     (address, port)
 }
 
-fn run_server_forever(addr: (String, u16)) {
+fn run_server_forever(addr: (String, u16), mock_control: Option<MockControl>) {
     Runtime::new().unwrap().block_on(async move {
+        start_status_screen(addr.1);
         let mdns_future = run_mdns_advertisement(addr.1);
-        let coap_future = run_coap_server(addr);
+        let coap_future = run_coap_server(addr, mock_control);
 
         tokio::try_join!(mdns_future, coap_future).unwrap();
     });
@@ -69,11 +153,25 @@ async fn run_mdns_advertisement(_port: u16) -> anyhow::Result<()> {
     Err(anyhow!("Unexpected avahi exit: {:?}", status))
 }
 
-async fn run_coap_server(addr: (String, u16)) -> anyhow::Result<()> {
+fn start_status_screen(port: u16) {
+    if let Some(display) = hal::HAL.display() {
+        status_screen::start(display, port, &SystemClock);
+    }
+}
+
+async fn run_coap_server(
+    addr: (String, u16),
+    mock_control: Option<MockControl>,
+) -> anyhow::Result<()> {
     let server = CoapServer::bind(UdpTransport::new(addr.clone())).await?;
     info!("Server up on {addr:?}");
-    server
-        .serve(app::new().resources(device_resources()))
-        .await?;
+    let mut app = app::new()
+        .resources(device_resources())
+        .resources(port_resources());
+    if let Some(control) = mock_control {
+        info!("Serving mock control at /mock");
+        app = app.resources(mock_resources(control));
+    }
+    server.serve(app).await?;
     Err(anyhow!("Unexpected CoAP server exit!"))
 }
@@ -0,0 +1,276 @@
+//! Remote control of the mock HAL, so that scripts driving the app can simulate things happening
+//! to the robot: a touch sensor being pressed, a motor being unplugged, a sensor failing, etc.
+//! Only served when the server is started with `--mock-control`.
+//!
+//! # Types
+//!
+//! ## Type: Injection
+//!
+//! ### Fields:
+//!
+//! **value**: mixed - value that reads of the attribute yield from now on, until the attribute is
+//!    written.  `null` goes back to the device's own value.
+//!
+//! ### Example:
+//!
+//! ```
+//! {
+//!   "value": 1,
+//! }
+//! ```
+//!
+//! ## Type: Fault
+//!
+//! ### Fields:
+//!
+//! **kind**: string - error kind as reported to clients, one of `not_connected`, `device_gone`,
+//!    `permission_denied`, `invalid_value`, `busy`, `io` or `internal`
+//! **count**: optional int - how many reads of the attribute fail, 1 by default.  0 clears a
+//!    previously injected fault.
+//!
+//! ### Example:
+//!
+//! ```
+//! {
+//!   "kind": "not_connected",
+//!   "count": 3,
+//! }
+//! ```
+//!
+//! # Requests
+//!
+//! ## POST /mock/devices
+//!
+//! Plug in a device, which is described just like the devices of a scenario (see
+//! `src/hal_mock_scenario.rs`).  Observers of `/devices` and `/ports` are notified.
+//!
+//! Request Type: device as in a JSON scenario
+//!
+//! ## DELETE /mock/devices/<address>
+//!
+//! Unplug the device.  Observers of `/devices` and `/ports` are notified, and requests for the
+//! device fail from then on.
+//!
+//! ## PUT /mock/devices/<address>/values/<attribute>
+//!
+//! Override what reading the attribute yields.  Observers of the attribute are notified.
+//!
+//! Request Type: Injection
+//!
+//! ## PUT /mock/devices/<address>/faults/<attribute>
+//!
+//! Make the next reads of the attribute fail.
+//!
+//! Request Type: Fault
+
+use crate::anyhow_error_wrapper::AnyhowErrorWrapper;
+use crate::hal::FaultKind;
+use crate::hal_mock::MockControl;
+use crate::hal_mock_scenario::{DeviceSpec, Scalar};
+use crate::status_screen::RecordClients;
+use coap_lite::{MessageClass, RequestType, ResponseType};
+use coap_server::app;
+use coap_server::app::{CoapError, Request, ResourceBuilder, Response};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use std::net::SocketAddr;
+
+pub fn mock_resources(control: MockControl) -> Vec<ResourceBuilder<SocketAddr>> {
+    let handler = move |request| handle_mock(control.clone(), request);
+    let handler = RecordClients::new(AnyhowErrorWrapper::new(handler));
+    vec![app::resource("mock").default_handler(handler)]
+}
+
+async fn handle_mock(
+    control: MockControl,
+    request: Request<SocketAddr>,
+) -> anyhow::Result<Response> {
+    let method = *request.original.get_method();
+    let path: Vec<_> = request.unmatched_path.iter().map(|s| s.as_str()).collect();
+
+    let code = match (method, path.as_slice()) {
+        (RequestType::Post, ["devices"]) => {
+            let spec: DeviceSpec = parse_payload(&request)?;
+            control
+                .add_device(&spec)
+                .map_err(|e| CoapError::bad_request(format!("{e:#}")))?;
+            ResponseType::Created
+        }
+        (RequestType::Delete, ["devices", address]) => {
+            if !control.remove_device(address) {
+                Err(CoapError::not_found())?;
+            }
+            ResponseType::Deleted
+        }
+        (RequestType::Put, ["devices", address, "values", name]) => {
+            let injection: Injection = parse_payload(&request)?;
+            let value = injection.value.map(|v| v.to_string());
+            control.inject_value(address, name, value)?;
+            ResponseType::Changed
+        }
+        (RequestType::Put, ["devices", address, "faults", name]) => {
+            let fault: Fault = parse_payload(&request)?;
+            control.inject_fault(address, name, fault.kind, fault.count)?;
+            ResponseType::Changed
+        }
+        (_, ["devices"] | ["devices", _] | ["devices", _, _, _]) => {
+            Err(CoapError::method_not_allowed())?
+        }
+        _ => Err(CoapError::not_found())?,
+    };
+
+    let mut reply = request.new_response();
+    reply.message.header.code = MessageClass::Response(code);
+    reply.message.payload.clear();
+    Ok(reply)
+}
+
+fn parse_payload<T: DeserializeOwned>(request: &Request<SocketAddr>) -> anyhow::Result<T> {
+    let payload = &request.original.message.payload;
+    serde_json::from_slice(payload).map_err(|e| CoapError::bad_request(e.to_string()).into())
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Injection {
+    value: Option<Scalar>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Fault {
+    kind: FaultKind,
+    #[serde(default = "default_fault_count")]
+    count: u32,
+}
+
+fn default_fault_count() -> u32 {
+    1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hal::{Hal, HalError};
+    use crate::hal_mock::HalMock;
+    use coap_lite::CoapRequest;
+    use coap_server::app::request_handler::RequestHandler;
+
+    fn request(method: RequestType, path: &str, payload: &str) -> Request<SocketAddr> {
+        let mut original = CoapRequest::new();
+        original.set_method(method);
+        original.message.payload = payload.as_bytes().to_vec();
+        Request {
+            original,
+            unmatched_path: path.split('/').map(str::to_owned).collect(),
+        }
+    }
+
+    /// Handles `request` the way the server does, returning the response code the client gets.
+    async fn send(control: &MockControl, request: Request<SocketAddr>) -> ResponseType {
+        let control = control.clone();
+        let handler = AnyhowErrorWrapper::new(move |r| handle_mock(control.clone(), r));
+        match handler.handle(request).await {
+            Ok(reply) => *reply.get_status(),
+            Err(e) => e.code.unwrap(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_plugging() {
+        let hal = HalMock::with_hardcoded_devices();
+        let control = hal.control();
+        let touch = r#"{
+            "class": "lego-sensor",
+            "driver_name": "lego-ev3-touch",
+            "address": "ev3-ports:in3",
+            "attributes": [{"name": "value0", "type": "uint8", "value": 0}]
+        }"#;
+
+        let post = || request(RequestType::Post, "devices", touch);
+        assert_eq!(send(&control, post()).await, ResponseType::Created);
+        assert!(hal.by_address("ev3-ports:in3").unwrap().is_some());
+        assert_eq!(send(&control, post()).await, ResponseType::BadRequest);
+
+        let delete = || request(RequestType::Delete, "devices/ev3-ports:in3", "");
+        assert_eq!(send(&control, delete()).await, ResponseType::Deleted);
+        assert!(hal.by_address("ev3-ports:in3").unwrap().is_none());
+        assert_eq!(send(&control, delete()).await, ResponseType::NotFound);
+    }
+
+    #[tokio::test]
+    async fn test_injections() {
+        let hal = HalMock::with_hardcoded_devices();
+        let control = hal.control();
+        let motor = hal.by_address("ev3-ports:outA").unwrap().unwrap();
+
+        let path = "devices/ev3-ports:outA/values/speed_sp";
+        let put = request(RequestType::Put, path, r#"{"value": 100}"#);
+        assert_eq!(send(&control, put).await, ResponseType::Changed);
+        assert_eq!(motor.get_attribute_str("speed_sp").unwrap(), "100");
+        let put = request(RequestType::Put, path, r#"{"value": null}"#);
+        assert_eq!(send(&control, put).await, ResponseType::Changed);
+        assert_eq!(motor.get_attribute_str("speed_sp").unwrap(), "0");
+
+        let path = "devices/ev3-ports:outA/faults/state";
+        let put = request(RequestType::Put, path, r#"{"kind": "busy", "count": 2}"#);
+        assert_eq!(send(&control, put).await, ResponseType::Changed);
+        for _ in 0..2 {
+            assert!(matches!(
+                motor.get_attribute_str("state"),
+                Err(HalError::Busy)
+            ));
+        }
+        assert_eq!(motor.get_attribute_str("state").unwrap(), "");
+
+        let put = request(RequestType::Put, path, r#"{"kind": "io"}"#);
+        assert_eq!(send(&control, put).await, ResponseType::Changed);
+        assert!(motor.get_attribute_str("state").is_err());
+        assert_eq!(motor.get_attribute_str("state").unwrap(), "");
+    }
+
+    #[tokio::test]
+    async fn test_bad_requests() {
+        let hal = HalMock::with_hardcoded_devices();
+        let control = hal.control();
+        let cases = [
+            (RequestType::Post, "devices", "{", ResponseType::BadRequest),
+            (RequestType::Post, "devices", "{}", ResponseType::BadRequest),
+            (
+                RequestType::Put,
+                "devices/ev3-ports:outA/values/speed_sp",
+                r#"{"value": 1, "extra": true}"#,
+                ResponseType::BadRequest,
+            ),
+            (
+                RequestType::Put,
+                "devices/ev3-ports:outA/faults/state",
+                r#"{"kind": "on_fire"}"#,
+                ResponseType::BadRequest,
+            ),
+            (
+                RequestType::Put,
+                "devices/ev3-ports:outD/values/state",
+                r#"{"value": 1}"#,
+                ResponseType::NotFound,
+            ),
+            (
+                RequestType::Put,
+                "devices/ev3-ports:outA/values/command",
+                r#"{"value": "stop"}"#,
+                ResponseType::NotFound,
+            ),
+            (
+                RequestType::Get,
+                "devices",
+                "",
+                ResponseType::MethodNotAllowed,
+            ),
+            (RequestType::Put, "bogus", "", ResponseType::NotFound),
+        ];
+        for (method, path, payload, expected) in cases {
+            let code = send(&control, request(method, path, payload)).await;
+            assert_eq!(code, expected, "{method:?} {path} {payload}");
+        }
+    }
+}
@@ -0,0 +1,171 @@
+//! Management of the physical ports that devices are plugged into.  Ports normally detect what's
+//! connected on their own, but some devices (NXT analog sensors, third-party I2C sensors, etc)
+//! require the port to be switched to a specific mode and possibly told which driver to load.
+//!
+//! # Types
+//!
+//! ## Type: Port
+//!
+//! ### Fields:
+//!
+//! **address**: string - name of the port, matching the address of any device plugged into it
+//! **driver_name**: string - kind of port, e.g. `ev3-input-port` or `ev3-output-port`
+//! **modes**: array of string - modes the port can be switched to
+//! **mode**: string - currently selected mode
+//! **status**: string - port specific status, typically the connected device or e.g. `no-sensor`
+//!
+//! ### Example:
+//!
+//! ```
+//! {
+//!   "address": "ev3-ports:in2",
+//!   "driver_name": "ev3-input-port",
+//!   "modes": ["auto", "ev3-analog", "ev3-uart", "nxt-analog", "nxt-color", "nxt-i2c", ...],
+//!   "mode": "auto",
+//!   "status": "no-sensor",
+//! }
+//! ```
+//!
+//! ## Type: PortUpdate
+//!
+//! ### Fields:
+//!
+//! **mode**: optional string - mode to switch to
+//! **set_device**: optional string - driver to load for the device on this port, only supported
+//!    in modes which can't detect the device automatically.  Applied after `mode`.
+//!
+//! ### Example:
+//!
+//! ```
+//! {
+//!   "mode": "other-i2c",
+//!   "set_device": "ms-ev3-smux",
+//! }
+//! ```
+//!
+//! # Requests
+//!
+//! ## GET /ports
+//!
+//! List all ports.  Observable, changes are reported whenever ports come and go or any port's
+//! mode or status changes.
+//!
+//! Response Type: array of Port
+//!
+//! ## GET /ports/<address>
+//!
+//! Look up a single port.  Observable as with `/ports`.
+//!
+//! Response Type: Port
+//!
+//! ## PUT /ports/<address>
+//!
+//! Change the port's mode and/or load a device driver.
+//!
+//! Request Type: PortUpdate
+
+use crate::anyhow_error_wrapper::AnyhowErrorWrapper;
+use crate::hal;
+use crate::hal::{HalPort, HalResult};
+use crate::ports_observable::HalWatchPorts;
+use crate::status_screen::RecordClients;
+use coap_lite::link_format::{LINK_ATTR_CONTENT_FORMAT, LINK_ATTR_RESOURCE_TYPE};
+use coap_lite::{ContentFormat, MessageClass, RequestType, ResponseType};
+use coap_server::app;
+use coap_server::app::{CoapError, Request, ResourceBuilder, Response};
+use serde::Deserialize;
+use serde::Serialize;
+use std::net::SocketAddr;
+
+pub fn port_resources() -> Vec<ResourceBuilder<SocketAddr>> {
+    vec![app::resource("ports")
+        .link_attr(LINK_ATTR_RESOURCE_TYPE, "ports")
+        .link_attr(LINK_ATTR_CONTENT_FORMAT, ContentFormat::ApplicationJSON)
+        .observable(HalWatchPorts::default())
+        .default_handler(RecordClients::new(AnyhowErrorWrapper::new(handle_ports)))]
+}
+
+async fn handle_ports(request: Request<SocketAddr>) -> anyhow::Result<Response> {
+    let method = *request.original.get_method();
+    let hal = &hal::HAL;
+
+    let mut path_iter = request.unmatched_path.iter();
+    let address = path_iter.next();
+    if path_iter.next().is_some() {
+        Err(CoapError::not_found())?;
+    }
+
+    match (method, address) {
+        (RequestType::Get, None) => {
+            let ports: Result<Vec<_>, _> =
+                hal.list_ports()?.into_iter().map(Port::from_hal).collect();
+            json_response(&request, &ports?)
+        }
+        (RequestType::Get, Some(address)) => {
+            let port = hal
+                .port_by_address(address)?
+                .ok_or_else(CoapError::not_found)?;
+            json_response(&request, &Port::from_hal(port)?)
+        }
+        (RequestType::Put, Some(address)) => {
+            let mut port = hal
+                .port_by_address(address)?
+                .ok_or_else(CoapError::not_found)?;
+            let payload_str = String::from_utf8(request.original.message.payload.clone())?;
+            let update = serde_json::from_str::<PortUpdate>(&payload_str)?;
+
+            // The driver can only be loaded once the port is in the right mode.
+            if let Some(mode) = &update.mode {
+                port.set_mode(mode)?;
+            }
+            if let Some(driver_name) = &update.set_device {
+                port.set_device(driver_name)?;
+            }
+
+            let mut reply = request.new_response();
+            reply.message.header.code = MessageClass::Response(ResponseType::Changed);
+            reply.message.payload.clear();
+            Ok(reply)
+        }
+        _ => Err(CoapError::method_not_allowed())?,
+    }
+}
+
+fn json_response<T: Serialize>(
+    request: &Request<SocketAddr>,
+    value: &T,
+) -> anyhow::Result<Response> {
+    let mut reply = request.new_response();
+    reply
+        .message
+        .set_content_format(ContentFormat::ApplicationJSON);
+    reply.message.payload = serde_json::to_string(value)?.into_bytes();
+    Ok(reply)
+}
+
+#[derive(Serialize, Deserialize)]
+struct Port {
+    address: String,
+    driver_name: String,
+    modes: Vec<String>,
+    mode: String,
+    status: String,
+}
+
+impl Port {
+    pub fn from_hal(hal: Box<dyn HalPort>) -> HalResult<Self> {
+        Ok(Self {
+            address: hal.get_address()?,
+            driver_name: hal.get_driver_name()?,
+            modes: hal.get_modes()?,
+            mode: hal.get_mode()?,
+            status: hal.get_status()?,
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct PortUpdate {
+    mode: Option<String>,
+    set_device: Option<String>,
+}
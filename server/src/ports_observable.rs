@@ -0,0 +1,39 @@
+use crate::hal;
+use async_trait::async_trait;
+use coap_server::app::{ObservableResource, Observers, ObserversHolder};
+use std::thread;
+
+#[derive(Default)]
+pub struct HalWatchPorts {}
+
+#[async_trait]
+impl ObservableResource for HalWatchPorts {
+    async fn on_active(&self, observers: Observers) -> Observers {
+        let hal = &hal::HAL;
+        let holder = ObserversHolder::new();
+        let attached = holder.attach(observers).await;
+
+        match hal.watch_ports() {
+            Ok(handle) => {
+                let holder_clone = holder.clone();
+                let rx = handle.receiver;
+                thread::spawn(move || {
+                    let rt = tokio::runtime::Builder::new_current_thread()
+                        .enable_all()
+                        .build()
+                        .unwrap();
+                    for _ in rx {
+                        rt.block_on(holder_clone.notify_change())
+                    }
+                });
+                attached.stay_active().await;
+
+                // As with devices, dropping the handle here stops the spawned thread.
+            }
+            Err(e) => {
+                log::error!("Cannot watch ports: {e:?}");
+            }
+        }
+        attached.detach().await
+    }
+}
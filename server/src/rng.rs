@@ -0,0 +1,37 @@
+//! Seeded pseudo-random numbers for the mock and fault injection, which need to be reproducible
+//! from one run to the next rather than unpredictable.
+
+/// xorshift64, which is plenty for wobbly sensor readings and keeps walks reproducible.
+pub(crate) fn next_rng(state: u64) -> u64 {
+    let mut x = state;
+    x ^= x << 13;
+    x ^= x >> 7;
+    x ^= x << 17;
+    x
+}
+
+pub(crate) fn seed_rng(seed: u64) -> u64 {
+    // xorshift gets stuck at zero.
+    seed ^ 0x9e37_79b9_7f4a_7c15
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reproducible_from_any_seed() {
+        let sequence = |seed| {
+            let mut state = seed_rng(seed);
+            (0..3)
+                .map(|_| {
+                    state = next_rng(state);
+                    state
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(sequence(0), sequence(0));
+        assert_ne!(sequence(0), sequence(1));
+        assert!(!sequence(0).contains(&0));
+    }
+}
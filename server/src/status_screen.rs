@@ -0,0 +1,234 @@
+//! Status screen shown on the brick's LCD whenever no client has drawn anything, so that it's
+//! easy to tell at a glance which robot this is and how to reach it.
+
+use std::collections::HashMap;
+use std::fs;
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use coap_server::app::request_handler::RequestHandler;
+use coap_server::app::{CoapError, Request, Response};
+use lazy_static::lazy_static;
+use log::warn;
+
+use crate::clock::{Clock, SystemClock};
+use crate::framebuffer::Canvas;
+use crate::hal_display::Display;
+
+const HOSTNAME_PATH: &str = "/proc/sys/kernel/hostname";
+const DEFAULT_ROBOT_NAME: &str = "ev3dev";
+
+const REFRESH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Clients which haven't made a request for this long are no longer counted as connected.
+const CLIENT_EXPIRY: Duration = Duration::from_secs(5 * 60);
+
+lazy_static! {
+    static ref RECENT_CLIENTS: Mutex<RecentClients> =
+        Mutex::new(RecentClients::new(SystemClock::shared()));
+}
+
+/// Clients that made a request within the last [`CLIENT_EXPIRY`].
+struct RecentClients {
+    clock: Arc<dyn Clock>,
+    last_seen: HashMap<SocketAddr, Instant>,
+}
+
+impl RecentClients {
+    fn new(clock: Arc<dyn Clock>) -> Self {
+        Self {
+            clock,
+            last_seen: HashMap::new(),
+        }
+    }
+
+    fn record(&mut self, client: SocketAddr) {
+        self.last_seen.insert(client, self.clock.now());
+    }
+
+    fn count(&mut self) -> usize {
+        let now = self.clock.now();
+        self.last_seen
+            .retain(|_, seen| now.saturating_duration_since(*seen) < CLIENT_EXPIRY);
+        self.last_seen.len()
+    }
+}
+
+/// Notes that `client` just made a request, for the purposes of the connected client count.
+fn record_client(client: SocketAddr) {
+    RECENT_CLIENTS.lock().unwrap().record(client);
+}
+
+/// Request handler wrapper that records every client it serves, see [`record_client`].
+#[derive(Clone)]
+pub struct RecordClients<H> {
+    inner: H,
+}
+
+impl<H> RecordClients<H> {
+    pub fn new(inner: H) -> Self {
+        Self { inner }
+    }
+}
+
+#[async_trait]
+impl<H> RequestHandler<SocketAddr> for RecordClients<H>
+where
+    H: RequestHandler<SocketAddr> + Clone + Send + Sync,
+{
+    async fn handle(&self, request: Request<SocketAddr>) -> Result<Response, CoapError> {
+        if let Some(source) = request.original.source {
+            record_client(source);
+        }
+        self.inner.handle(request).await
+    }
+}
+
+fn recent_client_count() -> usize {
+    RECENT_CLIENTS.lock().unwrap().count()
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StatusInfo {
+    pub robot_name: String,
+    pub address: Option<IpAddr>,
+    pub port: u16,
+    pub client_count: usize,
+}
+
+impl StatusInfo {
+    pub fn current(port: u16) -> Self {
+        let robot_name = fs::read_to_string(HOSTNAME_PATH)
+            .map(|name| name.trim().to_owned())
+            .ok()
+            .filter(|name| !name.is_empty())
+            .unwrap_or_else(|| DEFAULT_ROBOT_NAME.to_owned());
+        Self {
+            robot_name,
+            address: primary_address(),
+            port,
+            client_count: recent_client_count(),
+        }
+    }
+}
+
+pub fn render_status(canvas: &mut Canvas, info: &StatusInfo) {
+    let address = match info.address {
+        Some(address) => SocketAddr::new(address, info.port).to_string(),
+        None => format!("(no network):{}", info.port),
+    };
+    let text = format!(
+        "{}\n\n{}\n\nClients: {}",
+        info.robot_name, address, info.client_count
+    );
+    canvas.draw_text(&text);
+}
+
+/// Keeps the status screen up to date from now on, refreshing it every [`REFRESH_INTERVAL`] of
+/// `clock`.  Yields to client content on the display, see [`Display::draw_status`].
+pub fn start(display: Arc<Display>, port: u16, clock: &dyn Clock) {
+    let mut last_info = None;
+    let tick = move |now| {
+        let info = StatusInfo::current(port);
+        if last_info.as_ref() != Some(&info) {
+            match display.draw_status(|canvas| render_status(canvas, &info)) {
+                Ok(true) => last_info = Some(info),
+                // Redraw once the client lets go.
+                Ok(false) => last_info = None,
+                Err(e) => warn!("Failed to draw status screen: {e}"),
+            }
+        }
+        Some(now + REFRESH_INTERVAL)
+    };
+    clock.run_at(clock.now(), Box::new(tick));
+}
+
+/// Address of the interface used for outbound traffic, which is the one clients will most likely
+/// be able to reach us on.  No packets are actually sent.
+fn primary_address() -> Option<IpAddr> {
+    let socket = UdpSocket::bind("0.0.0.0:0").ok()?;
+    socket.connect("8.8.8.8:80").ok()?;
+    let address = socket.local_addr().ok()?.ip();
+    if address.is_unspecified() {
+        None
+    } else {
+        Some(address)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::VirtualClock;
+    use crate::framebuffer::{Framebuffer, FramebufferInfo};
+
+    #[test]
+    fn test_clients_expire() {
+        let clock = VirtualClock::new();
+        let mut clients = RecentClients::new(clock.clone());
+        clients.record("192.168.1.2:1234".parse().unwrap());
+        clock.advance(CLIENT_EXPIRY - Duration::from_secs(60));
+        clients.record("192.168.1.3:1234".parse().unwrap());
+        assert_eq!(clients.count(), 2);
+
+        clock.advance(Duration::from_secs(60));
+        assert_eq!(clients.count(), 1);
+        clock.advance(CLIENT_EXPIRY);
+        assert_eq!(clients.count(), 0);
+    }
+
+    #[test]
+    fn test_status_refreshed_by_clock() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path().join("fb0");
+        let framebuffer = Framebuffer::file_backed(path.clone(), FramebufferInfo::EV3).unwrap();
+        let display = Arc::new(Display::new(framebuffer));
+        let readback = Framebuffer::file_backed(path, FramebufferInfo::EV3).unwrap();
+        let clock = VirtualClock::new();
+
+        start(display.clone(), 5683, &*clock);
+        let blank = readback.read().unwrap();
+        clock.advance(Duration::ZERO);
+        assert_ne!(readback.read().unwrap(), blank);
+
+        display
+            .draw_client(|canvas| canvas.draw_text("Hi"))
+            .unwrap();
+        let client = readback.read().unwrap();
+        clock.advance(REFRESH_INTERVAL * 3);
+        assert_eq!(readback.read().unwrap(), client);
+    }
+
+    #[test]
+    fn test_status_rendered_until_client_draws() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path().join("fb0");
+        let framebuffer = Framebuffer::file_backed(path.clone(), FramebufferInfo::EV3).unwrap();
+        let display = Display::new(framebuffer);
+        let readback = Framebuffer::file_backed(path, FramebufferInfo::EV3).unwrap();
+
+        let info = StatusInfo {
+            robot_name: "robot".to_owned(),
+            address: Some("192.168.1.2".parse().unwrap()),
+            port: 5683,
+            client_count: 1,
+        };
+        assert!(display
+            .draw_status(|canvas| render_status(canvas, &info))
+            .unwrap());
+        let status = readback.read().unwrap();
+        assert!(status.ink_count() > 0);
+
+        display
+            .draw_client(|canvas| canvas.draw_text("Hi"))
+            .unwrap();
+        let client = readback.read().unwrap();
+        assert!(!display
+            .draw_status(|canvas| render_status(canvas, &info))
+            .unwrap());
+        assert_eq!(readback.read().unwrap(), client);
+        assert_ne!(client, status);
+    }
+}